@@ -186,7 +186,7 @@ pub static MCFG: Lazy<Option<Mutex<PhysicalMapping<AcpiHandler, acpi::mcfg::Mcfg
 });
 
 pub static PLATFORM_INFO: Lazy<
-    Option<Mutex<acpi::PlatformInfo<&slab::SlabAllocator<&crate::memory::pmm::PhysicalMemoryManager>>>>,
+    Option<Mutex<acpi::PlatformInfo<&slab::SlabAllocator<crate::memory::HeapFrameAllocator>>>>,
 > = Lazy::new(|| {
     TABLES
         .get()
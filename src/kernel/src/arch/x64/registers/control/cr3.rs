@@ -11,10 +11,26 @@ bitflags::bitflags! {
 pub struct CR3;
 
 impl CR3 {
+    const PCID_MASK: usize = 0xFFF;
+
     pub unsafe fn write(address: Address<Frame>, flags: CR3Flags) {
         asm!("mov cr3, {}", in(reg) address.get() | flags.bits(), options(nostack));
     }
 
+    /// Writes the given frame and process-context identifier to `CR3`.
+    ///
+    /// If `preserve` is set, TLB entries tagged with `pcid` are not invalidated.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure `CR4.PCIDE` is set, and that any preserved TLB entries for `pcid` are valid for `address`.
+    pub unsafe fn write_pcid(address: Address<Frame>, pcid: u16, preserve: bool) {
+        debug_assert!(pcid < 4096);
+
+        let value = address.get() | ((pcid as usize) & Self::PCID_MASK) | ((preserve as usize) << 63);
+        asm!("mov cr3, {}", in(reg) value, options(nostack));
+    }
+
     pub fn read() -> (Address<Frame>, CR3Flags) {
         let value: usize;
 
@@ -22,7 +38,18 @@ impl CR3 {
             asm!("mov {}, cr3", out(reg) value, options(nostack, nomem));
         }
 
-        (Address::new_truncate(value & !CR3Flags::all().bits()), CR3Flags::from_bits_truncate(value))
+        (Address::new_truncate(value & !Self::PCID_MASK), CR3Flags::from_bits_truncate(value))
+    }
+
+    /// Reads the current process-context identifier. This value is only meaningful if `CR4.PCIDE` is set.
+    pub fn read_pcid() -> u16 {
+        let value: usize;
+
+        unsafe {
+            asm!("mov {}, cr3", out(reg) value, options(nostack, nomem));
+        }
+
+        (value & Self::PCID_MASK) as u16
     }
 
     #[inline]
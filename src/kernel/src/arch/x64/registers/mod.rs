@@ -140,12 +140,12 @@ impl GeneralRegisters {
 #[cfg(target_arch = "x86_64")]
 #[repr(C, packed)]
 pub struct PreservedRegistersSysv64 {
-    r15: u64,
-    r14: u64,
-    r13: u64,
-    r12: u64,
-    rbp: u64,
-    rbx: u64,
-    rfl: u64,
-    rsp: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rfl: u64,
+    pub rsp: u64,
 }
@@ -21,6 +21,8 @@ macro_rules! push_fptr {
     };
 }
 
+/// Pushes the general registers such that they're laid out in memory in the field order of [`GeneralRegisters`]
+/// (`rax` at the lowest address), as handlers receive a pointer to them as that struct.
 macro_rules! push_gprs {
     () => {
         "
@@ -33,8 +35,8 @@ macro_rules! push_gprs {
         push r9
         push r8
         push rbp
-        push rsi
         push rdi
+        push rsi
         push rdx
         push rcx
         push rbx
@@ -50,8 +52,8 @@ macro_rules! pop_gprs {
         pop rbx
         pop rcx
         pop rdx
-        pop rdi
         pop rsi
+        pop rdi
         pop rbp
         pop r8
         pop r9
@@ -353,6 +355,7 @@ pub fn common_exception_handler(exception: Fault) {
 
         Err(Fault::PageFault { isf: _, gprs: _, err: _, address })
             if unsafe { crate::interrupts::pf_handler(address).is_ok() } => {}
+        Err(Fault::DeviceNotAvailable(_, _)) if crate::local_state::restore_extended_state() => {}
         Err(exception) => panic!("{:#X?}", exception),
     }
 }
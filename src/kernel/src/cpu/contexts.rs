@@ -20,3 +20,161 @@ pub fn default_arch_context() -> ArchContext {
         x64::registers::SpecialRegisters::with_kernel_segments(x64::registers::RFlags::INTERRUPT_FLAG),
     )
 }
+
+#[cfg(target_arch = "x86_64")]
+pub fn user_arch_context() -> ArchContext {
+    (
+        x64::registers::GeneralRegisters::empty(),
+        x64::registers::SpecialRegisters::flags_with_user_segments(x64::registers::RFlags::INTERRUPT_FLAG),
+    )
+}
+
+/// Constructs the architectural context of a task which entered the kernel via a system call, with the system
+/// call's return values already in place.
+#[cfg(target_arch = "x86_64")]
+pub fn syscall_arch_context(syscall_context: &SyscallContext, syscall_return: super::SyscallReturn) -> ArchContext {
+    let mut general_registers = x64::registers::GeneralRegisters::empty();
+    general_registers.rax = syscall_return.rax;
+    general_registers.rdx = syscall_return.rdx;
+    general_registers.rbx = syscall_context.rbx;
+    general_registers.rbp = syscall_context.rbp;
+    general_registers.r12 = syscall_context.r12;
+    general_registers.r13 = syscall_context.r13;
+    general_registers.r14 = syscall_context.r14;
+    general_registers.r15 = syscall_context.r15;
+
+    (
+        general_registers,
+        x64::registers::SpecialRegisters::flags_with_user_segments(x64::registers::RFlags::from_bits_truncate(
+            syscall_context.rfl,
+        )),
+    )
+}
+
+/// Length of the `syscall` instruction, which precedes the return address of every system call.
+#[cfg(target_arch = "x86_64")]
+pub const SYSCALL_INSTRUCTION_LEN: u64 = 2;
+
+/// Constructs the control flow and architectural contexts of a task which entered the kernel via a system call,
+/// such that resuming it makes the same system call again.
+#[cfg(target_arch = "x86_64")]
+pub fn syscall_restart_context(
+    ctrl_flow_context: ControlContext,
+    syscall_context: &SyscallContext,
+    vector: u64,
+    args: [u64; 5],
+) -> (ControlContext, ArchContext) {
+    let (mut general_registers, special_registers) =
+        syscall_arch_context(syscall_context, super::SyscallReturn::from(Ok(0)));
+    general_registers.rdx = args[1];
+    general_registers.rdi = vector;
+    general_registers.rsi = args[0];
+    general_registers.r10 = args[2];
+    general_registers.r8 = args[3];
+    general_registers.r9 = args[4];
+
+    (
+        ControlContext { ip: ctrl_flow_context.ip - SYSCALL_INSTRUCTION_LEN, sp: ctrl_flow_context.sp },
+        (general_registers, special_registers),
+    )
+}
+
+/// Sets the result registers of a task which is waiting to return from an endpoint system call (see
+/// [`abi::Vector::EndpointCall`]). The message is returned in `rdx`, `r10`, `r8`, and `r9`.
+#[cfg(target_arch = "x86_64")]
+pub fn set_register_message(arch_context: &mut ArchContext, result: Result<abi::RegisterMessage, super::SyscallError>) {
+    let super::SyscallReturn { rax, rdx } = super::SyscallReturn::from(result.map(|message| message[0]));
+    let message = result.unwrap_or([0; 4]);
+
+    let general_registers = &mut arch_context.0;
+    general_registers.rax = rax;
+    general_registers.rdx = rdx;
+    general_registers.r10 = message[1];
+    general_registers.r8 = message[2];
+    general_registers.r9 = message[3];
+}
+
+/// Sets the first argument register of a task which enters at an `extern "sysv64"` function.
+#[cfg(target_arch = "x86_64")]
+pub fn set_entry_argument(arch_context: &mut ArchContext, argument: u64) {
+    arch_context.0.rdi = argument;
+}
+
+/// Exits the current kernel task with `code`. The task's stack is freed as it exits, so this core's system call
+/// stack (which is unused outside of system calls) is switched to first.
+///
+/// ### Safety
+///
+/// Caller must ensure the current task is a kernel task, outside of any system call or interrupt handler.
+#[cfg(target_arch = "x86_64")]
+#[naked]
+pub unsafe extern "sysv64" fn exit_kernel_task(code: u32) -> ! {
+    core::arch::asm!(
+        "
+        cli                 # the task must not be preempted once it begins exiting
+        swapgs
+        mov rsp, gs:0x0     # switch to the system call stack (`code` remains in `edi`)
+        swapgs
+
+        call {}
+        ud2
+        ",
+        sym crate::proc::exit::exit_current,
+        options(noreturn)
+    )
+}
+
+/// Begins execution of the given context, abandoning the current one.
+///
+/// ### Safety
+///
+/// Caller must ensure the context is valid to execute, and that nothing on the current stack will be used again.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn enter_context(ctrl_flow_context: ControlContext, arch_context: ArchContext) -> ! {
+    /// Register state in the order it's popped from the stack.
+    #[repr(C)]
+    struct ContextFrame {
+        general_registers: x64::registers::GeneralRegisters,
+        ip: u64,
+        cs: u64,
+        flags: u64,
+        sp: u64,
+        ss: u64,
+    }
+
+    let (general_registers, special_registers) = arch_context;
+    let context_frame = ContextFrame {
+        general_registers,
+        ip: ctrl_flow_context.ip,
+        cs: special_registers.cs,
+        flags: special_registers.flags.bits(),
+        sp: ctrl_flow_context.sp,
+        ss: special_registers.ss,
+    };
+
+    core::arch::asm!(
+        "
+        mov rsp, {}
+
+        pop rax
+        pop rbx
+        pop rcx
+        pop rdx
+        pop rsi
+        pop rdi
+        pop rbp
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+
+        iretq
+        ",
+        in(reg) &context_frame,
+        options(noreturn)
+    )
+}
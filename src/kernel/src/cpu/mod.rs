@@ -1,7 +1,9 @@
 mod contexts;
 mod setup;
 mod syscall;
+mod xstate;
 
 pub use contexts::*;
 pub use setup::*;
 pub use syscall::*;
+pub use xstate::*;
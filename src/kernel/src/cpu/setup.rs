@@ -22,6 +22,10 @@ pub fn setup() {
         flags.insert(CR4Flags::OSFXSR);
     }
 
+    if cpuid::FEATURE_INFO.has_xsave() {
+        flags.insert(CR4Flags::OSXSAVE);
+    }
+
     if cpuid::FEATURE_INFO.has_mce() {
         flags.insert(CR4Flags::MCE);
     }
@@ -49,6 +53,9 @@ pub fn setup() {
     // ### Safety: Initialize the CR4 register with all CPU & kernel supported features.
     unsafe { CR4::write(flags) };
 
+    // ### Safety: `CR4.OSXSAVE` was set above, if `XSAVE` is supported.
+    unsafe { super::setup_extended_state() };
+
     // Enable use of the `NO_EXECUTE` page attribute, if supported.
     if cpuid::EXT_FUNCTION_INFO.as_ref().map_or(false, cpuid::ExtendedProcessorFeatureIdentifiers::has_execute_disable)
     {
@@ -99,12 +106,14 @@ pub fn setup() {
                         push rax
                         push rcx
 
-                        # caller already passed their own arguments in relevant registers
+                        # `rcx` is clobbered by `syscall`, so the fourth argument is passed in `r10`
+                        mov rcx, r10
+
+                        # caller already passed their other arguments in relevant registers
                         call {}
 
                         pop rcx     # store target `rip` in `rcx`
-                        pop rax     # store target `rsp` in `rax`
-                        mov [rsp + (7 * 8)], rax   # update userspace `rsp` on stack
+                        add rsp, 8  # discard target `rsp`, as the userspace `rsp` is restored below
 
                         # restore preserved registers
                         pop r15
@@ -114,6 +123,15 @@ pub fn setup() {
                         pop rbp
                         pop rbx
                         pop r11     # restore userspace `rflags`
+
+                        # clear remaining scratch registers, so no kernel values are leaked to userspace
+                        # (`rax` and `rdx` hold the return values)
+                        xor esi, esi
+                        xor edi, edi
+                        xor r8d, r8d
+                        xor r9d, r9d
+                        xor r10d, r10d
+
                         pop rsp     # this restores userspace `rsp`
 
                         sysretq
@@ -139,47 +157,30 @@ unsafe extern "sysv64" fn syscall_handler(
     vector: u64,
     arg0: u64,
     arg1: u64,
-    _arg2: u64,
-    _arg3: u64,
-    _arg4: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
     ret_ip: u64,
     ret_sp: u64,
-    mut syscall_context: crate::cpu::SyscallContext,
-) -> crate::cpu::ControlContext {
-    // Take a reference to the syscall context, to avoid not mutating the in-memory representation.
-    let _syscall_context = &mut syscall_context;
-
-    let syscall = match vector {
-        0x100 => {
-            use log::Level;
-
-            let log_level = match arg0 {
-                1 => Ok(Level::Error),
-                2 => Ok(Level::Warn),
-                3 => Ok(Level::Info),
-                4 => Ok(Level::Debug),
-                arg0 => Err(arg0),
-            };
-
-            match log_level {
-                Ok(level) => Some(super::Syscall::Log { level, cstr_ptr: arg1 as usize as *const _ }),
-                Err(invalid_level) => {
-                    warn!("Invalid log level provided: {}", invalid_level);
-                    None
-                }
-            }
-        }
-
-        vector => {
-            warn!("Unhandled system call vector: {:#X}", vector);
-            None
-        }
+    syscall_context: crate::cpu::SyscallContext,
+) -> crate::cpu::SyscallReturn {
+    crate::local_state::set_in_syscall(true);
+
+    // ### Safety: The control flow and preserved registers are exactly those of the calling task.
+    let result = unsafe {
+        super::do_syscall(
+            vector,
+            [arg0, arg1, arg2, arg3, arg4],
+            crate::cpu::ControlContext { ip: ret_ip, sp: ret_sp },
+            &syscall_context,
+        )
     };
 
-    match syscall {
-        Some(syscall) => super::do_syscall(syscall),
-        None => warn!("Failed to execute system call."),
+    if let Err(err) = result {
+        debug!("System call {:#X} failed: {:?}", vector, err);
     }
 
-    crate::cpu::ControlContext { ip: ret_ip, sp: ret_sp }
+    crate::local_state::set_in_syscall(false);
+
+    crate::cpu::SyscallReturn::from(result)
 }
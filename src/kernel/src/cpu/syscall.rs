@@ -1,23 +1,843 @@
-use core::ptr::NonNull;
+use crate::{
+    ipc::{channel, endpoint},
+    memory::{
+        address_space::{self, MaxProtection, MmapFlags},
+        shm, Page, Virtual,
+    },
+    proc::{
+        exit,
+        handles::{self, Handle, Object, Rights},
+        task::{Affinity, Task},
+    },
+};
+use abi::{ChannelFlags, RegisterMessage, TaskInfo};
+use alloc::{sync::Arc, vec::Vec};
+use core::{alloc::Layout, num::NonZeroUsize};
+use lzstd::Address;
+use uuid::Uuid;
+
+pub use abi::{Error as SyscallError, SyscallReturn};
+
+/// Maximum length of a string read from userspace, including its null terminator.
+pub const MAX_USER_STR_LEN: usize = 0x1000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Syscall {
+    /// Gives up the remainder of the current task's time slice.
+    ///
+    /// Vector: 0x001
+    Yield,
+
+    /// Writes the 16-byte ID of the current task to `out_ptr`.
+    ///
+    /// Vector: 0x002
+    TaskId { out_ptr: *mut u8 },
+
+    /// Exits the current task.
+    ///
+    /// Vector: 0x003
+    Exit { code: u32 },
+
+    /// Restricts the current task to the cores allowed by `affinity`.
+    ///
+    /// Vector: 0x004
+    SetAffinity { affinity: Affinity },
+
+    /// Writes the statistics of up to `capacity` tasks to `ptr`, returning the total number of tasks.
+    ///
+    /// Vector: 0x005
+    TaskStats { ptr: *mut TaskInfo, capacity: usize },
+
+    /// Creates a child of the current task in a copy-on-write clone of its address space, writing the child's
+    /// 16-byte ID to `out_ptr`. Returns 1 to the current task, and 0 to the child, which begins with no handles.
+    ///
+    /// Vector: 0x006
+    TaskClone { out_ptr: *mut u8 },
+
+    /// Blocks until the child task whose 16-byte ID is at `id_ptr` exits, returning its exit code.
+    ///
+    /// Vector: 0x007
+    TaskWait { id_ptr: *const u8 },
+
     /// Logs to the kernel standard output.
     ///
     /// Vector: 0x100
     Log { level: log::Level, cstr_ptr: *const core::ffi::c_char },
+
+    /// Maps a region of memory into the current task's address space, returning its address.
+    ///
+    /// Vector: 0x200
+    Mmap { address: Option<Address<Page>>, layout: Layout, flags: MmapFlags },
+
+    /// Unmaps a region of memory from the current task's address space.
+    ///
+    /// Vector: 0x201
+    Munmap { ptr: *mut u8, len: NonZeroUsize },
+
+    /// Changes the protection of a region of memory in the current task's address space.
+    ///
+    /// Vector: 0x202
+    Mprotect { ptr: *mut u8, len: NonZeroUsize, flags: MmapFlags },
+
+    /// Creates a zeroed shared memory object, returning its handle.
+    ///
+    /// Vector: 0x210
+    ShmCreate { len: NonZeroUsize },
+
+    /// Maps a shared memory object into the current task's address space, returning its address.
+    ///
+    /// Vector: 0x211
+    ShmMap { handle: Handle, address: Option<Address<Page>>, flags: MmapFlags },
+
+    /// Returns the number of microseconds elapsed since boot.
+    ///
+    /// Vector: 0x300
+    Uptime,
+
+    /// Blocks the current task for at least `microseconds`.
+    ///
+    /// Vector: 0x301
+    Sleep { microseconds: u64 },
+
+    /// Creates a pair of connected channel endpoints, returning their handles.
+    ///
+    /// Vector: 0x400
+    ChannelCreate,
+
+    /// Sends a message through a channel endpoint, optionally transferring a handle with it.
+    ///
+    /// Vector: 0x401
+    ChannelSend { handle: Handle, ptr: *const u8, len: usize, flags: ChannelFlags, transfer: Option<Handle> },
+
+    /// Receives a message from a channel endpoint, returning its length and the handle transferred with it.
+    ///
+    /// Vector: 0x402
+    ChannelRecv { handle: Handle, ptr: *mut u8, len: usize, flags: ChannelFlags },
+
+    /// Creates a call/reply endpoint, returning its handle.
+    ///
+    /// Vector: 0x410
+    EndpointCreate,
+
+    /// Calls an endpoint, returning the server's reply.
+    ///
+    /// Vector: 0x411
+    EndpointCall { handle: Handle, message: RegisterMessage },
+
+    /// Receives a call from an endpoint.
+    ///
+    /// Vector: 0x412
+    EndpointRecv { handle: Handle },
+
+    /// Replies to the last call received from an endpoint.
+    ///
+    /// Vector: 0x413
+    EndpointReply { handle: Handle, message: RegisterMessage },
+
+    /// Replies to the last call received from an endpoint, then receives the next.
+    ///
+    /// Vector: 0x414
+    EndpointReplyRecv { handle: Handle, message: RegisterMessage },
+
+    /// Duplicates a handle, with a subset of its rights.
+    ///
+    /// Vector: 0x500
+    HandleDuplicate { handle: Handle, rights: Rights },
+
+    /// Closes a handle.
+    ///
+    /// Vector: 0x501
+    HandleClose { handle: Handle },
+}
+
+impl Syscall {
+    /// Decodes a system call from its vector and arguments.
+    pub fn decode(vector: u64, args: [u64; 5]) -> Result<Self, SyscallError> {
+        use abi::Vector;
+
+        match Vector::try_from(vector).map_err(|_| SyscallError::InvalidVector)? {
+            Vector::Yield => Ok(Self::Yield),
+
+            Vector::TaskId => Ok(Self::TaskId { out_ptr: args[0] as usize as *mut _ }),
+
+            Vector::Exit => Ok(Self::Exit { code: args[0] as u32 }),
+
+            Vector::SetAffinity => Ok(Self::SetAffinity { affinity: Affinity::new(args[0]) }),
+
+            Vector::TaskStats => Ok(Self::TaskStats { ptr: args[0] as usize as *mut _, capacity: args[1] as usize }),
+
+            Vector::TaskClone => Ok(Self::TaskClone { out_ptr: args[0] as usize as *mut _ }),
+
+            Vector::TaskWait => Ok(Self::TaskWait { id_ptr: args[0] as usize as *const _ }),
+
+            Vector::Log => {
+                let level = abi::LogLevel::try_from(args[0]).map_err(|_| SyscallError::InvalidArgument)?;
+
+                Ok(Self::Log { level: level.into(), cstr_ptr: args[1] as usize as *const _ })
+            }
+
+            Vector::Mmap => {
+                let layout = Layout::from_size_align(args[0] as usize, args[1] as usize)
+                    .ok()
+                    .filter(|layout| layout.size() > 0)
+                    .ok_or(SyscallError::InvalidArgument)?;
+                let flags = MmapFlags::from_bits(args[2] as usize).ok_or(SyscallError::InvalidArgument)?;
+                let address = match args[3] {
+                    0 => None,
+                    address => Some(Address::new(address as usize).ok_or(SyscallError::InvalidArgument)?),
+                };
+
+                Ok(Self::Mmap { address, layout, flags })
+            }
+
+            Vector::Munmap => Ok(Self::Munmap {
+                ptr: args[0] as usize as *mut _,
+                len: NonZeroUsize::new(args[1] as usize).ok_or(SyscallError::InvalidArgument)?,
+            }),
+
+            Vector::Mprotect => Ok(Self::Mprotect {
+                ptr: args[0] as usize as *mut _,
+                len: NonZeroUsize::new(args[1] as usize).ok_or(SyscallError::InvalidArgument)?,
+                flags: MmapFlags::from_bits(args[2] as usize).ok_or(SyscallError::InvalidArgument)?,
+            }),
+
+            Vector::ShmCreate => {
+                Ok(Self::ShmCreate { len: NonZeroUsize::new(args[0] as usize).ok_or(SyscallError::InvalidArgument)? })
+            }
+
+            Vector::ShmMap => Ok(Self::ShmMap {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                address: match args[2] {
+                    0 => None,
+                    address => Some(Address::new(address as usize).ok_or(SyscallError::InvalidArgument)?),
+                },
+                flags: MmapFlags::from_bits(args[1] as usize).ok_or(SyscallError::InvalidArgument)?,
+            }),
+
+            Vector::Uptime => Ok(Self::Uptime),
+
+            Vector::Sleep => Ok(Self::Sleep { microseconds: args[0] }),
+
+            Vector::ChannelCreate => Ok(Self::ChannelCreate),
+
+            Vector::ChannelSend => Ok(Self::ChannelSend {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                ptr: args[1] as usize as *const _,
+                len: Some(args[2] as usize)
+                    .filter(|len| *len <= abi::MAX_MESSAGE_LEN)
+                    .ok_or(SyscallError::InvalidArgument)?,
+                flags: ChannelFlags::from_bits(args[3] as usize).ok_or(SyscallError::InvalidArgument)?,
+                transfer: match args[4] {
+                    0 => None,
+                    handle => Some(Handle::try_from(handle).map_err(|_| SyscallError::InvalidHandle)?),
+                },
+            }),
+
+            Vector::ChannelRecv => Ok(Self::ChannelRecv {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                ptr: args[1] as usize as *mut _,
+                len: args[2] as usize,
+                flags: ChannelFlags::from_bits(args[3] as usize).ok_or(SyscallError::InvalidArgument)?,
+            }),
+
+            Vector::EndpointCreate => Ok(Self::EndpointCreate),
+
+            Vector::EndpointCall => Ok(Self::EndpointCall {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                message: [args[1], args[2], args[3], args[4]],
+            }),
+
+            Vector::EndpointRecv => {
+                Ok(Self::EndpointRecv { handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)? })
+            }
+
+            Vector::EndpointReply => Ok(Self::EndpointReply {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                message: [args[1], args[2], args[3], args[4]],
+            }),
+
+            Vector::EndpointReplyRecv => Ok(Self::EndpointReplyRecv {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                message: [args[1], args[2], args[3], args[4]],
+            }),
+
+            Vector::HandleDuplicate => Ok(Self::HandleDuplicate {
+                handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)?,
+                rights: u32::try_from(args[1]).ok().and_then(Rights::from_bits).ok_or(SyscallError::InvalidArgument)?,
+            }),
+
+            Vector::HandleClose => {
+                Ok(Self::HandleClose { handle: Handle::try_from(args[0]).map_err(|_| SyscallError::InvalidHandle)? })
+            }
+        }
+    }
 }
 
-pub fn do_syscall(vector: Syscall) {
-    match vector {
+/// Decodes and executes the given system call on behalf of the current task.
+///
+/// ### Safety
+///
+/// Caller must ensure `ctrl_flow_context` and `syscall_context` describe the task which made the system call, as
+/// some system calls switch away from it.
+pub unsafe fn do_syscall(
+    vector: u64,
+    args: [u64; 5],
+    ctrl_flow_context: super::ControlContext,
+    syscall_context: &super::SyscallContext,
+) -> Result<u64, SyscallError> {
+    // Takes the current task out of the scheduler for a blocking system call, such that it makes the system call
+    // again once it's woken.
+    let restarting_waiter = || {
+        crate::local_state::take_current_task().map(|mut task| {
+            (task.ctrl_flow_context, task.arch_context) =
+                super::syscall_restart_context(ctrl_flow_context, syscall_context, vector, args);
+
+            task
+        })
+    };
+    // Takes the current task out of the scheduler for a blocking system call, such that it returns from the system
+    // call once it's woken, with whatever results were set by the task which woke it.
+    let returning_waiter = || {
+        crate::local_state::take_current_task().map(|mut task| {
+            task.ctrl_flow_context = ctrl_flow_context;
+            task.arch_context = super::syscall_arch_context(syscall_context, SyscallReturn::from(Ok(0)));
+
+            task
+        })
+    };
+
+    match Syscall::decode(vector, args)? {
+        Syscall::Yield => {
+            let mut ctrl_flow_context = ctrl_flow_context;
+            let mut arch_context = super::syscall_arch_context(syscall_context, SyscallReturn::from(Ok(0)));
+
+            // ### Safety: The task's context has been fully captured, so it can be resumed by the scheduler later.
+            unsafe {
+                crate::local_state::next_task(&mut ctrl_flow_context, &mut arch_context);
+                super::enter_context(ctrl_flow_context, arch_context)
+            }
+        }
+
+        Syscall::TaskId { out_ptr } => {
+            let uuid = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+
+            with_user_memory(|address_space| {
+                address_space
+                    .copy_to_user(user_address(out_ptr.cast_const())?, uuid.as_bytes())
+                    .map_err(|_| SyscallError::InvalidPointer)
+            })?;
+
+            Ok(0)
+        }
+
+        // ### Safety: System calls run on the core's system call stack, with interrupts disabled.
+        Syscall::Exit { code } => unsafe { crate::proc::exit::exit_current(code) },
+
+        Syscall::SetAffinity { affinity } => {
+            if !crate::proc::is_schedulable(affinity) {
+                return Err(SyscallError::InvalidArgument);
+            }
+
+            crate::local_state::with_current_task(|task| task.set_affinity(affinity)).ok_or(SyscallError::NoTask)?;
+
+            if affinity.allows(crate::local_state::core_id()) {
+                Ok(0)
+            } else {
+                // Requeue the task, so it's placed on a core it's allowed to run on.
+                let task = returning_waiter().ok_or(SyscallError::NoTask)?;
+                crate::proc::queue_pending(task);
+
+                // ### Safety: The current task has been queued to run on another core, so another can be run.
+                unsafe { switch_from_blocked(None) }
+            }
+        }
+
+        Syscall::TaskStats { ptr, capacity } => {
+            let infos = crate::proc::stats::snapshot().map_err(|_| SyscallError::OutOfMemory)?;
+            let count = core::cmp::min(infos.len(), capacity);
+
+            if count > 0 {
+                // ### Safety: `TaskInfo` is `repr(C)`, and has no padding, so every byte of it is initialized.
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(infos.as_ptr().cast::<u8>(), count * core::mem::size_of::<TaskInfo>())
+                };
+
+                with_user_memory(|address_space| {
+                    address_space
+                        .copy_to_user(user_address(ptr.cast_const())?, bytes)
+                        .map_err(|_| SyscallError::InvalidPointer)
+                })?;
+            }
+
+            Ok(infos.len() as u64)
+        }
+
+        Syscall::TaskClone { out_ptr } => {
+            let out_address = user_address(out_ptr.cast_const())?;
+            let (parent, priority, affinity) =
+                crate::local_state::with_current_task(|task| (task.uuid(), task.priority(), task.affinity()))
+                    .ok_or(SyscallError::NoTask)?;
+
+            // The child resumes from this system call, as if it had made it.
+            let child_arch_context = super::syscall_arch_context(syscall_context, SyscallReturn::from(Ok(0)));
+            let mut child = Task::new_clone(&parent, priority, ctrl_flow_context, child_arch_context)
+                .map_err(|_| SyscallError::OutOfMemory)?;
+            child.set_affinity(affinity);
+
+            let result = with_user_memory(|address_space| {
+                address_space
+                    .copy_to_user(out_address, child.uuid().as_bytes())
+                    .map_err(|_| SyscallError::InvalidPointer)
+            });
+
+            if let Err(err) = result {
+                // ### Safety: The child has never run, so its address space isn't active on any core.
+                let address_space = unsafe { crate::memory::address_space::unregister(&child.uuid()) };
+                drop(address_space);
+
+                return Err(err);
+            }
+
+            crate::proc::exit::register_child(&mut child, parent);
+            crate::proc::queue_pending(child);
+
+            Ok(1)
+        }
+
+        Syscall::TaskWait { id_ptr } => {
+            let parent = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+            let mut id = [0; 16];
+            with_user_memory(|address_space| {
+                address_space.copy_from_user(user_address(id_ptr)?, &mut id).map_err(|_| SyscallError::InvalidPointer)
+            })?;
+            let uuid = Uuid::from_bytes(id);
+
+            match exit::reap(&uuid, &parent) {
+                Ok(Some(code)) => Ok(u64::from(code)),
+
+                // Wait for the child to exit, then make the system call again to reap it.
+                Ok(None) if exit::EXITED.wait_while(|| !exit::has_exited(&uuid), restarting_waiter) => {
+                    // ### Safety: The current task has been queued to be woken, so another can be run.
+                    unsafe { switch_from_blocked(None) }
+                }
+
+                // The child exited after it was checked, so it can be reaped now.
+                Ok(None) => {
+                    exit::reap(&uuid, &parent).ok().flatten().map(u64::from).ok_or(SyscallError::InvalidArgument)
+                }
+
+                Err(()) => Err(SyscallError::InvalidArgument),
+            }
+        }
+
         Syscall::Log { level, cstr_ptr } => {
-            log!(
-                level,
-                "Syscall: Log: {:?}",
-                // Safety: If pointer is null, we panic.
-                // TODO do not panic.
-                unsafe { crate::memory::catch_read_str(NonNull::new(cstr_ptr.cast_mut().cast()).unwrap()).unwrap() }
-            );
+            let string = with_user_memory(|address_space| {
+                address_space
+                    .read_user_str(user_address(cstr_ptr)?, MAX_USER_STR_LEN)
+                    .map_err(|_| SyscallError::InvalidPointer)
+            })?;
+
+            log!(level, "Syscall: Log: {:?}", string);
+
+            Ok(0)
+        }
+
+        Syscall::Mmap { address, layout, flags } => with_user_memory(|address_space| {
+            address_space.mmap(address, layout, flags).map(|ptr| ptr.addr().get() as u64).map_err(SyscallError::from)
+        }),
+
+        Syscall::Munmap { ptr, len } => with_user_memory(|address_space| {
+            address_space.munmap(user_address(ptr.cast_const())?, len).map_err(|_| SyscallError::InvalidArgument)?;
+
+            Ok(0)
+        }),
+
+        Syscall::Mprotect { ptr, len, flags } => with_user_memory(|address_space| {
+            address_space
+                .mprotect(user_address(ptr.cast_const())?, len, flags)
+                .map_err(|_| SyscallError::InvalidArgument)?;
+
+            Ok(0)
+        }),
+
+        Syscall::ShmCreate { len } => {
+            let shared_memory = shm::SharedMemory::new(len).map_err(|_| SyscallError::OutOfMemory)?;
+            let shared_memory = Arc::try_new(shared_memory).map_err(|_| SyscallError::OutOfMemory)?;
+
+            with_handles(|handle_table| {
+                Ok(handle_table.insert(Object::SharedMemory(shared_memory), Rights::all()).map(u64::from)?)
+            })
+        }
+
+        Syscall::ShmMap { handle, address, flags } => {
+            let required = Rights::MAP | protection_rights(flags);
+            let (shared_memory, rights) = with_handles(|handle_table| {
+                let entry = handle_table.get(handle, required)?;
+                match &entry.object {
+                    Object::SharedMemory(shared_memory) => Ok((shared_memory.clone(), entry.rights)),
+                    _ => Err(SyscallError::InvalidHandle),
+                }
+            })?;
+            // The mapping can never be given protection that the handle doesn't grant (e.g. by `Mprotect`).
+            let max_protection =
+                MaxProtection { write: rights.contains(Rights::WRITE), execute: rights.contains(Rights::EXECUTE) };
+
+            with_user_memory(|address_space| {
+                shared_memory
+                    .map_into(address_space, address, flags, max_protection)
+                    .map(|ptr| ptr.addr().get() as u64)
+                    .map_err(SyscallError::from)
+            })
+        }
+
+        Syscall::Uptime => Ok(crate::time::TSC.uptime_us()),
+
+        Syscall::Sleep { microseconds } => {
+            let deadline = crate::time::TSC.uptime_us().saturating_add(microseconds);
+            let task = returning_waiter().ok_or(SyscallError::NoTask)?;
+            crate::local_state::sleep_task(deadline, task);
+
+            // ### Safety: The current task is sleeping until its deadline, so another can be run.
+            unsafe { switch_from_blocked(None) }
+        }
+
+        Syscall::ChannelCreate => {
+            let (endpoint0, endpoint1) = channel::create().map_err(|_| SyscallError::OutOfMemory)?;
+            let endpoint0 = Arc::try_new(endpoint0).map_err(|_| SyscallError::OutOfMemory)?;
+            let endpoint1 = Arc::try_new(endpoint1).map_err(|_| SyscallError::OutOfMemory)?;
+
+            let (handle0, handle1) = with_handles(|handle_table| {
+                // Both entries are reserved first, so the second insertion can only fail if handles are exhausted.
+                handle_table.reserve(2)?;
+                let handle0 = handle_table.insert(Object::Channel(endpoint0), Rights::all())?;
+                let handle1 = handle_table.insert(Object::Channel(endpoint1), Rights::all()).map_err(|err| {
+                    // No task can be waiting on the new endpoints, so the first is dropped without waking any.
+                    handle_table.remove(handle0).ok();
+                    err
+                })?;
+
+                Ok((handle0, handle1))
+            })?;
+
+            Ok(u64::from(handle0) | (u64::from(handle1) << 32))
         }
+
+        Syscall::ChannelSend { handle, ptr, len, flags, transfer } => {
+            let endpoint = channel_endpoint(handle, Rights::WRITE)?;
+
+            let mut bytes = Vec::new();
+            bytes.try_reserve_exact(len).map_err(|_| SyscallError::OutOfMemory)?;
+            bytes.resize(len, 0);
+            if len > 0 {
+                with_user_memory(|address_space| {
+                    address_space
+                        .copy_from_user(user_address(ptr)?, &mut bytes)
+                        .map_err(|_| SyscallError::InvalidPointer)
+                })?;
+            }
+
+            // The handle is only removed from the table once the message has been sent, so a failed (or restarted)
+            // send leaves it in place.
+            let attachment = transfer
+                .map(|transfer| {
+                    with_handles(|handle_table| {
+                        let entry = handle_table.get(transfer, Rights::TRANSFER)?;
+
+                        // An endpoint queued in its own channel would keep the channel alive indefinitely.
+                        if let Object::Channel(transferred) = &entry.object
+                            && transferred.shares_channel(&endpoint)
+                        {
+                            Err(SyscallError::InvalidArgument)
+                        } else {
+                            Ok(entry.clone())
+                        }
+                    })
+                })
+                .transpose()?;
+            let message = channel::Message { bytes, attachment };
+
+            let result = if flags.contains(ChannelFlags::NONBLOCKING) {
+                endpoint.send(message, || None)
+            } else {
+                endpoint.send(message, restarting_waiter)
+            };
+
+            match result {
+                Ok(()) => {
+                    if let Some(transfer) = transfer {
+                        // Bind the entry, so it's dropped outside of the lock.
+                        let entry = with_handles(|handle_table| Ok(handle_table.remove(transfer)?))?;
+                        drop(entry);
+                    }
+
+                    Ok(0)
+                }
+                Err(channel::Error::Blocked) => {
+                    // Switching away never returns, so the reference must be dropped first.
+                    drop(endpoint);
+
+                    // ### Safety: The current task has been queued to be woken, so another can be run.
+                    unsafe { switch_from_blocked(None) }
+                }
+                Err(err) => Err(SyscallError::from(err)),
+            }
+        }
+
+        Syscall::ChannelRecv { handle, ptr, len, flags } => {
+            let endpoint = channel_endpoint(handle, Rights::READ)?;
+
+            let receive = |message: &channel::Message| {
+                if !message.bytes.is_empty() {
+                    with_user_memory(|address_space| {
+                        address_space
+                            .copy_to_user(user_address(ptr.cast_const())?, &message.bytes)
+                            .map_err(|_| SyscallError::InvalidPointer)
+                    })?;
+                }
+
+                match message.attachment.clone() {
+                    Some(handles::Entry { object, rights }) => {
+                        with_handles(|handle_table| Ok(handle_table.insert(object, rights)?))
+                    }
+                    None => Ok(0),
+                }
+            };
+
+            let result = if flags.contains(ChannelFlags::NONBLOCKING) {
+                endpoint.recv(len, receive, || None)
+            } else {
+                endpoint.recv(len, receive, restarting_waiter)
+            };
+
+            match result {
+                Ok(receive_result) => receive_result
+                    .map(|(message_len, transferred)| (message_len as u64) | (u64::from(transferred) << 32)),
+                Err(channel::Error::Blocked) => {
+                    // Switching away never returns, so the reference must be dropped first.
+                    drop(endpoint);
+
+                    // ### Safety: The current task has been queued to be woken, so another can be run.
+                    unsafe { switch_from_blocked(None) }
+                }
+                Err(err) => Err(SyscallError::from(err)),
+            }
+        }
+
+        Syscall::EndpointCreate => {
+            let endpoint = Arc::try_new(endpoint::Endpoint::default()).map_err(|_| SyscallError::OutOfMemory)?;
+
+            with_handles(|handle_table| Ok(handle_table.insert(Object::Endpoint(endpoint), Rights::all())?))
+                .map(u64::from)
+        }
+
+        Syscall::EndpointCall { handle, message } => {
+            let endpoint = ipc_endpoint(handle, Rights::WRITE)?;
+            let server = endpoint.call(message, returning_waiter).map_err(|_| SyscallError::NoTask)?;
+            // Switching away never returns, so the reference must be dropped first.
+            drop(endpoint);
+
+            // ### Safety: The current task is waiting for a reply, so another can be run.
+            unsafe { switch_from_blocked(server) }
+        }
+
+        Syscall::EndpointRecv { handle } => {
+            let uuid = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+            let endpoint = ipc_endpoint(handle, Rights::READ)?;
+
+            let message = endpoint.recv(uuid, returning_waiter).map_err(|_| SyscallError::NoTask)?;
+            // Neither path returns, so the reference must be dropped first.
+            drop(endpoint);
+
+            match message {
+                // ### Safety: The control flow and preserved registers are exactly those of the calling task.
+                Some(message) => unsafe { return_register_message(ctrl_flow_context, syscall_context, message) },
+                // ### Safety: The current task is waiting for a call, so another can be run.
+                None => unsafe { switch_from_blocked(None) },
+            }
+        }
+
+        Syscall::EndpointReply { handle, message } => {
+            let uuid = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+            let endpoint = ipc_endpoint(handle, Rights::READ)?;
+
+            let caller = endpoint.reply(uuid, message).map_err(|_| SyscallError::InvalidArgument)?;
+            crate::local_state::push_task(caller);
+
+            Ok(0)
+        }
+
+        Syscall::EndpointReplyRecv { handle, message } => {
+            let uuid = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+            let endpoint = ipc_endpoint(handle, Rights::READ)?;
+
+            let caller = endpoint.reply(uuid, message).map_err(|_| SyscallError::InvalidArgument)?;
+            let message = endpoint.recv(uuid, returning_waiter);
+            // Neither successful path returns, so the reference must be dropped first.
+            drop(endpoint);
+
+            match message {
+                Ok(Some(message)) => {
+                    crate::local_state::push_task(caller);
+
+                    // ### Safety: The control flow and preserved registers are exactly those of the calling task.
+                    unsafe { return_register_message(ctrl_flow_context, syscall_context, message) }
+                }
+
+                // The server is now waiting for the next call, so run the caller on the remainder of its time slice.
+                // ### Safety: The current task is waiting for a call, so another can be run.
+                Ok(None) => unsafe { switch_from_blocked(Some(caller)) },
+
+                Err(_) => {
+                    crate::local_state::push_task(caller);
+
+                    Err(SyscallError::NoTask)
+                }
+            }
+        }
+
+        Syscall::HandleDuplicate { handle, rights } => {
+            with_handles(|handle_table| Ok(handle_table.duplicate(handle, rights)?)).map(u64::from)
+        }
+
+        Syscall::HandleClose { handle } => {
+            // Bind the entry, so it's dropped outside of the lock.
+            let entry = with_handles(|handle_table| Ok(handle_table.remove(handle)?))?;
+            drop(entry);
+
+            Ok(0)
+        }
+    }
+}
+
+impl From<handles::Error> for SyscallError {
+    fn from(err: handles::Error) -> Self {
+        match err {
+            handles::Error::Exhausted | handles::Error::OutOfMemory => SyscallError::OutOfMemory,
+            handles::Error::NotFound => SyscallError::InvalidHandle,
+            handles::Error::AccessDenied => SyscallError::AccessDenied,
+        }
+    }
+}
+
+impl From<address_space::Error> for SyscallError {
+    fn from(err: address_space::Error) -> Self {
+        match err {
+            address_space::Error::Invalid => SyscallError::InvalidArgument,
+            address_space::Error::OutOfMemory => SyscallError::OutOfMemory,
+            address_space::Error::AlreadyMapped => SyscallError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<shm::Error> for SyscallError {
+    fn from(err: shm::Error) -> Self {
+        match err {
+            shm::Error::OutOfMemory => SyscallError::OutOfMemory,
+            shm::Error::AddressSpaceError(err) => err.into(),
+        }
+    }
+}
+
+impl From<channel::Error> for SyscallError {
+    fn from(err: channel::Error) -> Self {
+        match err {
+            channel::Error::QueueFull | channel::Error::QueueEmpty | channel::Error::Blocked => {
+                SyscallError::WouldBlock
+            }
+            channel::Error::PeerClosed => SyscallError::PeerClosed,
+            channel::Error::OutOfMemory => SyscallError::OutOfMemory,
+            channel::Error::BufferTooSmall => SyscallError::BufferTooSmall,
+        }
+    }
+}
+
+/// Switches to `next_task` (or otherwise, the next task chosen by the scheduler), after the current task has been
+/// taken out of the scheduler to wait.
+///
+/// ### Safety
+///
+/// Caller must ensure the current task has been queued to be woken, and that nothing on the current stack will be
+/// used again.
+unsafe fn switch_from_blocked(next_task: Option<crate::proc::task::Task>) -> ! {
+    debug_assert!(crate::local_state::current_task_uuid().is_none());
+
+    // There's no current task, so these contexts aren't saved anywhere; they're only overwritten by the next task's.
+    let mut ctrl_flow_context = super::ControlContext { ip: 0, sp: 0 };
+    let mut arch_context = super::default_arch_context();
+
+    match next_task {
+        Some(next_task) => crate::local_state::switch_to_task(next_task, &mut ctrl_flow_context, &mut arch_context),
+        None => crate::local_state::next_task(&mut ctrl_flow_context, &mut arch_context),
     }
+
+    super::enter_context(ctrl_flow_context, arch_context)
+}
+
+/// Returns from the current system call with `message` in registers, which returning with `sysret` would clear.
+///
+/// ### Safety
+///
+/// Caller must ensure `ctrl_flow_context` and `syscall_context` describe the task which made the system call, and
+/// that nothing on the current stack will be used again.
+unsafe fn return_register_message(
+    ctrl_flow_context: super::ControlContext,
+    syscall_context: &super::SyscallContext,
+    message: RegisterMessage,
+) -> ! {
+    let mut arch_context = super::syscall_arch_context(syscall_context, SyscallReturn::from(Ok(0)));
+    super::set_register_message(&mut arch_context, Ok(message));
+    crate::local_state::set_in_syscall(false);
+
+    super::enter_context(ctrl_flow_context, arch_context)
+}
+
+/// Runs `func` with the current task's handle table.
+fn with_handles<T>(func: impl FnOnce(&mut handles::HandleTable) -> Result<T, SyscallError>) -> Result<T, SyscallError> {
+    let uuid = crate::local_state::current_task_uuid().ok_or(SyscallError::NoTask)?;
+    handles::with(&uuid, func)
+}
+
+/// Handle rights required to map memory with the protection specified by `flags`. Write and execute protections
+/// are exclusive, so [`MmapFlags::READ_EXECUTE`] (which contains the bits of [`MmapFlags::READ_WRITE`]) requires
+/// only read and execute rights.
+fn protection_rights(flags: MmapFlags) -> Rights {
+    if flags.contains(MmapFlags::READ_EXECUTE) {
+        Rights::READ | Rights::EXECUTE
+    } else if flags.contains(MmapFlags::READ_WRITE) {
+        Rights::READ | Rights::WRITE
+    } else if flags.contains(MmapFlags::READ) {
+        Rights::READ
+    } else {
+        Rights::empty()
+    }
+}
+
+/// Returns the channel endpoint referred to by `handle`, if the handle has every right in `required`.
+fn channel_endpoint(handle: Handle, required: Rights) -> Result<Arc<channel::Endpoint>, SyscallError> {
+    with_handles(|handle_table| match &handle_table.get(handle, required)?.object {
+        Object::Channel(endpoint) => Ok(endpoint.clone()),
+        _ => Err(SyscallError::InvalidHandle),
+    })
+}
+
+/// Returns the call/reply endpoint referred to by `handle`, if the handle has every right in `required`.
+fn ipc_endpoint(handle: Handle, required: Rights) -> Result<Arc<endpoint::Endpoint>, SyscallError> {
+    with_handles(|handle_table| match &handle_table.get(handle, required)?.object {
+        Object::Endpoint(endpoint) => Ok(endpoint.clone()),
+        _ => Err(SyscallError::InvalidHandle),
+    })
+}
+
+/// Runs `func` with the current task's address space.
+fn with_user_memory<T>(
+    func: impl FnOnce(
+        &mut crate::memory::address_space::AddressSpace<crate::memory::PhysicalAllocator>,
+    ) -> Result<T, SyscallError>,
+) -> Result<T, SyscallError> {
+    crate::local_state::with_address_space(func).ok_or(SyscallError::NoTask).flatten()
+}
+
+/// Converts a pointer provided by userspace into an address, rejecting null and non-canonical pointers.
+fn user_address<T>(ptr: *const T) -> Result<Address<Virtual>, SyscallError> {
+    Address::new(ptr.addr()).filter(|address| address.get() > 0).ok_or(SyscallError::InvalidPointer)
 }
@@ -0,0 +1,182 @@
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x64::{
+    cpuid,
+    registers::control::{CR0Flags, CR0},
+};
+use crate::memory::AlignedAllocator;
+use core::{alloc::AllocError, mem::MaybeUninit};
+use spin::Once;
+use try_alloc::boxed::TryBox;
+
+/// State components enabled for tasks, if supported: x87, SSE, AVX, and AVX-512 (opmask, and the upper halves of
+/// `ZMM0-15` and all of `ZMM16-31`). Components which must be requested by the task before use (e.g. AMX) aren't
+/// included.
+#[cfg(target_arch = "x86_64")]
+const XCR0_COMPONENTS: u64 = 0b1110_0111;
+
+/// Offset of the x87 FPU control word in the legacy region of the save area.
+#[cfg(target_arch = "x86_64")]
+const FCW_OFFSET: usize = 0;
+/// Offset of `MXCSR` in the legacy region of the save area.
+#[cfg(target_arch = "x86_64")]
+const MXCSR_OFFSET: usize = 24;
+
+#[derive(Debug, Clone, Copy)]
+enum SaveMethod {
+    /// `XSAVE`/`XRSTOR`, with the given components enabled in `XCR0`.
+    Xsave { components: u64 },
+    /// `FXSAVE`/`FXRSTOR`, which only covers x87 and SSE state.
+    Fxsave,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SaveArea {
+    method: SaveMethod,
+    size: usize,
+}
+
+/// How extended state is saved on this system, decided when the first core is set up.
+static SAVE_AREA: Once<SaveArea> = Once::new();
+
+/// Enables the extended state components used by tasks on the current core, and arranges for the first use of
+/// extended state to trap (see [`ExtendedState`]).
+///
+/// ### Safety
+///
+/// Caller must ensure `CR4.OSXSAVE` is already set, if `XSAVE` is supported.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn setup_extended_state() {
+    fn set_xcr0(components: u64) {
+        // ### Safety: `components` only contains components reported as supported by `CPUID`.
+        unsafe {
+            core::arch::asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") components as u32,
+                in("edx") (components >> 32) as u32,
+                options(nostack, nomem, preserves_flags)
+            );
+        }
+    }
+
+    let save_area = *SAVE_AREA.call_once(|| {
+        let save_area = if cpuid::FEATURE_INFO.has_xsave() {
+            // ### Safety: `XSAVE` support implies leaf `0xD` is available.
+            let supported = unsafe { core::arch::x86_64::__cpuid_count(0xD, 0) };
+            let components = (((supported.edx as u64) << 32) | (supported.eax as u64)) & XCR0_COMPONENTS;
+            set_xcr0(components);
+
+            // `EBX` of leaf `0xD` reports the size of the save area for the components currently enabled in `XCR0`.
+            // ### Safety: See above.
+            let size = unsafe { core::arch::x86_64::__cpuid_count(0xD, 0) }.ebx as usize;
+
+            SaveArea { method: SaveMethod::Xsave { components }, size }
+        } else {
+            SaveArea { method: SaveMethod::Fxsave, size: 512 }
+        };
+
+        debug!("Extended state is saved via {:?} ({} bytes per task).", save_area.method, save_area.size);
+
+        save_area
+    });
+
+    if let SaveMethod::Xsave { components } = save_area.method {
+        set_xcr0(components);
+    }
+
+    // ### Safety: The kernel doesn't use extended state, so trapping its use only affects tasks.
+    unsafe { CR0::enable(CR0Flags::TS) };
+}
+
+/// Saved extended (x87, SSE, AVX, ...) state of a task.
+///
+/// Extended state is restored lazily: after each context switch, `CR0.TS` is set, so a task's first use of extended
+/// state raises a device-not-available exception, whose handler restores the task's state (see
+/// [`crate::local_state::restore_extended_state`]). The state is then saved eagerly once the task is switched away
+/// from, so a task can be freely moved between cores.
+pub struct ExtendedState(TryBox<[MaybeUninit<u8>], AlignedAllocator<64>>);
+
+impl ExtendedState {
+    /// Allocates extended state in its initial configuration.
+    #[cfg(target_arch = "x86_64")]
+    pub fn new() -> Result<Self, AllocError> {
+        let save_area = SAVE_AREA.get().expect("extended state has not been set up");
+
+        let mut area = TryBox::new_uninit_slice_in(save_area.size, AlignedAllocator::new())?;
+        area.fill(MaybeUninit::new(0));
+
+        // A zeroed `XSAVE` header restores every component to its initial configuration, except `MXCSR`, which is
+        // always loaded from the legacy region. `FXRSTOR` loads the legacy region as-is, so both are initialized here.
+        let fcw = 0x037F_u16.to_le_bytes();
+        let mxcsr = 0x1F80_u32.to_le_bytes();
+        area[FCW_OFFSET..(FCW_OFFSET + fcw.len())].copy_from_slice(&fcw.map(MaybeUninit::new));
+        area[MXCSR_OFFSET..(MXCSR_OFFSET + mxcsr.len())].copy_from_slice(&mxcsr.map(MaybeUninit::new));
+
+        Ok(Self(area))
+    }
+
+    /// Saves the current core's extended state.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure `CR0.TS` is clear.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+
+        match SAVE_AREA.get().unwrap().method {
+            SaveMethod::Xsave { .. } => core::arch::asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags)
+            ),
+
+            SaveMethod::Fxsave => core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags)),
+        }
+    }
+
+    /// Loads this state as the current core's extended state.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure `CR0.TS` is clear.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn restore(&self) {
+        let area = self.0.as_ptr();
+
+        match SAVE_AREA.get().unwrap().method {
+            SaveMethod::Xsave { .. } => core::arch::asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags, readonly)
+            ),
+
+            SaveMethod::Fxsave => {
+                core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly))
+            }
+        }
+    }
+}
+
+/// Allows the current core to use extended state without trapping.
+///
+/// ### Safety
+///
+/// Caller must ensure the current core's extended state belongs to the task about to use it.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub unsafe fn allow_extended_state() {
+    core::arch::asm!("clts", options(nostack, nomem, preserves_flags));
+}
+
+/// Causes the next use of extended state on the current core to trap.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub fn trap_extended_state() {
+    // ### Safety: Trapping only defers use of extended state until it's been restored.
+    unsafe { CR0::enable(CR0Flags::TS) };
+}
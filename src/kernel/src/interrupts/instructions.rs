@@ -72,6 +72,43 @@ pub fn wait() {
     }
 }
 
+/// Waits for the next interrupt on the current core, as [`wait`] does, using `mwait` where it's supported. The
+/// `mwait` hint requests C1, the same state `hlt` enters; deeper C-states would need a hint chosen from the
+/// sub-states enumerated by CPUID leaf 5.
+#[inline]
+pub fn wait_idle() {
+    #[cfg(target_arch = "x86_64")]
+    if crate::arch::x64::cpuid::FEATURE_INFO.has_monitor_mwait() {
+        // Only interrupts are expected to wake the core, so the monitored line is never written to.
+        static MONITOR_LINE: u64 = 0;
+
+        // ### Safety: `monitor` only arms address monitoring, and `mwait` returns upon any interrupt, as `wait` does.
+        unsafe {
+            asm!(
+                "monitor",
+                in("rax") core::ptr::addr_of!(MONITOR_LINE),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack, nomem, preserves_flags)
+            );
+            // Hint 0 requests C1, without extensions.
+            asm!("mwait", in("eax") 0, in("ecx") 0, options(nostack, nomem, preserves_flags));
+        }
+
+        return;
+    }
+
+    wait();
+}
+
+/// Indefinitely waits for interrupts on the current core, as its idle task.
+#[inline]
+pub fn idle_loop() -> ! {
+    loop {
+        wait_idle();
+    }
+}
+
 /// Indefinitely waits for the next interrupt on the current core.
 #[inline]
 pub fn wait_loop() -> ! {
@@ -49,7 +49,11 @@ pub enum Vector {
     Timer = 0x30,
     Thermal = 0x32,
     Performance = 0x33,
-    /* 0x34..=0x3B free for use */
+    /// Sent to an idle core when a task is queued, so it's scheduled immediately.
+    Wake = 0x34,
+    /// Raised in software by a kernel task to give up the core (see [`crate::proc::wait::WaitQueue::sleep_while`]).
+    Yield = 0x35,
+    /* 0x36..=0x3B free for use */
     Error = 0x3C,
     LINT0 = 0x3D,
     LINT1 = 0x3E,
@@ -67,7 +71,11 @@ pub struct PageFaultHandlerError;
 #[repr(align(0x10))]
 pub unsafe fn pf_handler(address: Address<Virtual>) -> Result<(), PageFaultHandlerError> {
     crate::local_state::with_address_space(|addr_space| {
-        addr_space.demand_map(address).map_err(|_| PageFaultHandlerError)
+        addr_space.demand_map(address).map_err(|_| {
+            debug!("Unresolved page fault at {:#X}, in address space:\n{}", address.get(), addr_space.dump());
+
+            PageFaultHandlerError
+        })
     })
     .ok_or(PageFaultHandlerError)
     .flatten()
@@ -82,6 +90,14 @@ pub unsafe fn irq_handler(irq_vector: u64, ctrl_flow_context: &mut ControlContex
     match Vector::try_from(irq_vector) {
         Ok(Vector::Timer) => crate::local_state::next_task(ctrl_flow_context, arch_context),
 
+        // Only an idle core needs to reschedule; a running task will pick up queued tasks at the end of its slice.
+        Ok(Vector::Wake) if crate::local_state::current_task_uuid().is_none() => {
+            crate::local_state::next_task(ctrl_flow_context, arch_context);
+        }
+
+        // Software interrupts aren't delivered by the APIC, so there's no interrupt to end.
+        Ok(Vector::Yield) => return crate::local_state::yield_task(ctrl_flow_context, arch_context),
+
         _vector_result => {}
     }
 
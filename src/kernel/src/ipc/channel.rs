@@ -0,0 +1,168 @@
+use crate::proc::{handles, task::Task};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Maximum number of messages queued in each direction of a channel.
+pub const QUEUE_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The peer's queue is full, and the sender chose not to wait.
+    QueueFull,
+    /// No message is queued, and the receiver chose not to wait.
+    QueueEmpty,
+    /// The operation can't complete yet, so the calling task has been queued to be woken when it can.
+    Blocked,
+    /// The peer endpoint has been dropped (for receivers, after every queued message has been received).
+    PeerClosed,
+    /// The kernel couldn't allocate memory for the message.
+    OutOfMemory,
+    /// The next message is larger than the receiver accepts. It remains queued.
+    BufferTooSmall,
+}
+
+/// A message sent through a channel.
+pub struct Message {
+    pub bytes: Vec<u8>,
+    /// Object transferred along with the message, which is inserted into the receiver's handle table.
+    pub attachment: Option<handles::Entry>,
+}
+
+/// Messages queued for one endpoint of a channel, and the tasks waiting on them.
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Tasks waiting for a message to be queued.
+    receivers: VecDeque<Task>,
+    /// Tasks waiting for a message to be received, so the queue has room.
+    senders: VecDeque<Task>,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self { messages: VecDeque::new(), receivers: VecDeque::new(), senders: VecDeque::new() }
+    }
+
+    /// Wakes every task waiting on this queue.
+    fn wake_all(&mut self) {
+        self.receivers.drain(..).chain(self.senders.drain(..)).for_each(crate::proc::queue_pending);
+    }
+}
+
+struct Channel {
+    /// Queue of messages received by each endpoint.
+    queues: [Mutex<Queue>; 2],
+    /// Whether each endpoint has been dropped.
+    closed: [AtomicBool; 2],
+}
+
+/// One end of a bidirectional channel. Messages sent through an endpoint are received by its peer, in order.
+///
+/// Blocking operations take a `waiter` function, which is called (while the endpoint's state is locked) only if the
+/// operation must wait. It returns the task to be woken once the operation may be able to complete, which then
+/// retries it; if it returns `None`, the operation fails rather than waiting.
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+/// Creates a connected pair of endpoints.
+pub fn create() -> Result<(Endpoint, Endpoint), Error> {
+    let channel = Arc::try_new(Channel {
+        queues: [Mutex::new(Queue::new()), Mutex::new(Queue::new())],
+        closed: [AtomicBool::new(false), AtomicBool::new(false)],
+    })
+    .map_err(|_| Error::OutOfMemory)?;
+
+    Ok((Endpoint { channel: channel.clone(), side: 0 }, Endpoint { channel, side: 1 }))
+}
+
+impl Endpoint {
+    #[inline]
+    const fn peer_side(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Whether `self` and `other` are endpoints of the same channel.
+    #[inline]
+    pub fn shares_channel(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+
+    /// Queues `message` to be received by the peer endpoint.
+    pub fn send(&self, message: Message, waiter: impl FnOnce() -> Option<Task>) -> Result<(), Error> {
+        let mut queue = self.channel.queues[self.peer_side()].lock();
+
+        if self.channel.closed[self.peer_side()].load(Ordering::Acquire) {
+            Err(Error::PeerClosed)
+        } else if queue.messages.len() >= QUEUE_CAPACITY {
+            let task = waiter().ok_or(Error::QueueFull)?;
+            queue.senders.push_back(task);
+
+            Err(Error::Blocked)
+        } else {
+            queue.messages.try_reserve(1).map_err(|_| Error::OutOfMemory)?;
+            queue.messages.push_back(message);
+
+            if let Some(receiver) = queue.receivers.pop_front() {
+                crate::proc::queue_pending(receiver);
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Receives the next message, passing it to `func` (for instance, to copy it out), and returning its length
+    /// along with the result of `func`. The message is only removed from the queue if `func` succeeds. Messages
+    /// longer than `max_len` are left queued.
+    pub fn recv<T, E>(
+        &self,
+        max_len: usize,
+        func: impl FnOnce(&Message) -> Result<T, E>,
+        waiter: impl FnOnce() -> Option<Task>,
+    ) -> Result<Result<(usize, T), E>, Error> {
+        let mut queue = self.channel.queues[self.side].lock();
+
+        match queue.messages.front() {
+            Some(message) if message.bytes.len() > max_len => Err(Error::BufferTooSmall),
+
+            Some(message) => {
+                let message_len = message.bytes.len();
+                let value = match func(message) {
+                    Ok(value) => value,
+                    Err(err) => return Ok(Err(err)),
+                };
+
+                let message = queue.messages.pop_front();
+                if let Some(sender) = queue.senders.pop_front() {
+                    crate::proc::queue_pending(sender);
+                }
+
+                // Dropping an attached object may lock another channel, so release this one first.
+                drop(queue);
+                drop(message);
+
+                Ok(Ok((message_len, value)))
+            }
+
+            None if self.channel.closed[self.peer_side()].load(Ordering::Acquire) => Err(Error::PeerClosed),
+
+            None => {
+                let task = waiter().ok_or(Error::QueueEmpty)?;
+                queue.receivers.push_back(task);
+
+                Err(Error::Blocked)
+            }
+        }
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.channel.closed[self.side].store(true, Ordering::Release);
+
+        // Waiters on either queue may now fail with `Error::PeerClosed`. Taking each lock also ensures no task can
+        // begin waiting after observing the endpoint as open.
+        self.channel.queues.iter().for_each(|queue| queue.lock().wake_all());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{cpu::SyscallError, interrupts::InterruptCell, proc::task::Task};
+use abi::RegisterMessage;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+use uuid::Uuid;
+
+/// Endpoints each server (by its ID) has received calls from, so callers awaiting its reply can be failed if it
+/// exits (see [`abandon_replies`]). Entries may be stale, as replying doesn't remove them.
+static SERVED: InterruptCell<Mutex<BTreeMap<Uuid, Vec<Weak<Endpoint>>>>> =
+    InterruptCell::new(Mutex::new(BTreeMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No task was provided to wait on the endpoint.
+    NoTask,
+    /// The server hasn't received a call it hasn't replied to.
+    NoCaller,
+}
+
+#[derive(Default)]
+struct State {
+    /// Servers waiting to receive a call.
+    servers: VecDeque<Task>,
+    /// Callers waiting for a server to receive their call.
+    callers: VecDeque<(Task, RegisterMessage)>,
+    /// Callers whose call has been received, waiting for a reply from the server (by its ID) which received it.
+    replies: BTreeMap<Uuid, Task>,
+}
+
+/// A synchronous call/reply rendezvous between client and server tasks. Messages are small enough to be
+/// transferred in registers, and never queued in the kernel beyond the blocked caller itself.
+///
+/// Tasks waiting on an endpoint are stored with the context they resume at, as if their system call just returned;
+/// their result registers are filled in when they're woken (see [`crate::cpu::set_register_message`]). Tasks are
+/// provided by `waiter` functions, which are called only if the task must wait.
+#[derive(Default)]
+pub struct Endpoint {
+    state: Mutex<State>,
+}
+
+impl Endpoint {
+    /// Calls the endpoint with `message`. The caller always waits for the reply. If a server is waiting, it's
+    /// returned (with the message delivered) to be switched to directly.
+    pub fn call(
+        self: &Arc<Self>,
+        message: RegisterMessage,
+        waiter: impl FnOnce() -> Option<Task>,
+    ) -> Result<Option<Task>, Error> {
+        let mut state = self.state.lock();
+        let caller = waiter().ok_or(Error::NoTask)?;
+
+        match state.servers.pop_front() {
+            Some(mut server) => {
+                crate::cpu::set_register_message(&mut server.arch_context, Ok(message));
+                self.await_reply(&mut state, server.uuid(), caller);
+
+                Ok(Some(server))
+            }
+
+            None => {
+                state.callers.push_back((caller, message));
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Receives the next call on behalf of the server `server_uuid`. If there are none, the server waits for one,
+    /// and `None` is returned.
+    pub fn recv(
+        self: &Arc<Self>,
+        server_uuid: Uuid,
+        waiter: impl FnOnce() -> Option<Task>,
+    ) -> Result<Option<RegisterMessage>, Error> {
+        let mut state = self.state.lock();
+
+        match state.callers.pop_front() {
+            Some((caller, message)) => {
+                self.await_reply(&mut state, server_uuid, caller);
+
+                Ok(Some(message))
+            }
+
+            None => {
+                let server = waiter().ok_or(Error::NoTask)?;
+                state.servers.push_back(server);
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Replies to the last call received by the server `server_uuid` with `message`, returning the caller so it
+    /// can be scheduled.
+    pub fn reply(&self, server_uuid: Uuid, message: RegisterMessage) -> Result<Task, Error> {
+        let mut caller = self.state.lock().replies.remove(&server_uuid).ok_or(Error::NoCaller)?;
+        crate::cpu::set_register_message(&mut caller.arch_context, Ok(message));
+
+        Ok(caller)
+    }
+
+    /// Records `caller` as awaiting a reply from `server_uuid`, abandoning any previous caller.
+    fn await_reply(self: &Arc<Self>, state: &mut State, server_uuid: Uuid, caller: Task) {
+        if let Some(mut abandoned_caller) = state.replies.insert(server_uuid, caller) {
+            crate::cpu::set_register_message(&mut abandoned_caller.arch_context, Err(SyscallError::PeerClosed));
+            crate::proc::queue_pending(abandoned_caller);
+        }
+
+        SERVED.with(|served| {
+            let mut served = served.lock();
+            let endpoints = served.entry(server_uuid).or_default();
+
+            if !endpoints.iter().any(|endpoint| core::ptr::eq(endpoint.as_ptr(), Arc::as_ptr(self))) {
+                // Drop the records of endpoints which no longer exist, while there's an opportunity to.
+                endpoints.retain(|endpoint| endpoint.strong_count() > 0);
+                endpoints.push(Arc::downgrade(self));
+            }
+        });
+    }
+
+    /// Fails the call awaiting a reply from `server_uuid`, if there is one.
+    fn abandon_reply(&self, server_uuid: &Uuid) {
+        let caller = self.state.lock().replies.remove(server_uuid);
+
+        if let Some(mut caller) = caller {
+            crate::cpu::set_register_message(&mut caller.arch_context, Err(SyscallError::PeerClosed));
+            crate::proc::queue_pending(caller);
+        }
+    }
+}
+
+/// Fails every call awaiting a reply from the server `server_uuid`, as it's exited and will never reply.
+pub fn abandon_replies(server_uuid: &Uuid) {
+    let Some(endpoints) = SERVED.with(|served| served.lock().remove(server_uuid)) else { return };
+
+    // Endpoints are upgraded outside of the registry's lock, as dropping the last reference wakes their waiters.
+    for endpoint in endpoints.iter().filter_map(Weak::upgrade) {
+        endpoint.abandon_reply(server_uuid);
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+
+        // No task can wait on the endpoint any longer, so fail every waiting call and receive.
+        state
+            .servers
+            .drain(..)
+            .chain(state.callers.drain(..).map(|(caller, _)| caller))
+            .chain(core::mem::take(&mut state.replies).into_values())
+            .for_each(|mut task| {
+                crate::cpu::set_register_message(&mut task.arch_context, Err(SyscallError::PeerClosed));
+                crate::proc::queue_pending(task);
+            });
+    }
+}
@@ -1,11 +1,19 @@
 use crate::{
     exceptions::Exception,
-    memory::{address_space::AddressSpace, PhysicalAllocator, Stack, KMALLOC},
-    proc::{task::Task, Scheduler},
+    interrupts::Vector,
+    memory::{
+        address_space::AddressSpace, magazine::FrameMagazine, stack::GuardedStack, PhysicalAllocator, Stack, KMALLOC,
+    },
+    proc::{
+        task::{EntryPoint, Task, TaskStack},
+        wait::WaitQueue,
+        Scheduler,
+    },
 };
 use core::{
     alloc::Allocator,
     cell::UnsafeCell,
+    num::NonZeroUsize,
     sync::atomic::{AtomicBool, Ordering},
 };
 use try_alloc::boxed::TryBox;
@@ -15,6 +23,16 @@ pub(self) const US_WAIT: u32 = 10000;
 pub(self) const US_FREQ_FACTOR: u32 = US_PER_SEC / US_WAIT;
 
 pub const SYSCALL_STACK_SIZE: usize = 0x4000;
+/// Size of each core's idle task stack, which interrupts taken while idling are also handled on.
+pub const IDLE_STACK_SIZE: usize = 0x4000;
+
+/// Request made by the current kernel task before raising [`Vector::Yield`].
+enum YieldRequest {
+    /// Wait on the queue while the condition holds (see [`wait_on`]).
+    Wait(*const WaitQueue, *const dyn Fn() -> bool),
+    /// Sleep until the deadline, in microseconds since boot (see [`sleep_on_timer`]).
+    Sleep(u64),
+}
 
 pub enum ExceptionCatcher {
     Caught(Exception),
@@ -29,10 +47,15 @@ pub(crate) struct LocalState {
 
     magic: u64,
     core_id: u32,
+    /// Frequency of timer ticks, in Hz (see [`preemption_wait`]).
+    timer_frequency: u16,
 
     catching: AtomicBool,
     exception: UnsafeCell<Option<Exception>>,
     scheduler: Scheduler,
+    frame_magazine: FrameMagazine,
+    /// What the current kernel task is about to block on, set by [`wait_on`] or [`sleep_on_timer`].
+    yield_request: Option<YieldRequest>,
 
     #[cfg(target_arch = "x86_64")]
     idt: Option<TryBox<crate::arch::x64::structures::idt::InterruptDescriptorTable>>,
@@ -50,25 +73,43 @@ impl LocalState {
     }
 }
 
-/// Returns the pointer to the local state structure.
+/// Returns the pointer to the local state structure, if it has been initialized.
 #[inline]
-fn get() -> &'static mut LocalState {
+fn try_get() -> Option<&'static mut LocalState> {
     #[cfg(target_arch = "x86_64")]
     {
         // ### Safety: If MSR is not null, then the `LocalState` has been initialized.
         unsafe { ((crate::arch::x64::registers::msr::IA32_KERNEL_GS_BASE::read()) as *mut LocalState).as_mut() }
-            .unwrap()
     }
 }
 
+/// Returns the pointer to the local state structure.
+#[inline]
+fn get() -> &'static mut LocalState {
+    try_get().unwrap()
+}
+
 /// Initializes the core-local state structure.
 ///
 /// ### Safety
 ///
 /// This function invariantly assumes it will only be called once.
 pub unsafe fn init(core_id: u32, timer_frequency: u16) {
-    let Ok(syscall_stack) = crate::memory::allocate_kernel_stack::<SYSCALL_STACK_SIZE>() else { crate::memory::out_of_memory() };
-    let Ok(idle_task_stack) = crate::memory::allocate_kernel_stack::<0x10>() else { crate::memory::out_of_memory() };
+    let Ok(syscall_stack) = crate::memory::allocate_kernel_stack::<SYSCALL_STACK_SIZE>() else {
+        crate::memory::out_of_memory()
+    };
+    let Ok(idle_task_stack) = GuardedStack::new(NonZeroUsize::new(IDLE_STACK_SIZE).unwrap()) else {
+        crate::memory::out_of_memory()
+    };
+
+    let Ok(idle_task) = Task::new(
+        0,
+        EntryPoint::Function(|| crate::interrupts::idle_loop()),
+        TaskStack::Guarded(idle_task_stack),
+        crate::cpu::default_arch_context(),
+    ) else {
+        crate::memory::out_of_memory()
+    };
 
     let local_state = LocalState {
         syscall_stack_ptr: syscall_stack.as_ptr().add(syscall_stack.len() & !0xF).cast(),
@@ -76,13 +117,13 @@ pub unsafe fn init(core_id: u32, timer_frequency: u16) {
 
         magic: LocalState::MAGIC,
         core_id,
+        timer_frequency,
 
         catching: AtomicBool::new(false),
         exception: UnsafeCell::new(None),
-        scheduler: Scheduler::new(
-            false,
-            Task::new(0, || crate::interrupts::wait_loop(), idle_task_stack, crate::cpu::default_arch_context()),
-        ),
+        scheduler: Scheduler::new(false, core_id, idle_task),
+        frame_magazine: FrameMagazine::new(),
+        yield_request: None,
 
         #[cfg(target_arch = "x86_64")]
         idt: {
@@ -228,7 +269,7 @@ pub unsafe fn begin_scheduling() {
     trace!("Core #{} scheduled.", local_state.core_id);
 
     // ### Safety: Value provided is non-zero.
-    preemption_wait(core::num::NonZeroU16::new_unchecked(1));
+    preemption_wait(Some(core::num::NonZeroU16::new_unchecked(1)), None);
 }
 
 /// ### Safety
@@ -242,34 +283,212 @@ pub unsafe fn next_task(
     local_state.scheduler.next_task(ctrl_flow_context, arch_context);
 }
 
+/// Waits on `wait_queue` while `condition` holds, by raising [`Vector::Yield`] so the current kernel task's
+/// context is saved as it's descheduled.
+///
+/// ### Safety
+///
+/// Caller must ensure the current task is a kernel task, outside of any system call or interrupt handler.
+pub unsafe fn wait_on(wait_queue: &WaitQueue, condition: &dyn Fn() -> bool) {
+    // ### Safety: The references remain valid until the interrupt below has been handled, after which the request
+    //             is cleared.
+    let condition = unsafe { core::mem::transmute::<&dyn Fn() -> bool, &'static dyn Fn() -> bool>(condition) };
+    crate::interrupts::without(|| {
+        get().yield_request = Some(YieldRequest::Wait(wait_queue as *const _, condition as *const _));
+    });
+
+    #[cfg(target_arch = "x86_64")]
+    // The handler accesses memory on (and below) the current stack, so no options are given.
+    core::arch::asm!("int {}", const Vector::Yield as u8);
+}
+
+/// Sleeps until `deadline` (in microseconds since boot), by raising [`Vector::Yield`] so the current kernel task's
+/// context is saved as it's descheduled.
+///
+/// ### Safety
+///
+/// Caller must ensure the current task is a kernel task, outside of any system call or interrupt handler.
+pub unsafe fn sleep_on_timer(deadline: u64) {
+    crate::interrupts::without(|| {
+        get().yield_request = Some(YieldRequest::Sleep(deadline));
+    });
+
+    #[cfg(target_arch = "x86_64")]
+    // The handler accesses memory on (and below) the current stack, so no options are given.
+    core::arch::asm!("int {}", const Vector::Yield as u8);
+}
+
+/// Handles [`Vector::Yield`]: switches to the next task, first moving the current task onto the wait queue
+/// requested by [`wait_on`] (if its condition still holds), or the timer queue requested by [`sleep_on_timer`].
+///
+/// ### Safety
+///
+/// Caller must ensure the contexts are those of the interrupted task.
+pub unsafe fn yield_task(
+    ctrl_flow_context: &mut crate::cpu::ControlContext,
+    arch_context: &mut crate::cpu::ArchContext,
+) {
+    let local_state = get();
+
+    match local_state.yield_request.take() {
+        Some(YieldRequest::Wait(wait_queue, condition)) => {
+            // ### Safety: `wait_on` is still executing on the interrupted task's stack, so its references are valid.
+            let (wait_queue, condition) = unsafe { (&*wait_queue, &*condition) };
+
+            let waiting = wait_queue.wait_while(condition, || {
+                get().scheduler.take_current_task().map(|mut task| {
+                    task.ctrl_flow_context = *ctrl_flow_context;
+                    task.arch_context = *arch_context;
+
+                    task
+                })
+            });
+
+            // If the condition no longer holds, the task simply resumes.
+            if waiting {
+                local_state.scheduler.next_task(ctrl_flow_context, arch_context);
+            }
+        }
+
+        Some(YieldRequest::Sleep(deadline)) => {
+            if let Some(mut task) = local_state.scheduler.take_current_task() {
+                task.ctrl_flow_context = *ctrl_flow_context;
+                task.arch_context = *arch_context;
+
+                local_state.scheduler.sleep_task(deadline, task);
+            }
+
+            local_state.scheduler.next_task(ctrl_flow_context, arch_context);
+        }
+
+        None => local_state.scheduler.next_task(ctrl_flow_context, arch_context),
+    }
+}
+
+/// Sends the interrupt `vector` to the core `core_id`.
+pub fn send_ipi(core_id: u32, vector: Vector) {
+    #[cfg(target_arch = "x86_64")]
+    // ### Safety: Fixed interrupts are handled like any other interrupt by the receiving core.
+    unsafe {
+        get().apic.0.send_int_cmd(apic::InterruptCommand::new(
+            vector as u8,
+            core_id,
+            apic::DeliveryMode::Fixed,
+            false,
+            true,
+        ));
+    }
+}
+
 #[inline]
 pub unsafe fn end_of_interrupt() {
     #[cfg(target_arch = "x86_64")]
     get().apic.0.end_of_interrupt()
 }
 
+/// Arms the timer to interrupt after `interval_wait` ticks, or at `deadline` (in microseconds since boot), whichever
+/// is sooner. If neither is given, the timer is stopped.
+///
 /// ### Safety
 ///
 /// Caller must ensure that setting a new preemption wait will not cause undefined behaviour.
-pub unsafe fn preemption_wait(interval_wait: core::num::NonZeroU16) {
+pub unsafe fn preemption_wait(interval_wait: Option<core::num::NonZeroU16>, deadline: Option<u64>) {
     #[cfg(target_arch = "x86_64")]
     {
-        let (apic, timer_interval) = &get().apic;
+        let local_state = get();
+        let (apic, timer_interval) = &local_state.apic;
+
+        let slice_wait = interval_wait.map(|interval_wait| timer_interval * (interval_wait.get() as u64));
+        let deadline_wait = deadline.map(|deadline| {
+            let remaining_us = deadline.saturating_sub(crate::time::TSC.uptime_us());
+            let deadline_wait =
+                (*timer_interval as u128) * (local_state.timer_frequency as u128) * (remaining_us as u128)
+                    / (US_PER_SEC as u128);
+            // Distant deadlines (e.g. sleeping indefinitely) saturate, rather than wrapping to a near one.
+            let deadline_wait = u64::try_from(deadline_wait).unwrap_or(u64::MAX);
+
+            // A zero wait would stop the one-shot timer, rather than interrupt immediately.
+            deadline_wait.max(1)
+        });
+        // A wait of zero stops the timer in either mode.
+        let wait = slice_wait.into_iter().chain(deadline_wait).min().unwrap_or(0);
+
         match apic.get_timer().get_mode() {
             // ### Safety: Control flow expects timer initial count to be changed.
+            // A nonzero wait is clamped to the largest count, rather than truncated (possibly to zero, which would
+            // stop the timer). The timer is simply re-armed when it expires early.
             apic::TimerMode::OneShot => unsafe {
-                apic.set_timer_initial_count((timer_interval * (interval_wait.get() as u64)) as u32)
+                apic.set_timer_initial_count(u32::try_from(wait).unwrap_or(u32::MAX))
             },
             apic::TimerMode::TscDeadline => unsafe {
-                crate::arch::x64::registers::msr::IA32_TSC_DEADLINE::set(
-                    core::arch::x86_64::_rdtsc() + (timer_interval * (interval_wait.get() as u64)),
-                )
+                let deadline = if wait > 0 { core::arch::x86_64::_rdtsc().saturating_add(wait) } else { 0 };
+                crate::arch::x64::registers::msr::IA32_TSC_DEADLINE::set(deadline)
             },
             apic::TimerMode::Periodic => unimplemented!(),
         }
     }
 }
 
+/// Queues `task` (which must have been taken from this core's scheduler) to be woken once `deadline` has passed, in
+/// microseconds since boot.
+pub fn sleep_task(deadline: u64, task: Task) {
+    get().scheduler.sleep_task(deadline, task);
+}
+
+/// Restores the current task's extended state on this core, after its use trapped. Returns `false` if there's no
+/// current task to restore the state of, or its state couldn't be allocated.
+pub fn restore_extended_state() -> bool {
+    get().scheduler.restore_extended_state()
+}
+
+/// Runs `func` with this core's frame magazine, or returns `None` if the local state hasn't yet been initialized.
+pub fn with_frame_magazine<T>(func: impl FnOnce(&mut FrameMagazine) -> T) -> Option<T> {
+    crate::interrupts::without(|| try_get().map(|local_state| func(&mut local_state.frame_magazine)))
+}
+
+/// Returns the ID of the current core.
+pub fn core_id() -> u32 {
+    get().core_id
+}
+
+/// Runs `func` with the current task, or returns `None` if there's no current task.
+pub fn with_current_task<T>(func: impl FnOnce(&mut Task) -> T) -> Option<T> {
+    get().scheduler.current_task_mut().map(func)
+}
+
+/// Records the current task (if any) entering or leaving a system call, so its time is accounted accordingly.
+pub fn set_in_syscall(in_syscall: bool) {
+    let now = crate::time::TSC.uptime_ticks();
+    with_current_task(|task| task.set_in_syscall(in_syscall, now));
+}
+
+/// Returns the ID of the current task, or `None` if there's no current task.
+pub fn current_task_uuid() -> Option<uuid::Uuid> {
+    get().scheduler.current_task().map(crate::proc::task::Task::uuid)
+}
+
+/// Removes the current task from this core's scheduler, e.g. so it can wait to be woken. Returns `None` if there's
+/// no current task.
+pub fn take_current_task() -> Option<Task> {
+    get().scheduler.take_current_task()
+}
+
+/// Queues `task` to be run by this core's scheduler.
+pub fn push_task(task: Task) {
+    get().scheduler.push_task(task);
+}
+
+/// ### Safety
+///
+/// Caller must ensure that context switching to `task` will not cause undefined behaviour.
+pub unsafe fn switch_to_task(
+    task: Task,
+    ctrl_flow_context: &mut crate::cpu::ControlContext,
+    arch_context: &mut crate::cpu::ArchContext,
+) {
+    get().scheduler.switch_to_task(task, ctrl_flow_context, arch_context);
+}
+
 /// Allows safely running a function that manipulates the current task's address space, or returns `None` if there's no current task.
 pub fn with_address_space<T>(with_fn: impl FnOnce(&mut AddressSpace<PhysicalAllocator>) -> T) -> Option<T> {
     get().scheduler.current_task().and_then(|task| crate::memory::address_space::with(&task.uuid(), with_fn))
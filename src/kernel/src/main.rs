@@ -59,9 +59,10 @@ mod cpu;
 mod elf;
 mod exceptions;
 mod interrupts;
+mod ipc;
 mod local_state;
 mod memory;
-// mod modules;
+mod modules;
 mod num;
 mod panic;
 mod proc;
@@ -196,22 +197,21 @@ unsafe extern "C" fn _entry() -> ! {
             range: core::ops::Range<usize>,
             attributes: PageAttributes,
         ) {
-            for address in range.step_by(0x1000) {
-                to_mapper
-                    .map(
-                        Address::new_truncate(address),
-                        PageDepth::MIN,
-                        from_mapper
-                            .get_mapped_to({
-                                let addr = Address::new_truncate(address);
-                                info!("ADDR: {:?}", addr);
-                                addr
-                            })
-                            .unwrap(),
-                        false,
-                        attributes,
-                    )
-                    .unwrap();
+            let mut address = range.start;
+            while address < range.end {
+                let frame = from_mapper.get_mapped_to(Address::new_truncate(address)).unwrap();
+
+                // Coalesce the physically contiguous run of pages, so it can be mapped with huge pages.
+                let mut run_end = address + 0x1000;
+                while run_end < range.end
+                    && from_mapper.get_mapped_to(Address::new_truncate(run_end))
+                        == Address::new(frame.get() + (run_end - address))
+                {
+                    run_end += 0x1000;
+                }
+
+                to_mapper.map_range(Address::new_truncate(address), frame, run_end - address, attributes).unwrap();
+                address = run_end;
             }
         }
 
@@ -270,17 +270,17 @@ unsafe extern "C" fn _entry() -> ! {
                         }
             };
 
-            for phys_base in (entry.base..(entry.base + entry.len)).step_by(0x1000).map(|p| p as usize) {
-                kmapper
-                    .map(
-                        Address::new_truncate(hhdm_address().get() + phys_base),
-                        PageDepth::MIN,
-                        Address::new_truncate(phys_base as usize),
-                        false,
-                        page_attributes,
-                    )
-                    .unwrap();
-            }
+            let page_size = core::num::NonZeroUsize::new(0x1000).unwrap();
+            let phys_base = lzstd::align_down(entry.base as usize, page_size);
+            let phys_end = lzstd::align_up((entry.base + entry.len) as usize, page_size);
+            kmapper
+                .map_range(
+                    Address::new_truncate(hhdm_address().get() + phys_base),
+                    Address::new_truncate(phys_base),
+                    phys_end - phys_base,
+                    page_attributes,
+                )
+                .unwrap();
 
             // ... map architecture-specific memory ...
 
@@ -300,6 +300,10 @@ unsafe extern "C" fn _entry() -> ! {
             }
         }
 
+        // Ensure all higher-half root entries exist, so every address space shares the kernel's page tables.
+        kmapper.populate_higher_half().unwrap();
+        trace!("Kernel mappings:\n{}", kmapper.dump());
+
         debug!("Switching to kernel page tables...");
         // ### Safety: Kernel mapper has mapped all existing memory references, so commiting changes nothing from the software perspective.
         unsafe { kmapper.commit_vmem_register() }.unwrap();
@@ -309,6 +313,9 @@ unsafe extern "C" fn _entry() -> ! {
     debug!("Initializing ACPI interface...");
     crate::acpi::init_interface();
 
+    debug!("Initializing timestamp counter...");
+    spin::Lazy::force(&crate::time::TSC);
+
     /* symbols */
     if !PARAMETERS.low_memory {
         debug!("Parsing kernel symbols...");
@@ -374,12 +381,11 @@ unsafe extern "C" fn _entry() -> ! {
         debug!("Kernel is running in low memory mode; pretty stack tracing will be disabled.");
     }
 
-    // TODO modules
-    // debug!("Loading kernel modules...");
-    // crate::modules::load_modules();
+    debug!("Loading kernel modules...");
+    crate::modules::load_modules();
 
     /* smp */
-    {
+    let bsp_lapic_id = {
         static LIMINE_SMP: limine::LimineSmpRequest = limine::LimineSmpRequest::new(crate::boot::LIMINE_REV)
             // Enable x2APIC mode if available.
             .flags(0b1);
@@ -413,10 +419,15 @@ unsafe extern "C" fn _entry() -> ! {
                     _idle_forever
                 };
             }
+
+            bsp_lapic_id
         } else {
             debug!("Bootloader has not provided any SMP information.");
+
+            // Without SMP information, the BSP is assumed to be the only core, with the first LAPIC ID.
+            0
         }
-    }
+    };
 
     /* configure I/O APIC redirections */
     #[cfg(target_arch = "x86_64")]
@@ -510,7 +521,8 @@ unsafe extern "C" fn _entry() -> ! {
     debug!("Reclaiming bootloader memory...");
     crate::boot::reclaim_boot_memory();
 
-    kernel_thread_setup(0)
+    // The BSP is identified by its LAPIC ID, as the other cores are.
+    kernel_thread_setup(bsp_lapic_id)
 }
 
 /// ### Safety
@@ -1,7 +1,9 @@
+use super::{MappingDump, Walker};
 use crate::memory::{AttributeModify, Page, PageAttributes, PageDepth, PageTable, PageTableEntry, PagingError, PMM};
+use core::num::NonZeroU32;
 use lzstd::{
     mem::{Mut, Ref},
-    Address, Frame,
+    Address, Frame, PAGE_SHIFT, PAGE_SIZE, TABLE_INDEX_SHIFT,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -25,7 +27,7 @@ unsafe impl Send for Mapper {}
 impl Mapper {
     /// Attempts to construct a new page manager. Returns `None` if the PMM could not provide a root frame.
     pub fn new() -> Option<Self> {
-        PMM.next_frame().ok().map(|root_frame| {
+        crate::memory::next_frame().ok().map(|root_frame| {
             // Safety: Pointer is guaranteed valid due HHDM guarantee from kernel, and renting guarantees from PMM.
             unsafe { core::ptr::write_bytes(crate::memory::hhdm_address().as_ptr().add(root_frame.get()), 0, 0x1000) };
 
@@ -40,6 +42,294 @@ impl Mapper {
         Self { root_frame, entry: PageTableEntry::new(root_frame, PageAttributes::PRESENT) }
     }
 
+    /// Attempts to construct a new page manager whose higher half (kernel space) shares the page tables of the
+    /// provided mapper. Returns `None` if the PMM could not provide a root frame.
+    ///
+    /// REMARK: Only the root table entries are copied, so `kernel_mapper` should have its higher half populated
+    ///         (see [`Mapper::populate_higher_half`]) for its future mappings to be reflected in this mapper.
+    pub fn new_sharing_higher_half(kernel_mapper: &Mapper) -> Option<Self> {
+        let mapper = Self::new()?;
+
+        let higher_half = Self::HIGHER_HALF_INDEXES;
+        // Safety: Both root frames are valid page tables, and `HIGHER_HALF_INDEXES` is bounded by the table size.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                kernel_mapper.root_table_ptr().add(higher_half.start),
+                mapper.root_table_ptr().add(higher_half.start),
+                higher_half.len(),
+            );
+        }
+
+        Some(mapper)
+    }
+
+    /// Range of root table entry indexes that cover the higher half of the address space.
+    const HIGHER_HALF_INDEXES: core::ops::Range<usize> = 256..512;
+
+    #[inline]
+    fn root_table_ptr(&self) -> *mut PageTableEntry {
+        // Safety: Root frame is required to be a valid page table, and the HHDM is guaranteed valid by the kernel.
+        unsafe { crate::memory::hhdm_address().as_ptr().add(self.root_frame.get()).cast() }
+    }
+
+    /// Ensures every higher-half entry of the root table points to a page table, allocating them as required. This
+    /// allows mappers created via [`Mapper::new_sharing_higher_half`] to observe future higher-half mappings.
+    pub fn populate_higher_half(&mut self) -> Result<(), MapperError> {
+        for index in Self::HIGHER_HALF_INDEXES {
+            // Safety: Index is bounded by the table size.
+            let entry = unsafe { &mut *self.root_table_ptr().add(index) };
+
+            if !entry.is_present() {
+                let frame = crate::memory::next_frame().map_err(|_| MapperError::AllocError)?;
+                // Safety: Pointer is guaranteed valid due HHDM guarantee from kernel, and renting guarantees from PMM.
+                unsafe { core::ptr::write_bytes(crate::memory::hhdm_address().as_ptr().add(frame.get()), 0, 0x1000) };
+
+                *entry = PageTableEntry::new(frame, PageAttributes::PRESENT | PageAttributes::WRITABLE);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attempts to construct a new page manager with the same lower half (userspace) mappings as this one, and
+    /// which shares its higher half. Writable pages are made read-only and [`PageAttributes::COPY_ON_WRITE`] in
+    /// both mappers (except [`PageAttributes::SHARED`] pages), and every frame mapped by both gains a reference in
+    /// the PMM.
+    ///
+    /// If cloning fails, the partial clone is freed (dropping the references it took), and pages of this mapper
+    /// which no longer share their frame are made writable again, before the error is returned.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure any other core using this mapper invalidates its TLB entries, as write permissions are
+    /// removed from its pages.
+    pub unsafe fn clone_copy_on_write(&mut self) -> Result<Self, MapperError> {
+        let mut mapper = Self::new().ok_or(MapperError::AllocError)?;
+
+        let higher_half = Self::HIGHER_HALF_INDEXES;
+        // Safety: Both root frames are valid page tables, and `HIGHER_HALF_INDEXES` is bounded by the table size.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.root_table_ptr().add(higher_half.start),
+                mapper.root_table_ptr().add(higher_half.start),
+                higher_half.len(),
+            );
+        }
+
+        for index in 0..higher_half.start {
+            // Safety: Index is bounded by the table size, and the root tables are distinct.
+            let (from_entry, to_entry) =
+                unsafe { (&mut *self.root_table_ptr().add(index), &mut *mapper.root_table_ptr().add(index)) };
+
+            // Safety: Both entries are within the lower half of their root tables.
+            let result = unsafe {
+                Self::clone_entry_copy_on_write(
+                    from_entry,
+                    to_entry,
+                    PageDepth::MAX,
+                    Self::entry_base(index, PageDepth::MAX),
+                )
+            };
+
+            if let Err(err) = result {
+                // Safety: The partial clone has never been active on any core, and isn't used again.
+                unsafe { mapper.teardown() };
+
+                for index in 0..higher_half.start {
+                    // Safety: Index is bounded by the table size, and the entry is within the lower half.
+                    unsafe { Self::restore_entry_writable(&mut *self.root_table_ptr().add(index), PageDepth::MAX) };
+                }
+
+                return Err(err);
+            }
+        }
+
+        Ok(mapper)
+    }
+
+    /// Frees every frame mapped in the lower half (userspace) of this mapper, along with the page tables mapping
+    /// them, and clears the lower half of the root table. Shared frames only lose this mapper's reference.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure the mapper isn't active on any core, and that nothing references its lower-half memory.
+    pub unsafe fn free_lower_half(&mut self) {
+        for index in 0..Self::HIGHER_HALF_INDEXES.start {
+            // Safety: Index is bounded by the table size.
+            let entry = unsafe { &mut *self.root_table_ptr().add(index) };
+
+            // Safety: The entry is within the lower half of the root table.
+            unsafe { Self::free_entry(entry, PageDepth::MAX) };
+            *entry = PageTableEntry::empty();
+        }
+    }
+
+    /// Frees everything this mapper owns: every frame mapped in its lower half, every page table of its lower half,
+    /// and its root table. Higher-half page tables are shared with the kernel mapper, so are left as-is.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure the mapper isn't active on any core, that nothing references its lower-half memory, and
+    /// that the mapper isn't used again.
+    pub unsafe fn teardown(&mut self) {
+        // Safety: Caller is required to maintain the same invariants.
+        unsafe { self.free_lower_half() };
+        crate::memory::free_frame(self.root_frame).expect("root table frame was not locked");
+    }
+
+    /// Frees whatever `entry` (an entry of a table at `depth`) maps, recursing into any subtables.
+    ///
+    /// ### Safety
+    ///
+    /// The entry must belong to the lower half of a valid page table, which isn't active on any core.
+    unsafe fn free_entry(entry: &PageTableEntry, depth: PageDepth) {
+        let attributes = entry.get_attributes();
+
+        if !attributes.contains(PageAttributes::PRESENT) {
+            // Demand pages which were never touched have no frame to free.
+        } else if depth > PageDepth::MIN && !attributes.contains(PageAttributes::HUGE) {
+            // Safety: Frame is a valid page table.
+            let table =
+                unsafe { crate::memory::hhdm_address().as_ptr().add(entry.get_frame().get()) }.cast::<PageTableEntry>();
+            let next_depth = PageDepth::new(NonZeroU32::new(depth.get().get() - 1).unwrap());
+
+            for index in 0..(1 << TABLE_INDEX_SHIFT.get()) {
+                // Safety: Index is bounded by the table size.
+                unsafe { Self::free_entry(&*table.add(index), next_depth) };
+            }
+
+            crate::memory::free_frame(entry.get_frame()).expect("page table frame was not locked");
+        } else {
+            let frame_count = 1usize << (TABLE_INDEX_SHIFT.get() * (depth.get().get() - 1));
+
+            for frame_index in 0..frame_count {
+                // Frames which weren't allocated from the PMM (e.g. device memory) aren't locked, so aren't freed.
+                if let Some(frame) = Address::new(entry.get_frame().get() + (frame_index << PAGE_SHIFT.get())) {
+                    crate::memory::free_frame(frame).ok();
+                }
+            }
+        }
+    }
+
+    /// Makes every copy-on-write page under `entry` (an entry of a table at `depth`) writable again, if its frame
+    /// has no other references. This undoes a failed [`Self::clone_copy_on_write`], once the partial clone's
+    /// references have been dropped.
+    ///
+    /// ### Safety
+    ///
+    /// The entry must belong to the lower half of a valid page table, and any core using it must invalidate its
+    /// TLB entries before relying on the restored permissions.
+    unsafe fn restore_entry_writable(entry: &mut PageTableEntry, depth: PageDepth) {
+        let attributes = entry.get_attributes();
+
+        if !attributes.contains(PageAttributes::PRESENT) {
+            // Demand pages are never shared.
+        } else if depth > PageDepth::MIN && !attributes.contains(PageAttributes::HUGE) {
+            // Safety: Frame is a valid page table.
+            let table =
+                unsafe { crate::memory::hhdm_address().as_ptr().add(entry.get_frame().get()) }.cast::<PageTableEntry>();
+            let next_depth = PageDepth::new(NonZeroU32::new(depth.get().get() - 1).unwrap());
+
+            for index in 0..(1 << TABLE_INDEX_SHIFT.get()) {
+                // Safety: Index is bounded by the table size.
+                unsafe { Self::restore_entry_writable(&mut *table.add(index), next_depth) };
+            }
+        } else if attributes.contains(PageAttributes::COPY_ON_WRITE)
+            && PMM.frame_references(entry.get_frame()).is_ok_and(|references| references == 1)
+        {
+            // Safety: The page is mapped to the same frame, which it now holds the sole reference to.
+            unsafe {
+                entry.set_attributes(PageAttributes::COPY_ON_WRITE, AttributeModify::Remove);
+                entry.set_attributes(PageAttributes::WRITABLE, AttributeModify::Insert);
+            }
+        }
+    }
+
+    /// Base address of the memory covered by the entry at `index` of a table at `depth`, relative to the base of
+    /// the table.
+    #[inline]
+    pub(super) const fn entry_base(index: usize, depth: PageDepth) -> usize {
+        index << (PAGE_SHIFT.get() + (TABLE_INDEX_SHIFT.get() * (depth.get().get() - 1)))
+    }
+
+    /// Copies `from_entry` (an entry of a table at `depth`, covering memory from `base`) into `to_entry`, allocating
+    /// copies of any subtables, and sharing any mapped frames copy-on-write.
+    ///
+    /// ### Safety
+    ///
+    /// `to_entry` must be empty, and both entries must belong to the lower half of valid page tables.
+    unsafe fn clone_entry_copy_on_write(
+        from_entry: &mut PageTableEntry,
+        to_entry: &mut PageTableEntry,
+        depth: PageDepth,
+        base: usize,
+    ) -> Result<(), MapperError> {
+        let attributes = from_entry.get_attributes();
+
+        if !attributes.contains(PageAttributes::PRESENT) {
+            // Demand pages have no frame yet, so each mapper will fault in its own.
+            if attributes.contains(PageAttributes::DEMAND) {
+                *to_entry = *from_entry;
+            }
+
+            Ok(())
+        } else if depth > PageDepth::MIN && !attributes.contains(PageAttributes::HUGE) {
+            let table_frame = crate::memory::next_frame().map_err(|_| MapperError::AllocError)?;
+            let hhdm_ptr = crate::memory::hhdm_address().as_ptr();
+            // Safety: Pointer is guaranteed valid due HHDM guarantee from kernel, and renting guarantees from PMM.
+            unsafe { core::ptr::write_bytes(hhdm_ptr.add(table_frame.get()), 0, 0x1000) };
+            *to_entry = PageTableEntry::new(table_frame, attributes);
+
+            // Safety: Both frames are valid page tables.
+            let (from_table, to_table) = unsafe {
+                (
+                    hhdm_ptr.add(from_entry.get_frame().get()).cast::<PageTableEntry>(),
+                    hhdm_ptr.add(table_frame.get()).cast::<PageTableEntry>(),
+                )
+            };
+            let next_depth = PageDepth::new(NonZeroU32::new(depth.get().get() - 1).unwrap());
+
+            for index in 0..(1 << TABLE_INDEX_SHIFT.get()) {
+                // Safety: Index is bounded by the table size, and the tables are distinct.
+                unsafe {
+                    Self::clone_entry_copy_on_write(
+                        &mut *from_table.add(index),
+                        &mut *to_table.add(index),
+                        next_depth,
+                        base + Self::entry_base(index, next_depth),
+                    )
+                }?;
+            }
+
+            Ok(())
+        } else if depth > PageDepth::MIN {
+            // Reference counts are kept per-frame, so huge pages can't be shared (and are never mapped in userspace).
+            Err(MapperError::PagingError(PagingError::WalkInterrupted))
+        } else {
+            PMM.share_frame(from_entry.get_frame()).map_err(|_| MapperError::AllocError)?;
+
+            if attributes.contains(PageAttributes::WRITABLE) && !attributes.contains(PageAttributes::SHARED) {
+                // Safety: The page remains mapped to the same frame, and will be copied before it's written.
+                unsafe {
+                    from_entry.set_attributes(PageAttributes::WRITABLE, AttributeModify::Remove);
+                    from_entry.set_attributes(PageAttributes::COPY_ON_WRITE, AttributeModify::Insert);
+                }
+
+                #[cfg(target_arch = "x86_64")]
+                crate::arch::x64::instructions::tlb::invlpg(Address::new_truncate(base));
+            }
+
+            *to_entry = *from_entry;
+
+            Ok(())
+        }
+    }
+
+    #[inline]
+    pub const fn root_frame(&self) -> Address<Frame> {
+        self.root_frame
+    }
+
     fn with_root_table<T>(&self, func: impl FnOnce(PageTable<Ref>) -> T) -> T {
         // Safety: `Self` requires that the entry be valid, so it can be safely constructed into a page table.
         func(unsafe { PageTable::<Ref>::new(PageDepth::MAX, &self.entry).unwrap_unchecked() })
@@ -93,14 +383,67 @@ impl Mapper {
 
         #[cfg(debug_assertions)]
         if result.is_ok() {
-            debug_assert_eq!(self.get_mapped_to(page), Some(frame));
+            if attributes.contains(PageAttributes::PRESENT) {
+                debug_assert_eq!(self.get_mapped_to(page), Some(frame));
+            }
             debug_assert_eq!(self.get_page_attributes(page), Some(attributes));
         }
 
         result
     }
 
-    /// Unmaps the given page, optionally freeing the frame the page points to within the given [`FrameManager`].
+    /// Maps `len` bytes of memory (a multiple of the page size) from `page` to `frame`, mapping each part of the range
+    /// with the largest page size it's aligned to, and which fits within the range. Frames aren't locked.
+    pub fn map_range(
+        &mut self,
+        page: Address<Page>,
+        frame: Address<Frame>,
+        len: usize,
+        attributes: PageAttributes,
+    ) -> Result<(), MapperError> {
+        let mut offset = 0;
+
+        while offset < len {
+            let page = Address::new(page.get() + offset).ok_or(MapperError::UnalignedPageAddress)?;
+            let frame = Address::new_truncate(frame.get() + offset);
+
+            let depth = (1..=PageDepth::MAX.get().get())
+                .rev()
+                .map(|depth| PageDepth::new(NonZeroU32::new(depth).unwrap()))
+                .find(|depth| {
+                    let page_size = depth.page_size();
+
+                    depth.can_map_pages()
+                        && (page.get() & (page_size - 1)) == 0
+                        && (frame.get() & (page_size - 1)) == 0
+                        && (len - offset) >= page_size
+                        // Memory already mapped with smaller pages isn't replaced by a huge page.
+                        && !self.is_table(page, *depth)
+                })
+                .unwrap_or(PageDepth::MIN);
+
+            self.map(page, depth, frame, false, attributes)?;
+            offset += depth.page_size();
+        }
+
+        Ok(())
+    }
+
+    /// Whether the entry of the table at `depth` for `page` points to a subtable, rather than mapping a page.
+    fn is_table(&self, page: Address<Page>, depth: PageDepth) -> bool {
+        depth > PageDepth::MIN
+            && self.with_root_table(|root_table| {
+                root_table.with_entry(page, Some(depth), |entry| {
+                    entry.map_or(false, |entry| {
+                        entry.is_present() && !entry.get_attributes().contains(PageAttributes::HUGE)
+                    })
+                })
+            })
+    }
+
+    /// Unmaps the given page, optionally freeing the frame the page points to within the given [`FrameManager`]. If
+    /// `to_depth` is deeper than the huge page which maps `page`, the huge page is split, and only part of it is
+    /// unmapped. Otherwise, every frame of a huge page is freed.
     ///
     /// ### Safety
     ///
@@ -111,18 +454,29 @@ impl Mapper {
         to_depth: Option<PageDepth>,
         free_frame: bool,
     ) -> Result<(), PagingError> {
+        let depth = match to_depth {
+            Some(to_depth) => to_depth,
+            None => self.get_page_depth(page).ok_or(PagingError::NotMapped)?,
+        };
+
         self.with_root_table_mut(|mut root_table| {
-            root_table.with_entry_mut(page, to_depth, |entry| {
+            root_table.with_entry_mut(page, Some(depth), |entry| {
                 entry.map(|entry| {
                     // ### Safety: We've got an explicit directive from the caller to unmap this page, so the caller must ensure that's a valid operation.
-                    unsafe { entry.set_attributes(PageAttributes::PRESENT, AttributeModify::Remove) };
+                    unsafe {
+                        entry.set_attributes(PageAttributes::PRESENT | PageAttributes::DEMAND, AttributeModify::Remove);
+                    };
 
                     let frame = entry.get_frame();
                     // ### Safety: See above.
                     unsafe { entry.set_frame(Address::new_truncate(0)) };
 
                     if free_frame {
-                        PMM.free_frame(frame).unwrap();
+                        for offset in (0..depth.page_size()).step_by(PAGE_SIZE) {
+                            // Frames which weren't allocated from the PMM (e.g. device memory) aren't locked, so
+                            // aren't freed.
+                            crate::memory::free_frame(Address::new_truncate(frame.get() + offset)).ok();
+                        }
                     }
 
                     // Invalidate the page in the TLB.
@@ -133,33 +487,77 @@ impl Mapper {
         })
     }
 
-    pub fn auto_map(&mut self, page: Address<Page>, attributes: PageAttributes) -> Result<(), MapperError> {
-        match PMM.next_frame() {
-            Ok(frame) => {
-                self.map(page, PageDepth::MIN, frame, !attributes.contains(PageAttributes::DEMAND), attributes)
+    /// Maps the given page to a newly allocated frame. If `attributes` contains [`PageAttributes::DEMAND`], no
+    /// frame is allocated, and the page is left non-present until it is demand mapped.
+    pub fn auto_map(&mut self, page: Address<Page>, mut attributes: PageAttributes) -> Result<(), MapperError> {
+        if attributes.contains(PageAttributes::DEMAND) {
+            attributes.remove(PageAttributes::PRESENT);
+            self.map(page, PageDepth::MIN, Address::new_truncate(0), false, attributes)
+        } else {
+            match crate::memory::next_frame() {
+                // `next_frame` returns an already-locked frame.
+                Ok(frame) => self.map(page, PageDepth::MIN, frame, false, attributes),
+                Err(_) => Err(MapperError::AllocError),
             }
-            Err(_) => Err(MapperError::AllocError),
         }
     }
 
     /* STATE QUERYING */
 
+    /// Whether the page is mapped, either to a frame or on demand.
     pub fn is_mapped(&self, page: Address<Page>, depth: Option<PageDepth>) -> bool {
-        self.with_root_table(|root_table| root_table.with_entry(page, depth, |entry| entry.is_ok()))
+        self.with_root_table(|root_table| {
+            root_table.with_entry(page, depth, |entry| {
+                entry.map_or(false, |entry| {
+                    entry.is_present() || entry.get_attributes().contains(PageAttributes::DEMAND)
+                })
+            })
+        })
     }
 
     pub fn is_mapped_to(&self, page: Address<Page>, frame: Address<Frame>) -> bool {
-        self.with_root_table(|root_table| {
-            root_table.with_entry(page, None, |entry| entry.map(|entry| entry.get_frame() == frame).unwrap_or(false))
-        })
+        self.get_mapped_to(page) == Some(frame)
     }
 
+    /// Returns the frame which `page` is mapped to. Pages within huge pages are mapped to the corresponding frame
+    /// within the huge page's memory.
     pub fn get_mapped_to(&self, page: Address<Page>) -> Option<Address<Frame>> {
+        let depth = self.get_page_depth(page)?;
+
         self.with_root_table(|root_table| {
-            root_table.with_entry(page, None, |entry| entry.ok().map(|entry| entry.get_frame()))
+            root_table.with_entry(page, Some(depth), |entry| {
+                entry.ok().filter(|entry| entry.is_present()).map(|entry| {
+                    Address::new_truncate(entry.get_frame().get() + (page.get() & (depth.page_size() - 1)))
+                })
+            })
         })
     }
 
+    /// Returns the depth of the table whose entry maps `page`, which determines the size of the page (see
+    /// [`PageDepth::page_size`]), or `None` if the page isn't mapped by any table.
+    pub fn get_page_depth(&self, page: Address<Page>) -> Option<PageDepth> {
+        self.with_root_table(|root_table| root_table.page_depth(page))
+    }
+
+    /* WALKING */
+
+    /// Returns an iterator over every page mapped by this mapper, to a frame or on demand.
+    pub fn walk(&self) -> Walker<'_> {
+        // Safety: Root frame is required to be a valid page table, and the borrow prevents it from being modified.
+        unsafe { Walker::new(self.root_frame, 0..Self::HIGHER_HALF_INDEXES.end) }
+    }
+
+    /// Returns an iterator over every page mapped in the lower half (userspace) of this mapper.
+    pub fn walk_lower_half(&self) -> Walker<'_> {
+        // Safety: See [`Mapper::walk`].
+        unsafe { Walker::new(self.root_frame, 0..Self::HIGHER_HALF_INDEXES.start) }
+    }
+
+    /// Returns a printable listing of every page mapped by this mapper, coalescing contiguous runs of pages.
+    pub fn dump(&self) -> MappingDump<'_> {
+        MappingDump(self.walk())
+    }
+
     /* STATE CHANGING */
 
     pub fn get_page_attributes(&self, page: Address<Page>) -> Option<PageAttributes> {
@@ -171,6 +569,12 @@ impl Mapper {
         })
     }
 
+    /// Changes the attributes of the page `page`. If `depth` is deeper than the huge page which maps `page`, the huge
+    /// page is split, and only part of it is changed.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure changing the attributes of the page does not cause any memory corruption side effects.
     pub unsafe fn set_page_attributes(
         &mut self,
         page: Address<Page>,
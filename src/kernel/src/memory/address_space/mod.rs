@@ -1,37 +1,62 @@
 mod mapper;
+mod regions;
+mod walker;
+
 pub use mapper::*;
+use regions::Regions;
+pub use walker::*;
 
 use crate::{
     interrupts::InterruptCell,
-    memory::{PageAttributes, PhysicalAllocator},
+    memory::{hhdm_address, AttributeModify, PageAttributes, PageDepth, PagingRegister, PhysicalAllocator},
 };
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::{
     alloc::{Allocator, Layout},
     num::NonZeroUsize,
-    ops::ControlFlow,
     ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
 };
-use lzstd::{Address, PAGE_SIZE};
+use lzstd::{Address, Frame, PAGE_MASK, PAGE_SIZE};
 use spin::{Lazy, Mutex, RwLock};
-use try_alloc::vec::TryVec;
 use uuid::Uuid;
 
-use super::Virtual;
+use super::{Page, Virtual};
 
 static ADDRESS_SPACES: InterruptCell<
     Lazy<RwLock<BTreeMap<Uuid, Mutex<AddressSpace<PhysicalAllocator>>, PhysicalAllocator>>>,
 > = InterruptCell::new(Lazy::new(|| RwLock::new(BTreeMap::new_in(&*super::PMM))));
 
 pub fn register(uuid: Uuid, size: NonZeroUsize) -> Result<(), Error> {
-    let address_space = unsafe { AddressSpace::new_in(size, &*super::PMM).map_err(|_| Error) }?;
+    let address_space = unsafe { AddressSpace::new_in(size, &*super::PMM) }?;
+
+    ADDRESS_SPACES.with(|address_spaces| {
+        let mut guard = address_spaces.write();
+        guard.try_insert(uuid, Mutex::new(address_space)).map(|_| ()).map_err(|_| Error::Invalid)
+    })
+}
+
+/// Registers a copy-on-write clone (see [`AddressSpace::clone_copy_on_write`]) of the address space of
+/// `source_uuid` for `uuid`.
+pub fn register_clone(uuid: Uuid, source_uuid: &Uuid) -> Result<(), Error> {
+    let address_space =
+        with(source_uuid, |address_space| address_space.clone_copy_on_write()).ok_or(Error::Invalid)??;
 
     ADDRESS_SPACES.with(|address_spaces| {
         let mut guard = address_spaces.write();
-        guard.try_insert(uuid, Mutex::new(address_space)).map(|_| ()).map_err(|_| Error)
+        guard.try_insert(uuid, Mutex::new(address_space)).map(|_| ()).map_err(|_| Error::Invalid)
     })
 }
 
+/// Removes the address space of `uuid`, returning it so it can be dropped (freeing its memory) outside of the lock.
+///
+/// ### Safety
+///
+/// Caller must ensure the address space isn't active on any core.
+pub unsafe fn unregister(uuid: &Uuid) -> Option<AddressSpace<PhysicalAllocator>> {
+    ADDRESS_SPACES.with(|address_spaces| address_spaces.write().remove(uuid)).map(Mutex::into_inner)
+}
+
 pub fn with<T>(uuid: &Uuid, func: impl FnOnce(&mut AddressSpace<PhysicalAllocator>) -> T) -> Option<T> {
     ADDRESS_SPACES.with(|address_spaces| {
         let address_spaces = address_spaces.read();
@@ -42,14 +67,7 @@ pub fn with<T>(uuid: &Uuid, func: impl FnOnce(&mut AddressSpace<PhysicalAllocato
     })
 }
 
-bitflags::bitflags! {
-    pub struct MmapFlags : usize {
-        const READ = 0b1;
-        const READ_WRITE = 0b11;
-        const READ_EXECUTE = 0b111;
-        const NOT_DEMAND = 0b1000;
-    }
-}
+pub use abi::MmapFlags;
 
 impl From<MmapFlags> for PageAttributes {
     fn from(flags: MmapFlags) -> Self {
@@ -91,144 +109,631 @@ impl From<PageAttributes> for MmapFlags {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Error;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The request doesn't describe memory of the address space which it can be applied to.
+    Invalid,
+    /// Memory (or a free range of the address space) couldn't be allocated.
+    OutOfMemory,
+    /// A fixed mapping which mustn't replace existing mappings overlaps them (see [`MmapFlags::FIXED_NOREPLACE`]).
+    AlreadyMapped,
+}
+
+impl From<MapperError> for Error {
+    fn from(err: MapperError) -> Self {
+        match err {
+            MapperError::AllocError => Error::OutOfMemory,
+            _ => Error::Invalid,
+        }
+    }
+}
+
+/// The most permissive protection a mapping may ever be given (see [`AddressSpace::map_frames`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxProtection {
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl MaxProtection {
+    /// Attributes which record this limit in a mapping's pages, so it's kept by any later protection change.
+    fn deny_attributes(self) -> PageAttributes {
+        let mut attributes = PageAttributes::empty();
+        attributes.set(PageAttributes::DENY_WRITE, !self.write);
+        attributes.set(PageAttributes::DENY_EXECUTE, !self.execute);
+
+        attributes
+    }
+}
+
+/// Page attributes for the memory protection specified by `flags`.
+fn protection_attributes(flags: MmapFlags) -> PageAttributes {
+    // Address spaces only ever allocate userspace memory; the kernel's memory is in the shared higher half.
+    PageAttributes::USER
+        | if flags.contains(MmapFlags::READ_EXECUTE) {
+            PageAttributes::RX
+        } else if flags.contains(MmapFlags::READ_WRITE) {
+            PageAttributes::RW
+        } else if flags.contains(MmapFlags::READ) {
+            PageAttributes::RO
+        } else {
+            PageAttributes::empty()
+        }
+}
+
+/// Allocation bitmap of process-context identifiers. PCID 0 is reserved for the kernel's own page tables.
+static PCIDS: InterruptCell<Mutex<[u64; 4096 / 64]>> = InterruptCell::new(Mutex::new({
+    let mut bitmap = [0; 4096 / 64];
+    bitmap[0] = 0b1;
+    bitmap
+}));
+
+/// Allocates a free PCID, or returns `0` (the kernel's PCID) if all have been allocated.
+fn allocate_pcid() -> u16 {
+    PCIDS.with(|pcids| {
+        let mut pcids = pcids.lock();
+        pcids.iter_mut().enumerate().find(|(_, bits)| **bits != u64::MAX).map_or(0, |(index, bits)| {
+            let bit_index = bits.trailing_ones();
+            *bits |= 1 << bit_index;
+
+            ((index * 64) as u16) + (bit_index as u16)
+        })
+    })
+}
+
+/// Current TLB tag of the address space which owns each PCID.
+static TLB_TAGS: [AtomicU64; 4096] = [const { AtomicU64::new(0) }; 4096];
+
+/// Returns a tag which uniquely identifies a set of mappings for the purposes of TLB tagging. Unlike PCIDs, tags
+/// are never reused, so a core can tell whether the TLB entries it holds for a PCID belong to a prior address space,
+/// or to mappings which have since been removed.
+fn next_tlb_tag() -> u64 {
+    static NEXT_TLB_TAG: AtomicU64 = AtomicU64::new(1);
+
+    NEXT_TLB_TAG.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the current TLB tag of the address space using `pcid`.
+pub fn tlb_tag(pcid: u16) -> u64 {
+    TLB_TAGS[pcid as usize].load(Ordering::Acquire)
+}
+
+/// Invalidates any TLB entries other cores hold for `pcid`, by giving it a new tag.
+fn retag_pcid(pcid: u16) {
+    TLB_TAGS[pcid as usize].store(next_tlb_tag(), Ordering::Release);
+}
 
-#[derive(Debug, Clone, Copy)]
-struct Region {
-    len: usize,
-    free: bool,
+fn free_pcid(pcid: u16) {
+    if pcid > 0 {
+        PCIDS.with(|pcids| pcids.lock()[(pcid / 64) as usize] &= !(1 << (pcid % 64)));
+    }
 }
 
 pub struct AddressSpace<A: Allocator + Clone> {
-    regions: TryVec<Region, A>,
+    regions: Regions<A>,
     allocator: A,
     mapper: Mapper,
+    pcid: u16,
 }
 
 impl<A: Allocator + Clone> AddressSpace<A> {
     pub unsafe fn new_in(size: NonZeroUsize, allocator: A) -> Result<Self, Error> {
-        let mut vec = TryVec::new_in(allocator.clone());
-        vec.push(Region { len: size.get(), free: true }).map_err(|_| Error)?;
+        let mut regions = Regions::new_in(size, allocator.clone())?;
+        // Reserve the null page, so no allocation is ever placed at address 0.
+        regions.set(0, PAGE_SIZE, false)?;
+
+        // Every address space shares the kernel's higher half, so the kernel remains mapped across context switches.
+        let mapper = crate::memory::with_kmapper(|kmapper| Mapper::new_sharing_higher_half(kmapper))
+            .ok_or(Error::OutOfMemory)?;
+
+        let pcid = allocate_pcid();
+        retag_pcid(pcid);
+
+        Ok(Self { regions, allocator, mapper, pcid })
+    }
+
+    /// Creates a copy of this address space, which shares all of its mapped frames. Writable pages are made
+    /// read-only in both address spaces, and are copied on their first write (see [`AddressSpace::demand_map`]).
+    pub fn clone_copy_on_write(&mut self) -> Result<Self, Error> {
+        let regions = self.regions.try_clone()?;
+
+        // Safety: This address space is retagged below, so no core retains write permissions to the shared frames.
+        let mapper = unsafe { self.mapper.clone_copy_on_write() };
+        // Write permissions may have been removed from pages mapped by other cores, even if cloning failed.
+        retag_pcid(self.pcid);
+        let mapper = mapper?;
+
+        let pcid = allocate_pcid();
+        retag_pcid(pcid);
 
-        Ok(Self { regions: vec, allocator, mapper: Mapper::new().ok_or(Error)? })
+        Ok(Self { regions, allocator: self.allocator.clone(), mapper, pcid })
     }
 
-    // TODO better error type for this function
+    /// The value of the paging register that will switch to this address space.
+    pub fn paging_register(&self) -> PagingRegister {
+        #[cfg(target_arch = "x86_64")]
+        {
+            PagingRegister(self.mapper.root_frame(), crate::arch::x64::registers::control::CR3Flags::empty(), self.pcid)
+        }
+    }
+
+    /// Current tag of this address space's mappings, see [`next_tlb_tag`].
+    #[inline]
+    pub fn tlb_tag(&self) -> u64 {
+        tlb_tag(self.pcid)
+    }
+
+    /// Maps a region of memory fitting `layout`, returning it.
+    ///
+    /// If `address` is provided, the placement of the region depends on `flags`:
+    /// - [`MmapFlags::FIXED_NOREPLACE`]: the region is placed at `address`, failing if any of it is already allocated.
+    /// - [`MmapFlags::FIXED`]: the region is placed at `address`, unmapping anything already allocated there.
+    /// - Otherwise, `address` is a hint, and is used only if the region there is free.
     pub fn mmap(
         &mut self,
-        // TODO
-        // address: Option<Address<Virtual>>,
+        address: Option<Address<Page>>,
         layout: Layout,
         flags: MmapFlags,
     ) -> Result<NonNull<[u8]>, Error> {
-        let layout =
-            Layout::from_size_align(layout.size(), core::cmp::max(layout.align(), PAGE_SIZE)).map_err(|_| Error)?;
+        // Safety: Value is non-zero.
+        let page_size = unsafe { NonZeroUsize::new_unchecked(PAGE_SIZE) };
+        let layout = Layout::from_size_align(
+            lzstd::align_up(layout.size(), page_size),
+            core::cmp::max(layout.align(), PAGE_SIZE),
+        )
+        .map_err(|_| Error::Invalid)?;
+        let base_address = self.allocate_region(address, layout, flags)?;
+
+        // Set up paging attributes based on provided mmap flags.
+        let mut attributes = protection_attributes(flags);
+        // Demand paging is the default, but optionally the user can specify front-loading the physical page allocations.
+        if !flags.contains(MmapFlags::NOT_DEMAND) {
+            attributes.insert(PageAttributes::DEMAND);
+        }
+        // Finally, map all of the allocated pages in the virtual address space.
+        for page_base in (base_address..(base_address + layout.size())).step_by(PAGE_SIZE) {
+            let map_result = Address::new(page_base)
+                .ok_or(Error::Invalid)
+                .and_then(|page| self.mapper.auto_map(page, attributes).map_err(Error::from));
+
+            if let Err(err) = map_result {
+                // Release whatever was mapped before the failure.
+                self.munmap(
+                    Address::new(base_address).ok_or(Error::Invalid)?,
+                    NonZeroUsize::new(layout.size()).ok_or(Error::Invalid)?,
+                )?;
+                return Err(err);
+            }
+        }
+
+        NonNull::new(base_address as *mut u8)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(Error::Invalid)
+    }
+
+    /// Maps a region of memory backed by `frames` (in order), with the protection specified by `flags`, returning
+    /// it. The region is placed as with [`AddressSpace::mmap`], and [`MmapFlags::NOT_DEMAND`] is ignored.
+    ///
+    /// Each frame gains a reference for its mapping, which is dropped when it's unmapped. The pages are marked
+    /// [`PageAttributes::SHARED`], so writes to them are visible to every other mapping of the frames. The region can
+    /// never be given more protection than `max_protection` (see [`AddressSpace::mprotect`]).
+    pub fn map_frames(
+        &mut self,
+        address: Option<Address<Page>>,
+        frames: &[Address<Frame>],
+        flags: MmapFlags,
+        max_protection: MaxProtection,
+    ) -> Result<NonNull<[u8]>, Error> {
+        let protection = protection_attributes(flags);
+        if Self::exceeds_maximum(protection, max_protection.deny_attributes()) {
+            return Err(Error::Invalid);
+        }
+
+        let layout = Layout::from_size_align(frames.len().checked_mul(PAGE_SIZE).ok_or(Error::Invalid)?, PAGE_SIZE)
+            .map_err(|_| Error::Invalid)?;
+        let base_address = self.allocate_region(address, layout, flags)?;
+
+        let attributes = protection | PageAttributes::SHARED | max_protection.deny_attributes();
+        for (page_base, frame) in (base_address..(base_address + layout.size())).step_by(PAGE_SIZE).zip(frames) {
+            let map_result = Address::new(page_base).ok_or(Error::Invalid).and_then(|page| {
+                super::PMM.share_frame(*frame).map_err(|_| Error::Invalid)?;
+
+                self.mapper.map(page, PageDepth::MIN, *frame, false, attributes).map_err(|err| {
+                    // The page was never mapped, so its reference won't be dropped by unmapping it.
+                    super::free_frame(*frame).ok();
+                    Error::from(err)
+                })
+            });
+
+            if let Err(err) = map_result {
+                // Release whatever was mapped before the failure.
+                self.munmap(
+                    Address::new(base_address).ok_or(Error::Invalid)?,
+                    NonZeroUsize::new(layout.size()).ok_or(Error::Invalid)?,
+                )?;
+                return Err(err);
+            }
+        }
+
+        NonNull::new(base_address as *mut u8)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(Error::Invalid)
+    }
+
+    /// Allocates a region for the page-aligned `layout`, placed according to `address` and `flags` (see
+    /// [`AddressSpace::mmap`]), returning its base address.
+    fn allocate_region(
+        &mut self,
+        address: Option<Address<Page>>,
+        layout: Layout,
+        flags: MmapFlags,
+    ) -> Result<usize, Error> {
         // Safety: `Layout` does not allow `0` for alignments.
         let layout_align = unsafe { NonZeroUsize::new_unchecked(layout.align()) };
+        let layout_size = NonZeroUsize::new(layout.size()).ok_or(Error::Invalid)?;
+        let is_fixed = flags.intersects(MmapFlags::FIXED | MmapFlags::FIXED_NOREPLACE);
+
+        let fixed_address = match address {
+            Some(address) if (address.get() & (layout.align() - 1)) == 0 => {
+                let start = address.get();
+                let end = start.checked_add(layout.size()).ok_or(Error::Invalid)?;
+
+                if self.regions.is_free(start, end) {
+                    Some(start)
+                } else if flags.contains(MmapFlags::FIXED_NOREPLACE) {
+                    return Err(Error::AlreadyMapped);
+                } else if flags.contains(MmapFlags::FIXED) {
+                    self.munmap(Address::new(start).ok_or(Error::Invalid)?, layout_size)?;
+                    Some(start)
+                } else {
+                    None
+                }
+            }
 
-        let search = self.regions.iter().try_fold((0usize, 0usize), |(index, address), region| {
-            let aligned_address = lzstd::align_up(address, layout_align);
-            let aligned_padding = aligned_address - address;
-            let aligned_len = region.len.saturating_sub(aligned_padding);
+            // A fixed mapping can't be placed without an address, or at a misaligned one.
+            _ if is_fixed => return Err(Error::Invalid),
+            _ => None,
+        };
 
-            if aligned_len < layout.size() {
-                ControlFlow::Continue((index + 1, address + region.len))
-            } else {
-                ControlFlow::Break((index, address))
+        let base_address = match fixed_address {
+            Some(fixed_address) => fixed_address,
+            None => self.regions.find_free(layout.size(), layout_align).ok_or(Error::OutOfMemory)?,
+        };
+        self.regions.set(base_address, base_address + layout.size(), false)?;
+
+        Ok(base_address)
+    }
+
+    /// Unmaps the pages in `address..(address + len)`, freeing their frames and returning the range to the free
+    /// regions of the address space. Pages in the range which aren't mapped are ignored.
+    pub fn munmap(&mut self, address: Address<Virtual>, len: NonZeroUsize) -> Result<(), Error> {
+        let (start, end) = Self::page_range(address, len)?;
+        if end > self.regions.size() {
+            return Err(Error::Invalid);
+        }
+
+        // The range is only returned to the free regions once every page in it is unmapped, so no mapping can
+        // remain in a free region (where a later allocation could be placed over it).
+        let unmapped = self.unmap_range(start, end);
+        // Unmapping only invalidates this core's TLB, so force any other core which ran this address space to flush
+        // (even if unmapping failed partway).
+        retag_pcid(self.pcid);
+        unmapped?;
+
+        self.regions.set(start, end, true)
+    }
+
+    /// Unmaps the pages in `start..end`, freeing their frames. Pages in the range which aren't mapped are ignored.
+    fn unmap_range(&mut self, start: usize, end: usize) -> Result<(), Error> {
+        let mut page_base = start;
+        while page_base < end {
+            let page = Address::new(page_base).ok_or(Error::Invalid)?;
+            let depth = self.range_page_depth(page, end);
+            page_base += depth.page_size();
+
+            if self.mapper.is_mapped(page, None) {
+                // Demand pages which were never touched have no frame to free.
+                let has_frame = self.mapper.get_mapped_to(page).is_some();
+                // Safety: The pages are being returned to the free regions, so the caller no longer expects them to be mapped.
+                unsafe { self.mapper.unmap(page, Some(depth), has_frame) }?;
             }
-        });
-
-        match search.break_value() {
-            None => Err(Error),
-
-            Some((mut index, address)) => {
-                let aligned_address = lzstd::align_up(address, layout_align);
-                let aligned_padding = aligned_address - address;
-
-                if aligned_padding > 0 {
-                    if let Some(region) = self.regions.get_mut(index) && region.free {
-                        region.len += aligned_padding;
-                    } else {
-                        self.regions.insert(index, Region { len: aligned_padding, free: true }).map_err(|_| Error)?;
-                        index += 1;
-                    }
-                }
+        }
 
-                let remaining_len = {
-                    let region = self.regions.get_mut(index).ok_or(Error)?;
-                    region.len -= aligned_padding;
-                    region.free = false;
-
-                    let len = region.len;
-                    region.len = layout.size();
-                    len - region.len
-                };
-
-                if remaining_len > 0 {
-                    if let Some(region) = self.regions.get_mut(index.saturating_add(1)) && region.free {
-                        region.len += remaining_len;
-                    } else {
-                        self.regions.insert(index + 1, Region { len: remaining_len, free: true }).map_err(|_| Error)?;
-                    }
-                }
+        Ok(())
+    }
 
-                // Set up paging attributes based on provided mmap flags.
-                let mut attributes = {
-                    if flags.contains(MmapFlags::READ_EXECUTE) {
-                        PageAttributes::RX
-                    } else if flags.contains(MmapFlags::READ_WRITE) {
-                        PageAttributes::RW
-                    } else if flags.contains(MmapFlags::READ) {
-                        PageAttributes::RO
-                    } else {
-                        PageAttributes::empty()
-                    }
-                };
-                // Demand paging is the default, but optionally the user can specify front-loading the physical page allocations.
-                if !flags.contains(MmapFlags::NOT_DEMAND) {
-                    attributes.insert(PageAttributes::DEMAND);
-                }
-                // Finally, map all of the allocated pages in the virtual address space.
-                for page_base in (aligned_address..(aligned_address + layout.size())).step_by(PAGE_SIZE) {
-                    let page = Address::new(page_base).ok_or(Error)?;
-                    self.mapper.auto_map(page, attributes).map_err(|_| Error)?;
+    /// Changes the memory protection of the pages in `address..(address + len)`, which must all have been allocated
+    /// with [`AddressSpace::mmap`]. At least [`MmapFlags::READ`] must be specified, and [`MmapFlags::NOT_DEMAND`]
+    /// is ignored. Write and execute permissions are exclusive, so no page can ever be both writable and executable.
+    /// Fails without changing any page if the protection exceeds the maximum of any of the pages' mappings (see
+    /// [`AddressSpace::map_frames`]).
+    pub fn mprotect(&mut self, address: Address<Virtual>, len: NonZeroUsize, flags: MmapFlags) -> Result<(), Error> {
+        let (start, end) = Self::page_range(address, len)?;
+
+        if !flags.contains(MmapFlags::READ) || !self.regions.is_allocated(start, end) {
+            return Err(Error::Invalid);
+        }
+
+        let protection = protection_attributes(flags);
+        // Check every page before changing any, so a failed call leaves the range's protection unchanged.
+        let mut page_base = start;
+        while page_base < end {
+            let page = Address::new(page_base).ok_or(Error::Invalid)?;
+            page_base += self.range_page_depth(page, end).page_size();
+
+            if let Some(attributes) = self.mapper.get_page_attributes(page)
+                && Self::exceeds_maximum(protection, attributes)
+            {
+                return Err(Error::Invalid);
+            }
+        }
+
+        let mut page_base = start;
+        while page_base < end {
+            let page = Address::new(page_base).ok_or(Error::Invalid)?;
+            let depth = self.range_page_depth(page, end);
+            page_base += depth.page_size();
+
+            let Some(attributes) = self.mapper.get_page_attributes(page) else { continue };
+
+            let limits = attributes & (PageAttributes::DENY_WRITE | PageAttributes::DENY_EXECUTE);
+            let new_attributes = if attributes.contains(PageAttributes::DEMAND) {
+                // Demand pages must remain non-present until they're faulted in.
+                ((protection | limits | PageAttributes::DEMAND) - PageAttributes::PRESENT)
+            } else if attributes.contains(PageAttributes::PRESENT) {
+                let mut new_attributes = protection
+                    | limits
+                    | (attributes & (PageAttributes::ACCESSED | PageAttributes::DIRTY | PageAttributes::SHARED));
+
+                // Frames shared with another address space must still be copied before they're written (unless
+                // they're intentionally shared).
+                if new_attributes.contains(PageAttributes::WRITABLE)
+                    && !new_attributes.contains(PageAttributes::SHARED)
+                    && let Some(frame) = self.mapper.get_mapped_to(page)
+                    && super::PMM.frame_references(frame).map_err(|_| Error::Invalid)? > 1
+                {
+                    new_attributes.remove(PageAttributes::WRITABLE);
+                    new_attributes.insert(PageAttributes::COPY_ON_WRITE);
                 }
 
-                NonNull::new(aligned_address as *mut u8)
-                    .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
-                    .ok_or(Error)
+                new_attributes
+            } else {
+                continue;
+            };
+
+            // Safety: The range was allocated for userspace, so changing its protection can't affect the kernel.
+            unsafe { self.mapper.set_page_attributes(page, Some(depth), new_attributes, AttributeModify::Set) }
+                .map_err(|_| Error::Invalid)?;
+        }
+
+        // Permissions may have been reduced, so force any other core which ran this address space to flush.
+        retag_pcid(self.pcid);
+
+        Ok(())
+    }
+
+    /// Whether the protection attributes `protection` exceed the maximum protection recorded in `attributes` (see
+    /// [`MaxProtection`]).
+    fn exceeds_maximum(protection: PageAttributes, attributes: PageAttributes) -> bool {
+        let is_executable =
+            protection.contains(PageAttributes::PRESENT) && !protection.contains(PageAttributes::NO_EXECUTE);
+
+        (attributes.contains(PageAttributes::DENY_WRITE) && protection.contains(PageAttributes::WRITABLE))
+            || (attributes.contains(PageAttributes::DENY_EXECUTE) && is_executable)
+    }
+
+    /// Returns the depth at which to change the page `page`, so only memory before `end` is affected: the depth of
+    /// the page which maps it, unless that's a huge page extending beyond the range, which must be split.
+    fn range_page_depth(&self, page: Address<Page>, end: usize) -> PageDepth {
+        match self.mapper.get_page_depth(page) {
+            Some(depth) if (page.get() & (depth.page_size() - 1)) == 0 && (end - page.get()) >= depth.page_size() => {
+                depth
             }
+
+            _ => PageDepth::MIN,
         }
     }
 
+    /// Validates and page-aligns the range `address..(address + len)`, returning its bounds.
+    fn page_range(address: Address<Virtual>, len: NonZeroUsize) -> Result<(usize, usize), Error> {
+        let start = address.get();
+        let end =
+            lzstd::align_up(start.checked_add(len.get()).ok_or(Error::Invalid)?, NonZeroUsize::new(PAGE_SIZE).unwrap());
+
+        // The range must be page-aligned, and entirely canonical.
+        if (start & PAGE_MASK) > 0 || Address::<Virtual>::new(end - 1).is_none() {
+            Err(Error::Invalid)
+        } else {
+            Ok((start, end))
+        }
+    }
+
+    /// Resolves a page fault at `address`, by allocating a frame for a demand page, or giving a copy-on-write page
+    /// a frame of its own.
     pub fn demand_map(&mut self, address: Address<Virtual>) -> Result<(), Error> {
-        let page = Address::new_truncate(address.get());
-        // TODO we need to return the page size from `get_page_attributes` or something, so when we clear from a page fault, it clears huge pages too.
+        let depth = self.mapper.get_page_depth(Address::new_truncate(address.get())).ok_or(Error::Invalid)?;
+        let page_size = depth.page_size();
+        let page = Address::new_truncate(address.get() & !(page_size - 1));
+
         match self.mapper.get_page_attributes(page) {
             Some(mut attributes) if attributes.contains(PageAttributes::DEMAND) => {
-                self.mapper
-                    .auto_map(page, {
-                        // remove demand bit ...
-                        attributes.remove(PageAttributes::DEMAND);
-                        // ... insert present bit ...
-                        attributes.insert(PageAttributes::PRESENT);
-                        // ... return attributes
-                        attributes
-                    })
-                    .unwrap();
-
-                // Safety: We know the page was just mapped, and contains no relevant memory.
-                unsafe { core::ptr::write_bytes(page.as_ptr(), 0, PAGE_SIZE) };
+                let frame = if depth == PageDepth::MIN {
+                    super::next_frame()
+                } else {
+                    super::PMM.next_frames(
+                        NonZeroUsize::new(page_size / PAGE_SIZE).unwrap(),
+                        NonZeroUsize::new(page_size).unwrap(),
+                    )
+                }
+                .map_err(|_| Error::OutOfMemory)?;
+
+                attributes.remove(PageAttributes::DEMAND);
+                attributes.insert(PageAttributes::PRESENT);
+                // The frames were locked when they were allocated.
+                self.mapper.map(page, depth, frame, false, attributes)?;
+
+                // Zero the frames through the HHDM, as this address space may not be the active one (and SMAP
+                // disallows kernel accesses to userspace pages).
+                // Safety: We know the frames were just allocated, and contain no relevant memory.
+                unsafe { core::ptr::write_bytes(hhdm_address().as_ptr().add(frame.get()), 0, page_size) };
 
                 Ok(())
             }
 
-            _ => Err(Error),
+            // Reference counts are kept per-frame, so huge pages are never shared copy-on-write.
+            Some(attributes)
+                if depth == PageDepth::MIN
+                    && attributes.contains(PageAttributes::PRESENT | PageAttributes::COPY_ON_WRITE) =>
+            {
+                self.copy_on_write(page, attributes)
+            }
+
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// Gives `page` (which must be mapped copy-on-write, with `attributes`) write access to a frame of its own,
+    /// copying the shared frame if any other mapping still references it.
+    fn copy_on_write(&mut self, page: Address<Page>, attributes: PageAttributes) -> Result<(), Error> {
+        let frame = self.mapper.get_mapped_to(page).ok_or(Error::Invalid)?;
+        let new_attributes = (attributes - PageAttributes::COPY_ON_WRITE) | PageAttributes::WRITABLE;
+
+        if super::PMM.frame_references(frame).map_err(|_| Error::Invalid)? == 1 {
+            // Every other mapping has already taken its own copy, so this one can write to the frame directly.
+            // Safety: The page is already mapped to the frame, and now has its sole reference.
+            unsafe { self.mapper.set_page_attributes(page, None, new_attributes, AttributeModify::Set) }
+                .map_err(|_| Error::Invalid)?;
+        } else {
+            let new_frame = super::next_frame().map_err(|_| Error::OutOfMemory)?;
+            // Safety: Both frames are within physical memory, and the new frame was just allocated, so they don't
+            // overlap.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    hhdm_address().as_ptr().add(frame.get()),
+                    hhdm_address().as_ptr().add(new_frame.get()),
+                    PAGE_SIZE,
+                )
+            };
+
+            // `next_frame` returns an already-locked frame.
+            self.mapper.map(page, PageDepth::MIN, new_frame, false, new_attributes)?;
+            // Drop this mapping's reference to the shared frame.
+            super::free_frame(frame).map_err(|_| Error::Invalid)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a pointer to the HHDM mapping of the byte at `address`, ensuring it's accessible to userspace (and
+    /// writable, if `write` is set). Demand pages are mapped as required.
+    fn user_byte_ptr(&mut self, address: Address<Virtual>, write: bool) -> Result<*mut u8, Error> {
+        let page = Address::new_truncate(address.get());
+        let attributes = self.mapper.get_page_attributes(page).ok_or(Error::Invalid)?;
+
+        let writable = attributes.intersects(PageAttributes::WRITABLE | PageAttributes::COPY_ON_WRITE);
+        if !attributes.contains(PageAttributes::USER) || (write && !writable) {
+            return Err(Error::Invalid);
         }
+
+        if attributes.contains(PageAttributes::DEMAND) || (write && attributes.contains(PageAttributes::COPY_ON_WRITE))
+        {
+            self.demand_map(address)?;
+        }
+
+        let frame = self.mapper.get_mapped_to(page).ok_or(Error::Invalid)?;
+        // Safety: HHDM is guaranteed by kernel to be valid, and the frame is within physical memory.
+        Ok(unsafe { hhdm_address().as_ptr().add(frame.get() + (address.get() & PAGE_MASK)) })
+    }
+
+    /// Copies `buffer.len()` bytes from userspace memory at `address` into `buffer`.
+    pub fn copy_from_user(&mut self, address: Address<Virtual>, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut copied = 0;
+
+        while copied < buffer.len() {
+            let from_address =
+                Address::new(address.get().checked_add(copied).ok_or(Error::Invalid)?).ok_or(Error::Invalid)?;
+            let copy_len = core::cmp::min(PAGE_SIZE - (from_address.get() & PAGE_MASK), buffer.len() - copied);
+            let from_ptr = self.user_byte_ptr(from_address, false)?;
+
+            // Safety: `from_ptr` is valid for the remainder of its page, and `copy_len` doesn't exceed it.
+            unsafe { core::ptr::copy_nonoverlapping(from_ptr, buffer.as_mut_ptr().add(copied), copy_len) };
+            copied += copy_len;
+        }
+
+        Ok(())
+    }
+
+    /// Copies `data` into userspace memory at `address`.
+    pub fn copy_to_user(&mut self, address: Address<Virtual>, data: &[u8]) -> Result<(), Error> {
+        let mut copied = 0;
+
+        while copied < data.len() {
+            let to_address =
+                Address::new(address.get().checked_add(copied).ok_or(Error::Invalid)?).ok_or(Error::Invalid)?;
+            let copy_len = core::cmp::min(PAGE_SIZE - (to_address.get() & PAGE_MASK), data.len() - copied);
+            let to_ptr = self.user_byte_ptr(to_address, true)?;
+
+            // Safety: `to_ptr` is valid for the remainder of its page, and `copy_len` doesn't exceed it.
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr().add(copied), to_ptr, copy_len) };
+            copied += copy_len;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a null-terminated UTF-8 string from userspace memory at `address`, failing if no null terminator is
+    /// found within `max_len` bytes.
+    pub fn read_user_str(&mut self, address: Address<Virtual>, max_len: usize) -> Result<String, Error> {
+        let mut bytes = Vec::new();
+        let mut read = 0;
+
+        // Each page is checked once, and then searched for the terminator.
+        while read < max_len {
+            let from_address =
+                Address::new(address.get().checked_add(read).ok_or(Error::Invalid)?).ok_or(Error::Invalid)?;
+            let read_len = core::cmp::min(PAGE_SIZE - (from_address.get() & PAGE_MASK), max_len - read);
+            let from_ptr = self.user_byte_ptr(from_address, false)?;
+            // Safety: `from_ptr` is valid for the remainder of its page, and `read_len` doesn't exceed it.
+            let chunk = unsafe { core::slice::from_raw_parts(from_ptr.cast_const(), read_len) };
+
+            let terminator = chunk.iter().position(|byte| *byte == 0);
+            let chunk = &chunk[..terminator.unwrap_or(read_len)];
+            bytes.try_reserve(chunk.len()).map_err(|_| Error::OutOfMemory)?;
+            bytes.extend_from_slice(chunk);
+
+            if terminator.is_some() {
+                return String::from_utf8(bytes).map_err(|_| Error::Invalid);
+            }
+
+            read += read_len;
+        }
+
+        Err(Error::Invalid)
     }
 
     pub fn is_mmapped(&self, address: Address<Virtual>) -> bool {
         self.mapper.is_mapped(Address::new_truncate(address.get()), None)
     }
+
+    /// Returns a printable listing of every page mapped in the lower half (userspace) of this address space,
+    /// coalescing contiguous runs of pages (see [`Mapper::dump`]).
+    pub fn dump(&self) -> MappingDump<'_> {
+        MappingDump(self.mapper.walk_lower_half())
+    }
+
+    /// Direct access to the underlying [`Mapper`], e.g. for looking up the frames backing a mapping.
+    #[inline]
+    pub fn mapper_mut(&mut self) -> &mut Mapper {
+        &mut self.mapper
+    }
+}
+
+impl<A: Allocator + Clone> Drop for AddressSpace<A> {
+    fn drop(&mut self) {
+        // Safety: Registered address spaces are only dropped once unregistered, which requires they aren't active on
+        //         any core, and unregistered address spaces are never switched to.
+        unsafe { self.mapper.teardown() };
+
+        free_pcid(self.pcid);
+    }
 }
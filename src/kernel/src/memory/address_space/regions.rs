@@ -0,0 +1,183 @@
+use super::Error;
+use alloc::vec::Vec;
+use core::{alloc::Allocator, num::NonZeroUsize};
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    len: usize,
+    free: bool,
+}
+
+/// Tracks which ranges of an address space are allocated.
+///
+/// Regions are contiguous, and together cover the entire address space. Adjacent regions never share the same
+/// state, so each allocated or free range is represented by exactly one region.
+///
+/// Regions are kept in sorted vectors rather than trees, so the memory for any change can be reserved before the
+/// change is made, and running out of memory fails the change rather than aborting.
+pub(super) struct Regions<A: Allocator + Clone> {
+    /// All regions, sorted by their base address.
+    regions: Vec<(usize, Region), A>,
+    /// Free regions, as their length and base address, sorted for best-fit searches.
+    free: Vec<(usize, usize), A>,
+    size: usize,
+}
+
+impl<A: Allocator + Clone> Regions<A> {
+    pub fn new_in(size: NonZeroUsize, allocator: A) -> Result<Self, Error> {
+        let mut regions =
+            Self { regions: Vec::new_in(allocator.clone()), free: Vec::new_in(allocator), size: size.get() };
+        regions.reserve(1)?;
+        regions.insert(0, Region { len: size.get(), free: true });
+
+        Ok(regions)
+    }
+
+    /// Copies these regions, failing rather than aborting if memory for the copy can't be allocated.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        let mut regions = Vec::new_in(self.regions.allocator().clone());
+        regions.try_reserve_exact(self.regions.len()).map_err(|_| Error::OutOfMemory)?;
+        regions.extend_from_slice(&self.regions);
+
+        let mut free = Vec::new_in(self.free.allocator().clone());
+        free.try_reserve_exact(self.free.len()).map_err(|_| Error::OutOfMemory)?;
+        free.extend_from_slice(&self.free);
+
+        Ok(Self { regions, free, size: self.size })
+    }
+
+    /// Size of the address space the regions cover.
+    #[inline]
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Finds the smallest free range which fits `size` bytes at the given alignment, returning its base address.
+    pub fn find_free(&self, size: usize, align: NonZeroUsize) -> Option<usize> {
+        let first = self.free.partition_point(|&free| free < (size, 0));
+
+        self.free[first..].iter().find_map(|&(len, base)| {
+            let aligned_base = lzstd::align_up(base, align);
+            let end = aligned_base.checked_add(size)?;
+
+            (end <= (base + len)).then_some(aligned_base)
+        })
+    }
+
+    /// Whether the entirety of `start..end` is free.
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        self.containing(start).map_or(false, |(base, region)| region.free && end <= (base + region.len))
+    }
+
+    /// Whether the entirety of `start..end` is allocated.
+    pub fn is_allocated(&self, start: usize, end: usize) -> bool {
+        self.containing(start).map_or(false, |(base, region)| !region.free && end <= (base + region.len))
+    }
+
+    /// Marks `start..end` as free or allocated, splitting and merging regions as required.
+    pub fn set(&mut self, start: usize, end: usize, free: bool) -> Result<(), Error> {
+        if start >= end || end > self.size {
+            return Err(Error::Invalid);
+        }
+
+        // Splitting at `start` and `end` adds at most two regions, and merging only removes them.
+        self.reserve(2)?;
+
+        self.split_at(start);
+        self.split_at(end);
+
+        // After splitting, regions begin exactly at `start` and `end`.
+        let mut base = start;
+        while base < end
+            && let Some(region) = self.remove(base)
+        {
+            self.insert(base, Region { len: region.len, free });
+            base += region.len;
+        }
+
+        self.merge_around(start, end);
+
+        Ok(())
+    }
+
+    /// Ensures `additional` regions can be inserted (of either state) without allocating.
+    fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+        let len = self.regions.len() + additional;
+
+        // Free regions are a subset of all regions, so never outnumber them.
+        self.regions.try_reserve(additional).map_err(|_| Error::OutOfMemory)?;
+        self.free.try_reserve(len - self.free.len()).map_err(|_| Error::OutOfMemory)
+    }
+
+    /// Returns the region which contains `address`, and its base address.
+    fn containing(&self, address: usize) -> Option<(usize, Region)> {
+        let index = self.regions.partition_point(|(base, _)| *base <= address).checked_sub(1)?;
+
+        Some(self.regions[index]).filter(|(base, region)| address < (base + region.len))
+    }
+
+    /// Returns the region beginning at `base`.
+    fn get(&self, base: usize) -> Option<Region> {
+        self.regions.binary_search_by_key(&base, |(base, _)| *base).ok().map(|index| self.regions[index].1)
+    }
+
+    /// Ensures a region begins at `address`, splitting the region which contains it if necessary.
+    fn split_at(&mut self, address: usize) {
+        if let Some((base, region)) = self.containing(address)
+            && base < address
+        {
+            let split_len = address - base;
+
+            self.remove(base);
+            self.insert(base, Region { len: split_len, free: region.free });
+            self.insert(address, Region { len: region.len - split_len, free: region.free });
+        }
+    }
+
+    /// Merges adjacent regions which share the same state, from the region preceding `start` to the region
+    /// following `end`.
+    fn merge_around(&mut self, start: usize, end: usize) {
+        let mut base = self.containing(start.saturating_sub(1)).map_or(start, |(base, _)| base);
+
+        while base <= end
+            && let Some(region) = self.get(base)
+        {
+            let next_base = base + region.len;
+
+            match self.get(next_base) {
+                Some(next_region) if next_region.free == region.free => {
+                    self.remove(next_base);
+                    self.remove(base);
+                    self.insert(base, Region { len: region.len + next_region.len, free: region.free });
+                }
+
+                _ => base = next_base,
+            }
+        }
+    }
+
+    /// Inserts `region` at `base`, which must not already begin a region. Doesn't allocate, so long as the
+    /// insertion was reserved (see [`Self::reserve`]).
+    fn insert(&mut self, base: usize, region: Region) {
+        if region.free {
+            let index = self.free.partition_point(|&free| free < (region.len, base));
+            self.free.insert(index, (region.len, base));
+        }
+
+        let index = self.regions.partition_point(|(region_base, _)| *region_base < base);
+        self.regions.insert(index, (base, region));
+    }
+
+    fn remove(&mut self, base: usize) -> Option<Region> {
+        let index = self.regions.binary_search_by_key(&base, |(base, _)| *base).ok()?;
+        let (_, region) = self.regions.remove(index);
+
+        if region.free
+            && let Ok(index) = self.free.binary_search(&(region.len, base))
+        {
+            self.free.remove(index);
+        }
+
+        Some(region)
+    }
+}
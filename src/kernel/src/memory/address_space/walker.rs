@@ -0,0 +1,178 @@
+use super::Mapper;
+use crate::memory::{hhdm_address, Page, PageAttributes, PageDepth, PageTableEntry};
+use core::{fmt, marker::PhantomData, num::NonZeroU32, ops::Range};
+use lzstd::{Address, Frame, PAGE_SHIFT, TABLE_INDEX_SHIFT};
+
+/// Number of entries in each page table.
+const TABLE_LEN: usize = 1 << TABLE_INDEX_SHIFT.get();
+/// Number of tables walked through to reach a page of the smallest size.
+const TABLE_LEVELS: usize = PageDepth::MAX.get().get() as usize;
+
+/// A page mapped by a [`Mapper`], either to a frame or on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub page: Address<Page>,
+    /// Depth of the table whose entry maps the page, which determines its size (see [`PageDepth::page_size`]).
+    pub depth: PageDepth,
+    /// Frame the page is mapped to, or `None` if the page is mapped on demand.
+    pub frame: Option<Address<Frame>>,
+    pub attributes: PageAttributes,
+}
+
+impl Mapping {
+    #[inline]
+    pub const fn size(&self) -> usize {
+        self.depth.page_size()
+    }
+
+    /// Attributes which determine how the page can be accessed, ignoring those set by the CPU or which only
+    /// reflect the page's size.
+    fn access_attributes(&self) -> PageAttributes {
+        self.attributes - (PageAttributes::ACCESSED | PageAttributes::DIRTY | PageAttributes::HUGE)
+    }
+
+    /// Whether `next` directly follows a run of `len` bytes of memory beginning with this mapping, with the same
+    /// access, and (if mapped to frames) mapped to the frames which directly follow the run's frames.
+    fn is_continued_by(&self, next: &Mapping, len: usize) -> bool {
+        next.page.get() == (self.page.get() + len)
+            && next.access_attributes() == self.access_attributes()
+            && match (self.frame, next.frame) {
+                (Some(frame), Some(next_frame)) => next_frame.get() == (frame.get() + len),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+/// Iterator over the pages mapped by a [`Mapper`], in ascending address order (see [`Mapper::walk`]).
+#[derive(Clone)]
+pub struct Walker<'a> {
+    /// Table being walked at each depth (indexed by depth less one), down to the current depth.
+    tables: [*const PageTableEntry; TABLE_LEVELS],
+    /// Index of the next entry to visit within each of `tables`.
+    indexes: [usize; TABLE_LEVELS],
+    depth: PageDepth,
+    /// Index of the root table entry at which the walk ends.
+    root_end: usize,
+    _mapper: PhantomData<&'a Mapper>,
+}
+
+impl<'a> Walker<'a> {
+    /// ### Safety
+    ///
+    /// `root_frame` must be a valid top-level page table, which isn't modified for the lifetime of the walker, and
+    /// `root_indexes` must be bounded by the table size.
+    pub(super) unsafe fn new(root_frame: Address<Frame>, root_indexes: Range<usize>) -> Self {
+        let mut tables = [core::ptr::null(); TABLE_LEVELS];
+        let mut indexes = [0; TABLE_LEVELS];
+        // Safety: Caller is required to provide a valid page table.
+        tables[TABLE_LEVELS - 1] = unsafe { hhdm_address().as_ptr().add(root_frame.get()) }.cast();
+        indexes[TABLE_LEVELS - 1] = root_indexes.start;
+
+        Self { tables, indexes, depth: PageDepth::MAX, root_end: root_indexes.end, _mapper: PhantomData }
+    }
+
+    /// Builds the mapping of the entry which was last visited at the current depth.
+    fn mapping(&self, frame: Option<Address<Frame>>, attributes: PageAttributes) -> Mapping {
+        let level = (self.depth.get().get() - 1) as usize;
+        let base = (level..TABLE_LEVELS).fold(0, |base, level| {
+            let depth = PageDepth::new(NonZeroU32::new((level + 1) as u32).unwrap());
+            base | Mapper::entry_base(self.indexes[level] - 1, depth)
+        });
+
+        // Sign-extend the address from its highest translated bit, so it's canonical.
+        let unused_bits = usize::BITS - (PAGE_SHIFT.get() + (TABLE_INDEX_SHIFT.get() * (TABLE_LEVELS as u32)));
+        let page = Address::new_truncate((((base << unused_bits) as isize) >> unused_bits) as usize);
+
+        Mapping { page, depth: self.depth, frame, attributes }
+    }
+}
+
+impl Iterator for Walker<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let level = (self.depth.get().get() - 1) as usize;
+            let table_end = if self.depth == PageDepth::MAX { self.root_end } else { TABLE_LEN };
+            let index = self.indexes[level];
+
+            if index >= table_end {
+                if self.depth == PageDepth::MAX {
+                    return None;
+                }
+
+                // Return to the parent table, which resumes after the entry of this table.
+                self.depth = PageDepth::new(NonZeroU32::new(self.depth.get().get() + 1).unwrap());
+                continue;
+            }
+
+            self.indexes[level] = index + 1;
+            // Safety: Index is bounded by the table size, and the table is valid for the lifetime of the walker.
+            let entry = unsafe { *self.tables[level].add(index) };
+            let attributes = entry.get_attributes();
+
+            if !attributes.contains(PageAttributes::PRESENT) {
+                if attributes.contains(PageAttributes::DEMAND) {
+                    return Some(self.mapping(None, attributes));
+                }
+            } else if self.depth > PageDepth::MIN && !attributes.contains(PageAttributes::HUGE) {
+                // Safety: Frame is a valid page table.
+                self.tables[level - 1] = unsafe { hhdm_address().as_ptr().add(entry.get_frame().get()) }.cast();
+                self.indexes[level - 1] = 0;
+                self.depth = PageDepth::new(NonZeroU32::new(self.depth.get().get() - 1).unwrap());
+            } else {
+                return Some(self.mapping(Some(entry.get_frame()), attributes));
+            }
+        }
+    }
+}
+
+/// Prints the pages mapped by a [`Mapper`], one line per run of contiguous pages with the same access which are
+/// mapped to contiguous frames (see [`Mapper::dump`]).
+pub struct MappingDump<'a>(pub(super) Walker<'a>);
+
+impl MappingDump<'_> {
+    fn write_run(formatter: &mut fmt::Formatter<'_>, first: &Mapping, len: usize) -> fmt::Result {
+        let start = first.page.get();
+        let end = start + len;
+
+        match first.frame {
+            Some(frame) => writeln!(
+                formatter,
+                "{:#018X}..{:#018X} -> {:#014X} {:?}",
+                start,
+                end,
+                frame.get(),
+                first.access_attributes()
+            ),
+
+            None => {
+                writeln!(formatter, "{:#018X}..{:#018X} -> (demand)       {:?}", start, end, first.access_attributes())
+            }
+        }
+    }
+}
+
+impl fmt::Display for MappingDump<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut run: Option<(Mapping, usize)> = None;
+        // Walk a copy of the walker, so the dump can be printed more than once.
+        for mapping in self.0.clone() {
+            match &mut run {
+                Some((first, len)) if first.is_continued_by(&mapping, *len) => *len += mapping.size(),
+
+                _ => {
+                    if let Some((first, len)) = run.replace((mapping, mapping.size())) {
+                        Self::write_run(formatter, &first, len)?;
+                    }
+                }
+            }
+        }
+
+        match run {
+            Some((first, len)) => Self::write_run(formatter, &first, len),
+            None => Ok(()),
+        }
+    }
+}
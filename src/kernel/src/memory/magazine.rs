@@ -0,0 +1,69 @@
+use crate::memory::{pmm, PMM};
+use lzstd::{Address, Frame};
+
+/// Core-local cache of locked frames, which serves frame allocations and frees without touching the PMM's shared
+/// state. Frames are taken from the PMM in batches once the magazine is empty, and returned to it in batches once
+/// the magazine is full.
+pub struct FrameMagazine {
+    frames: [Option<Address<Frame>>; Self::CAPACITY],
+    len: usize,
+}
+
+impl FrameMagazine {
+    /// Maximum number of frames held by each magazine.
+    const CAPACITY: usize = 32;
+    /// Number of frames moved between a magazine and the PMM at once.
+    const BATCH_SIZE: usize = Self::CAPACITY / 2;
+
+    pub const fn new() -> Self {
+        Self { frames: [None; Self::CAPACITY], len: 0 }
+    }
+
+    /// Returns a locked frame, refilling the magazine from the PMM if it's empty.
+    pub fn next_frame(&mut self) -> pmm::Result<Address<Frame>> {
+        if self.len == 0 {
+            let mut filled = 0;
+            PMM.next_frame_batch(Self::BATCH_SIZE, |frame| {
+                self.frames[filled] = Some(frame);
+                filled += 1;
+            });
+            self.len = filled;
+        }
+
+        match self.len.checked_sub(1) {
+            Some(index) => {
+                self.len = index;
+                Ok(self.frames[index].take().unwrap())
+            }
+
+            None => Err(pmm::Error::NoneFree),
+        }
+    }
+
+    /// Drops a reference to a locked frame (see [`pmm::PhysicalMemoryManager::free_frame`]). Once its last reference
+    /// is dropped, a generic frame is kept in the magazine, returning a batch of frames to the PMM if it's full.
+    pub fn free_frame(&mut self, frame: Address<Frame>) -> pmm::Result<()> {
+        if !PMM.release_frame(frame)? {
+            return Ok(());
+        }
+
+        if self.len == Self::CAPACITY {
+            let remaining = Self::CAPACITY - Self::BATCH_SIZE;
+            let batch = self.frames[remaining..].iter_mut().map(|frame| frame.take().unwrap());
+            // Frames in the magazine are always locked.
+            PMM.free_frame_batch(batch).expect("magazine frame was not locked");
+            self.len = remaining;
+        }
+
+        self.frames[self.len] = Some(frame);
+        self.len += 1;
+
+        Ok(())
+    }
+}
+
+impl Default for FrameMagazine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
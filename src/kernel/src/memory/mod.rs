@@ -3,7 +3,10 @@ mod paging;
 pub mod io;
 pub use paging::*;
 pub mod address_space;
+pub mod magazine;
 pub mod pmm;
+pub mod shm;
+pub mod stack;
 
 use crate::{exceptions::Exception, interrupts::InterruptCell, local_state::do_catch};
 use address_space::Mapper;
@@ -111,7 +114,8 @@ pub fn with_kmapper<T>(func: impl FnOnce(&mut Mapper) -> T) -> T {
 }
 
 #[cfg(target_arch = "x86_64")]
-pub struct PagingRegister(pub Address<Frame>, pub crate::arch::x64::registers::control::CR3Flags);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagingRegister(pub Address<Frame>, pub crate::arch::x64::registers::control::CR3Flags, pub u16);
 #[cfg(target_arch = "riscv64")]
 pub struct VmemRegister(pub Address<Frame>, pub u16, pub crate::arch::rv64::registers::satp::Mode);
 
@@ -119,8 +123,14 @@ impl PagingRegister {
     pub fn read() -> Self {
         #[cfg(target_arch = "x86_64")]
         {
-            let args = crate::arch::x64::registers::control::CR3::read();
-            Self(args.0, args.1)
+            use crate::arch::x64::registers::control::{CR3Flags, CR3};
+
+            let args = CR3::read();
+            if pcid_enabled() {
+                Self(args.0, CR3Flags::empty(), CR3::read_pcid())
+            } else {
+                Self(args.0, args.1, 0)
+            }
         }
 
         #[cfg(target_arch = "riscv64")]
@@ -135,16 +145,59 @@ impl PagingRegister {
     /// Writing to this register has the chance to externally invalidate memory references.
     pub unsafe fn write(args: &Self) {
         #[cfg(target_arch = "x86_64")]
-        crate::arch::x64::registers::control::CR3::write(args.0, args.1);
+        if pcid_enabled() {
+            crate::arch::x64::registers::control::CR3::write_pcid(args.0, args.2, false);
+        } else {
+            crate::arch::x64::registers::control::CR3::write(args.0, args.1);
+        }
 
         #[cfg(target_arch = "riscv64")]
         crate::arch::rv64::registers::satp::write(args.0.as_usize(), args.1, args.2);
     }
 
+    /// Writes the register without invalidating the TLB entries tagged with this register's PCID. If PCIDs
+    /// are not enabled, this is equivalent to [`PagingRegister::write`].
+    ///
+    /// ### Safety
+    ///
+    /// In addition to the invariants of [`PagingRegister::write`], caller must ensure any TLB entries tagged with
+    /// this register's PCID were cached from the same root frame.
+    pub unsafe fn write_preserving(args: &Self) {
+        #[cfg(target_arch = "x86_64")]
+        if pcid_enabled() {
+            crate::arch::x64::registers::control::CR3::write_pcid(args.0, args.2, true);
+        } else {
+            crate::arch::x64::registers::control::CR3::write(args.0, args.1);
+        }
+
+        #[cfg(target_arch = "riscv64")]
+        Self::write(args);
+    }
+
     #[inline]
     pub const fn frame(&self) -> Address<Frame> {
         self.0
     }
+
+    /// The process-context identifier the TLB entries of this address space are tagged with.
+    #[inline]
+    pub const fn pcid(&self) -> u16 {
+        self.2
+    }
+}
+
+/// Whether TLB entries are tagged with process-context identifiers.
+pub fn pcid_enabled() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        crate::arch::x64::registers::control::CR4::read()
+            .contains(crate::arch::x64::registers::control::CR4Flags::PCIDE)
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    {
+        false
+    }
 }
 
 pub fn supports_5_level_paging() -> bool {
@@ -200,7 +253,45 @@ pub static PMM: Lazy<pmm::PhysicalMemoryManager> = Lazy::new(|| unsafe {
     .unwrap()
 });
 
-pub static KMALLOC: Lazy<SlabAllocator<&pmm::PhysicalMemoryManager>> = Lazy::new(|| SlabAllocator::new_in(11, &*PMM));
+pub static KMALLOC: Lazy<SlabAllocator<HeapFrameAllocator>> =
+    Lazy::new(|| SlabAllocator::new_in(11, HeapFrameAllocator));
+
+/// Backing allocator of [`KMALLOC`], which serves single frames (e.g. for slabs) from the current core's frame
+/// magazine (see [`next_frame`]), and anything larger directly from the PMM.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapFrameAllocator;
+
+impl HeapFrameAllocator {
+    #[inline]
+    const fn fits_frame(layout: Layout) -> bool {
+        layout.size() <= PAGE_SIZE && layout.align() <= PAGE_SIZE
+    }
+}
+
+/// ### Safety: Frames are locked until they're deallocated, and are accessed through the HHDM.
+unsafe impl Allocator for HeapFrameAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if Self::fits_frame(layout) {
+            let frame = next_frame().map_err(|_| AllocError)?;
+            // ### Safety: The HHDM maps all physical memory.
+            let ptr = NonNull::new(unsafe { hhdm_address().as_ptr().add(frame.get()) }).ok_or(AllocError)?;
+
+            Ok(NonNull::slice_from_raw_parts(ptr, PAGE_SIZE))
+        } else {
+            (&*PMM).allocate(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if Self::fits_frame(layout) {
+            let frame = Address::new_truncate(ptr.addr().get() - hhdm_address().get());
+            free_frame(frame).expect("heap frame was not locked");
+        } else {
+            // ### Safety: This function shares the same invariants as `Allocator::deallocate`.
+            unsafe { (&*PMM).deallocate(ptr, layout) };
+        }
+    }
+}
 
 mod global_allocator_impl {
     use super::KMALLOC;
@@ -235,6 +326,27 @@ mod global_allocator_impl {
     static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator;
 }
 
+/// Locks a frame, from the current core's frame magazine if it has one (see [`magazine::FrameMagazine`]), or
+/// otherwise directly from the PMM.
+pub fn next_frame() -> pmm::Result<Address<Frame>> {
+    fn lock_next() -> pmm::Result<Address<Frame>> {
+        crate::local_state::with_frame_magazine(magazine::FrameMagazine::next_frame).unwrap_or_else(|| PMM.next_frame())
+    }
+
+    match lock_next() {
+        // Under memory pressure, return the kernel heap's empty slabs, and try again.
+        Err(pmm::Error::NoneFree) if KMALLOC.reclaim() > 0 => lock_next(),
+        result => result,
+    }
+}
+
+/// Drops a reference to a locked frame, returning it to the current core's frame magazine if it has one (see
+/// [`magazine::FrameMagazine`]), or otherwise directly to the PMM.
+pub fn free_frame(frame: Address<Frame>) -> pmm::Result<()> {
+    crate::local_state::with_frame_magazine(|magazine| magazine.free_frame(frame))
+        .unwrap_or_else(|| PMM.free_frame(frame))
+}
+
 pub unsafe fn out_of_memory() -> ! {
     panic!("Kernel ran out of memory during initialization.")
 }
@@ -49,6 +49,27 @@ impl PageDepth {
     pub const fn align(self) -> usize {
         PAGE_SIZE.checked_shl(TABLE_INDEX_SHIFT.get() * self.0.get()).unwrap()
     }
+
+    /// Size of the pages mapped by the entries of a table at this depth.
+    #[inline]
+    pub const fn page_size(self) -> usize {
+        PAGE_SIZE.checked_shl(TABLE_INDEX_SHIFT.get() * (self.0.get() - 1)).unwrap()
+    }
+
+    /// Whether the entries of a table at this depth can map pages (rather than only subtables). Tables at depths
+    /// above [`PageDepth::MIN`] map huge pages, which aren't supported at every depth.
+    pub fn can_map_pages(self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        match self.0.get() {
+            // 4 KiB and 2 MiB pages are always supported.
+            1 | 2 => true,
+            // 1 GiB pages.
+            3 => crate::arch::x64::cpuid::EXT_FUNCTION_INFO
+                .as_ref()
+                .map_or(false, crate::arch::x64::cpuid::ExtendedProcessorFeatureIdentifiers::has_1gib_pages),
+            _ => false,
+        }
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -65,6 +86,14 @@ bitflags::bitflags! {
         const HUGE = 1 << 7;
         const GLOBAL = 1 << 8;
         const DEMAND = 1 << 9;
+        /// Page is writable, but its frame is shared, and must be copied before the first write.
+        const COPY_ON_WRITE = 1 << 10;
+        /// Page's frame is intentionally shared with other address spaces, so it's never copied on write.
+        const SHARED = 1 << 11;
+        /// Page may never be made writable, as its mapping wasn't granted write access.
+        const DENY_WRITE = 1 << 52;
+        /// Page may never be made executable, as its mapping wasn't granted execute access.
+        const DENY_EXECUTE = 1 << 53;
         const NO_EXECUTE = 1 << 63;
 
         const RO = Self::PRESENT.bits() | Self::NO_EXECUTE.bits();
@@ -302,11 +331,73 @@ impl<'a> PageTable<'a, Ref> {
                 }
             }
 
-            None if is_huge => with_fn(Ok(entry)),
+            None if is_huge || self.depth() == PageDepth::MIN => with_fn(Ok(entry)),
+            None => match self.next_depth() {
+                // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
+                Some(next_depth) => match unsafe { PageTable::<Ref>::new(next_depth, entry) } {
+                    Some(page_table) => page_table.with_entry(page, None, with_fn),
+                    None => with_fn(Err(PagingError::NotMapped)),
+                },
+
+                None => with_fn(Err(PagingError::DepthUnderflow)),
+            },
 
             _ => with_fn(Err(PagingError::Unknown)),
         }
     }
+
+    /// Returns the depth of the table whose entry maps `page` (which determines the page's size, see
+    /// [`PageDepth::page_size`]), or `None` if no table's entry maps it.
+    pub fn page_depth(&self, page: Address<Page>) -> Option<PageDepth> {
+        let entry = self.get_entry(page);
+
+        if self.depth() == PageDepth::MIN || entry.get_attributes().contains(PageAttributes::HUGE) {
+            Some(self.depth())
+        } else {
+            // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
+            unsafe { PageTable::<Ref>::new(self.next_depth()?, entry) }?.page_depth(page)
+        }
+    }
+}
+
+/// Allocates a zeroed frame for a new page table.
+fn new_table_frame() -> Result<Address<Frame>, PagingError> {
+    let frame = crate::memory::next_frame().map_err(|_| PagingError::NoMoreFrames)?;
+    // Safety: The frame was just allocated, so nothing else references its memory.
+    unsafe { core::ptr::write_bytes(hhdm_address().as_ptr().add(frame.get()), 0, PAGE_SIZE) };
+
+    Ok(frame)
+}
+
+/// Splits the huge page mapped by `entry` (an entry of a table at `depth`) into a new table of pages of the next
+/// smaller size, each with the same attributes, so that part of the huge page can be changed independently of the
+/// rest. Huge pages which are mapped on demand remain on demand.
+fn split_huge_entry(entry: &mut PageTableEntry, depth: PageDepth) -> Result<(), PagingError> {
+    let next_depth = PageDepth::new(NonZeroU32::new(depth.get().get() - 1).ok_or(PagingError::DepthUnderflow)?);
+    let table_frame = new_table_frame()?;
+
+    let mut attributes = entry.get_attributes();
+    // At the lowest depth, the `HUGE` bit is instead the page attribute table bit.
+    if next_depth == PageDepth::MIN {
+        attributes.remove(PageAttributes::HUGE);
+    }
+
+    // Safety: The frame was just allocated as a page table.
+    let table = unsafe { hhdm_address().as_ptr().add(table_frame.get()) }.cast::<PageTableEntry>();
+    for index in 0..(1 << TABLE_INDEX_SHIFT.get()) {
+        let frame = if entry.is_present() {
+            Address::new_truncate(entry.get_frame().get() + (index * next_depth.page_size()))
+        } else {
+            Address::new_truncate(0)
+        };
+
+        // Safety: Index is bounded by the table size.
+        unsafe { table.add(index).write(PageTableEntry::new(frame, attributes)) };
+    }
+
+    *entry = PageTableEntry::new(table_frame, PageAttributes::PTE);
+
+    Ok(())
 }
 
 impl<'a> PageTable<'a, Mut> {
@@ -338,8 +429,13 @@ impl<'a> PageTable<'a, Mut> {
         match to_depth {
             Some(to_depth) if self.depth() == to_depth => with_fn(Ok(entry)),
             Some(to_depth) if self.depth() > to_depth => {
-                match is_huge {
-                    false if let Some(next_depth) = self.next_depth() => {
+                // Huge pages are split, so only the part of the huge page being walked to is affected.
+                if is_huge && let Err(err) = split_huge_entry(entry, self.depth()) {
+                    return with_fn(Err(err));
+                }
+
+                match self.next_depth() {
+                    Some(next_depth) => {
                         // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
                         match unsafe { PageTable::<Mut>::new(next_depth, entry) } {
                             Some(mut page_table) => page_table.with_entry_mut(page, Some(to_depth), with_fn),
@@ -347,12 +443,20 @@ impl<'a> PageTable<'a, Mut> {
                         }
                     }
 
-                    true => with_fn(Err(PagingError::WalkInterrupted)),
-                    false => with_fn(Err(PagingError::DepthUnderflow)),
+                    None => with_fn(Err(PagingError::DepthUnderflow)),
                 }
             }
 
-            None if is_huge => with_fn(Ok(entry)),
+            None if is_huge || self.depth() == PageDepth::MIN => with_fn(Ok(entry)),
+            None => match self.next_depth() {
+                // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
+                Some(next_depth) => match unsafe { PageTable::<Mut>::new(next_depth, entry) } {
+                    Some(mut page_table) => page_table.with_entry_mut(page, None, with_fn),
+                    None => with_fn(Err(PagingError::NotMapped)),
+                },
+
+                None => with_fn(Err(PagingError::DepthUnderflow)),
+            },
 
             _ => with_fn(Err(PagingError::Unknown)),
         }
@@ -370,30 +474,34 @@ impl<'a> PageTable<'a, Mut> {
         let entry = self.get_entry_mut(page);
         let is_huge = entry.get_attributes().contains(PageAttributes::HUGE);
 
-        // TODO this doesn't handle page depth correctly for creations
-        // TODO possibly handle present but no frame, or frame but no present?
-        if !entry.is_present() && self.depth() > to_depth {
-            let Ok(frame) = crate::memory::PMM.next_frame()
-                    else { return with_fn(Err(PagingError::NoMoreFrames)) };
-            *entry = PageTableEntry::new(frame, PageAttributes::PTE);
+        if self.depth() > to_depth {
+            // Huge pages are split, so only the part of the huge page being walked to is affected.
+            let result = if is_huge {
+                split_huge_entry(entry, self.depth())
+            } else if !entry.is_present() {
+                new_table_frame().map(|frame| *entry = PageTableEntry::new(frame, PageAttributes::PTE))
+            } else {
+                Ok(())
+            };
+
+            if let Err(err) = result {
+                return with_fn(Err(err));
+            }
         }
 
         match to_depth {
             to_depth if self.depth() == to_depth => with_fn(Ok(entry)),
-            to_depth if self.depth() > to_depth => {
-                match is_huge {
-                    false if let Some(next_depth) = self.next_depth() => {
-                        // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
-                        match unsafe { PageTable::<Mut>::new(next_depth, entry) } {
-                            Some(mut page_table) => page_table.with_entry_create(page, to_depth, with_fn),
-                            None => with_fn(Err(PagingError::NotMapped)),
-                        }
+            to_depth if self.depth() > to_depth => match self.next_depth() {
+                Some(next_depth) => {
+                    // Safety: If the page table entry is present, then it's a valid entry, all bits accounted.
+                    match unsafe { PageTable::<Mut>::new(next_depth, entry) } {
+                        Some(mut page_table) => page_table.with_entry_create(page, to_depth, with_fn),
+                        None => with_fn(Err(PagingError::NotMapped)),
                     }
-
-                    true => with_fn(Err(PagingError::WalkInterrupted)),
-                    false => with_fn(Err(PagingError::DepthUnderflow)),
                 }
-            }
+
+                None => with_fn(Err(PagingError::DepthUnderflow)),
+            },
 
             _ => with_fn(Err(PagingError::Unknown)),
         }
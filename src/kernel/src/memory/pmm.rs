@@ -7,6 +7,7 @@ use core::{
     sync::atomic::Ordering,
 };
 use lzstd::{Address, Frame};
+use spin::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
@@ -20,6 +21,8 @@ pub enum Error {
     NotFree,
     /// Attempted to free a frame that wasn't locked.
     NotLocked,
+    /// Attempted to share a frame which already has the maximum number of references.
+    TooManyReferences,
 
     TypeMismatch,
 
@@ -62,14 +65,17 @@ impl FrameType {
 }
 
 #[derive(Debug)]
-pub struct FrameData(core::sync::atomic::AtomicU8);
+pub struct FrameData(core::sync::atomic::AtomicU32);
 
 impl FrameData {
     const LOCKED_SHIFT: usize = 7;
     const PEEKED_SHIFT: usize = 6;
-    const LOCKED_BIT: u8 = 1 << Self::LOCKED_SHIFT;
-    const PEEKED_BIT: u8 = 1 << Self::PEEKED_SHIFT;
+    const LOCKED_BIT: u32 = 1 << Self::LOCKED_SHIFT;
+    const PEEKED_BIT: u32 = 1 << Self::PEEKED_SHIFT;
     const TYPE_RANGE: core::ops::Range<usize> = 0..4;
+    /// Number of references to a locked frame beyond the first (i.e. `0` for a frame with a single owner).
+    const SHARED_RANGE: core::ops::Range<usize> = 8..32;
+    const MAX_SHARED: u32 = (1 << (Self::SHARED_RANGE.end - Self::SHARED_RANGE.start)) - 1;
 
     #[inline]
     fn lock(&self) {
@@ -107,7 +113,26 @@ impl FrameData {
 
         self.0
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mut value| {
-                Some(*value.set_bits(Self::TYPE_RANGE, new_type.as_u8()))
+                Some(*value.set_bits(Self::TYPE_RANGE, new_type.as_u8() as u32))
+            })
+            .ok();
+    }
+
+    #[inline]
+    fn shared(&self) -> u32 {
+        debug_assert!(self.0.load(Ordering::Acquire).get_bit(Self::PEEKED_SHIFT));
+
+        self.0.load(Ordering::Relaxed).get_bits(Self::SHARED_RANGE)
+    }
+
+    #[inline]
+    fn set_shared(&self, shared: u32) {
+        debug_assert!(self.0.load(Ordering::Acquire).get_bit(Self::PEEKED_SHIFT));
+        debug_assert!(shared <= Self::MAX_SHARED);
+
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mut value| {
+                Some(*value.set_bits(Self::SHARED_RANGE, shared))
             })
             .ok();
     }
@@ -116,7 +141,7 @@ impl FrameData {
         debug_assert!(self.0.load(Ordering::Acquire).get_bit(Self::PEEKED_SHIFT));
 
         let raw = self.0.load(Ordering::Relaxed);
-        (raw.get_bit(Self::LOCKED_SHIFT), FrameType::from_u8(raw.get_bits(Self::TYPE_RANGE)))
+        (raw.get_bit(Self::LOCKED_SHIFT), FrameType::from_u8(raw.get_bits(Self::TYPE_RANGE) as u8))
     }
 }
 
@@ -127,14 +152,172 @@ pub struct MemoryMapping {
     pub typ: FrameType,
 }
 
+/// Maximum number of levels of a [`FrameBitmap`], which suffices for 2^40 frames (i.e. the 52-bit physical address
+/// space).
+const MAX_BITMAP_LEVELS: usize = 7;
+
+/// Hierarchical bitmap of the frames which are free to be allocated (i.e. unlocked, and of [`FrameType::Generic`]).
+///
+/// Each bit of the lowest level represents a frame, and each bit of a higher level indicates whether the
+/// corresponding word of the level below it has any bits set. Free frames are found by descending from the single
+/// word of the highest level, so allocations skip over fully allocated regions of memory without scanning them.
+struct FrameBitmap<'a> {
+    words: &'a mut [u64],
+    /// Offsets of each level's words within `words`, from the lowest level upwards. The highest level is the last
+    /// level with words, and always consists of a single word.
+    level_offsets: [usize; MAX_BITMAP_LEVELS + 1],
+    depth: usize,
+}
+
+impl<'a> FrameBitmap<'a> {
+    /// Returns the offsets of the levels of a bitmap of `frame_count` frames, and its number of levels.
+    fn layout(frame_count: usize) -> ([usize; MAX_BITMAP_LEVELS + 1], usize) {
+        let mut level_offsets = [0; MAX_BITMAP_LEVELS + 1];
+        let mut depth = 0;
+        let mut level_bits = core::cmp::max(frame_count, 1);
+
+        loop {
+            let level_words = level_bits.div_ceil(u64::BITS as usize);
+            level_offsets[depth + 1] = level_offsets[depth] + level_words;
+            depth += 1;
+
+            if level_words == 1 {
+                break (level_offsets, depth);
+            }
+
+            level_bits = level_words;
+        }
+    }
+
+    /// Returns the number of bytes required for a bitmap of `frame_count` frames.
+    fn size_of(frame_count: usize) -> usize {
+        let (level_offsets, depth) = Self::layout(frame_count);
+        level_offsets[depth] * core::mem::size_of::<u64>()
+    }
+
+    /// Creates an empty bitmap of `frame_count` frames in `words`, which must be at least [`FrameBitmap::size_of`]
+    /// bytes long.
+    fn new(words: &'a mut [u64], frame_count: usize) -> Self {
+        let (level_offsets, depth) = Self::layout(frame_count);
+        let words = &mut words[..level_offsets[depth]];
+        words.fill(0);
+
+        Self { words, level_offsets, depth }
+    }
+
+    #[inline]
+    fn level(&self, level: usize) -> &[u64] {
+        &self.words[self.level_offsets[level]..self.level_offsets[level + 1]]
+    }
+
+    #[inline]
+    fn level_mut(&mut self, level: usize) -> &mut [u64] {
+        &mut self.words[self.level_offsets[level]..self.level_offsets[level + 1]]
+    }
+
+    /// Marks the frame at `index` as free.
+    fn set(&mut self, index: usize) {
+        let mut index = index;
+
+        for level in 0..self.depth {
+            let word = &mut self.level_mut(level)[index / 64];
+            let was_empty = *word == 0;
+            word.set_bit(index % 64, true);
+
+            // The parent bit is already set, unless this word was empty.
+            if !was_empty {
+                break;
+            }
+
+            index /= 64;
+        }
+    }
+
+    /// Marks the frame at `index` as not free.
+    fn clear(&mut self, index: usize) {
+        let mut index = index;
+
+        for level in 0..self.depth {
+            let word = &mut self.level_mut(level)[index / 64];
+            word.set_bit(index % 64, false);
+
+            // The parent bit remains set, unless this word is now empty.
+            if *word != 0 {
+                break;
+            }
+
+            index /= 64;
+        }
+    }
+
+    /// Returns the index of the first set bit of `level`, at or after `index`.
+    fn find_from(&self, level: usize, index: usize) -> Option<usize> {
+        let words = self.level(level);
+        let word_index = index / 64;
+        let word = *words.get(word_index)? & (u64::MAX << (index % 64));
+
+        if word != 0 {
+            Some((word_index * 64) + (word.trailing_zeros() as usize))
+        } else if (level + 1) < self.depth {
+            // Find the next non-empty word of this level from the level above it.
+            let next_word_index = self.find_from(level + 1, word_index + 1)?;
+            Some((next_word_index * 64) + (words[next_word_index].trailing_zeros() as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index of the first unset bit of the lowest level within `range`, if any.
+    fn find_unset_in(&self, range: core::ops::Range<usize>) -> Option<usize> {
+        let words = self.level(0);
+        let mut index = range.start;
+
+        while index < range.end {
+            let word = !words[index / 64] & (u64::MAX << (index % 64));
+            if word != 0 {
+                let unset_index = ((index / 64) * 64) + (word.trailing_zeros() as usize);
+                return (unset_index < range.end).then_some(unset_index);
+            }
+
+            index = ((index / 64) + 1) * 64;
+        }
+
+        None
+    }
+
+    /// Returns the index of the first of `count` consecutive free frames, beginning at a multiple of `alignment`.
+    fn find_run(&self, count: usize, alignment: NonZeroUsize, frame_count: usize) -> Option<usize> {
+        let mut start = lzstd::align_up(self.find_from(0, 0)?, alignment);
+
+        loop {
+            let end = start.checked_add(count)?;
+            if end > frame_count {
+                break None;
+            }
+
+            match self.find_unset_in(start..end) {
+                // Resume the search from the next free frame after the allocated one.
+                Some(unset_index) => {
+                    start = lzstd::align_up(self.find_from(0, unset_index + 1)?, alignment);
+                }
+
+                None => break Some(start),
+            }
+        }
+    }
+}
+
 pub struct PhysicalMemoryManager<'a> {
     table: &'a [FrameData],
+    /// Free frames of the table, kept consistent with each frame's data. Locking the bitmap serializes every change
+    /// to whether a frame is free.
+    bitmap: Mutex<FrameBitmap<'a>>,
     physical_memory: Address<Virtual>,
 }
 
-// ### Safety: Type uses entirely atomic operations.
+// ### Safety: Type uses entirely atomic operations, or otherwise locks.
 unsafe impl Send for PhysicalMemoryManager<'_> {}
-// ### Safety: Type uses entirely atomic operations.
+// ### Safety: Type uses entirely atomic operations, or otherwise locks.
 unsafe impl Sync for PhysicalMemoryManager<'_> {}
 
 impl PhysicalMemoryManager<'_> {
@@ -162,17 +345,30 @@ impl PhysicalMemoryManager<'_> {
         };
         let total_frames = total_memory / 0x1000;
 
-        let table_size_in_bytes = lzstd::align_up(
+        // The bitmap is placed directly after the frame table, within the same frames.
+        let bitmap_offset = lzstd::align_up(
             total_frames * core::mem::size_of::<FrameData>(),
             // ### Safety: Value provided is non-zero.
+            unsafe { NonZeroUsize::new_unchecked(core::mem::align_of::<u64>()) },
+        );
+        let table_size_in_bytes = lzstd::align_up(
+            bitmap_offset + FrameBitmap::size_of(total_frames),
+            // ### Safety: Value provided is non-zero.
             unsafe { NonZeroUsize::new_unchecked(0x1000) },
         );
         let table_entry =
             memory_map.iter().find(|entry| entry.typ == FrameType::Generic && entry.len >= table_size_in_bytes)?;
-        let table = unsafe {
-            core::slice::from_raw_parts(
-                physical_memory.as_ptr().add(table_entry.base as usize).cast::<FrameData>(),
-                total_frames,
+        let (table, bitmap_words) = unsafe {
+            let table_ptr = physical_memory.as_ptr().add(table_entry.base as usize);
+            // Frame data is only ever updated in-place, so it must begin zeroed (unlocked, and with no references).
+            core::ptr::write_bytes(table_ptr.cast::<FrameData>(), 0, total_frames);
+
+            let bitmap_ptr = table_ptr.add(bitmap_offset).cast::<u64>();
+            let bitmap_len = (table_size_in_bytes - bitmap_offset) / core::mem::size_of::<u64>();
+
+            (
+                core::slice::from_raw_parts(table_ptr.cast::<FrameData>(), total_frames),
+                core::slice::from_raw_parts_mut(bitmap_ptr, bitmap_len),
             )
         };
 
@@ -196,7 +392,16 @@ impl PhysicalMemoryManager<'_> {
             },
         );
 
-        Some(Self { table, physical_memory })
+        let mut bitmap = FrameBitmap::new(bitmap_words, total_frames);
+        table.iter().enumerate().for_each(|(index, frame_data)| {
+            frame_data.peek();
+            if let (false, FrameType::Generic) = frame_data.data() {
+                bitmap.set(index);
+            }
+            frame_data.unpeek();
+        });
+
+        Some(Self { table, bitmap: Mutex::new(bitmap), physical_memory })
     }
 
     #[inline]
@@ -209,26 +414,85 @@ impl PhysicalMemoryManager<'_> {
         crate::interrupts::without(|| func(self.table))
     }
 
+    /// Runs `func` with the frame table and the bitmap of free frames, for changes to whether frames are free.
+    #[inline]
+    fn with_bitmap<T>(&self, func: impl FnOnce(&[FrameData], &mut FrameBitmap<'_>) -> T) -> T {
+        crate::interrupts::without(|| func(self.table, &mut self.bitmap.lock()))
+    }
+
+    /// Locks the first free frame of the table.
+    fn lock_next(table: &[FrameData], bitmap: &mut FrameBitmap<'_>) -> Result<Address<Frame>> {
+        let index = bitmap.find_from(0, 0).ok_or(Error::NoneFree)?;
+        bitmap.clear(index);
+
+        let frame_data = &table[index];
+        frame_data.peek();
+        debug_assert_eq!(frame_data.data(), (false, FrameType::Generic));
+        frame_data.lock();
+        frame_data.unpeek();
+
+        Address::from_index(index).ok_or(Error::Unknown)
+    }
+
+    /// Drops a reference to a locked frame, freeing the frame once its last reference is dropped. If `keep_generic`
+    /// is set, the last reference to a generic frame is instead kept, and `true` is returned.
+    fn drop_reference(
+        table: &[FrameData],
+        bitmap: &mut FrameBitmap<'_>,
+        frame: Address<Frame>,
+        keep_generic: bool,
+    ) -> Result<bool> {
+        let Some(frame_data) = table.get(frame.index()) else { return Err(Error::OutOfBounds) };
+
+        frame_data.peek();
+
+        match frame_data.data() {
+            // Shared frames only drop a reference, and remain locked for their remaining owners.
+            (true, _) if frame_data.shared() > 0 => {
+                frame_data.set_shared(frame_data.shared() - 1);
+                frame_data.unpeek();
+
+                Ok(false)
+            }
+
+            (true, FrameType::Generic) if keep_generic => {
+                frame_data.unpeek();
+
+                Ok(true)
+            }
+
+            (locked, ty) if locked => {
+                frame_data.free();
+                frame_data.unpeek();
+
+                if ty == FrameType::Generic {
+                    bitmap.set(frame.index());
+                }
+
+                Ok(false)
+            }
+
+            _ => {
+                frame_data.unpeek();
+
+                Err(Error::NotLocked)
+            }
+        }
+    }
+
     pub fn next_frame(&self) -> Result<Address<Frame>> {
-        self.with_table(|table| {
-            table
-                .iter()
-                .enumerate()
-                .find_map(|(index, frame_data)| {
-                    frame_data.peek();
-
-                    if let (false, FrameType::Generic) = frame_data.data() {
-                        frame_data.lock();
-                        frame_data.unpeek();
-
-                        Address::from_index(index)
-                    } else {
-                        frame_data.unpeek();
-
-                        None
-                    }
-                })
-                .ok_or(Error::NoneFree)
+        self.with_bitmap(Self::lock_next)
+    }
+
+    /// Locks up to `count` free frames at once, passing each to `func`. Returns the number of frames locked.
+    pub fn next_frame_batch(&self, count: usize, mut func: impl FnMut(Address<Frame>)) -> usize {
+        self.with_bitmap(|table, bitmap| {
+            for locked in 0..count {
+                let Ok(frame) = Self::lock_next(table, bitmap) else { return locked };
+                func(frame);
+            }
+
+            count
         })
     }
 
@@ -237,50 +501,26 @@ impl PhysicalMemoryManager<'_> {
             return Err(Error::InvalidAlignment);
         }
 
-        self.with_table(|mut sub_table| {
-            let alignment = core::cmp::max(alignment.get() / 0x1000, 1);
+        self.with_bitmap(|table, bitmap| {
+            let alignment = NonZeroUsize::new(alignment.get() / 0x1000).unwrap_or(NonZeroUsize::MIN);
+            let start_index = bitmap.find_run(count.get(), alignment, table.len()).ok_or(Error::NoneFree)?;
 
-            // Loop the table and attempt to locate a viable range of blocks.
-            loop {
-                let pages = sub_table.iter().take(count.get());
-                if pages.len() < count.get() {
-                    break Err(Error::NoneFree);
-                }
+            (start_index..(start_index + count.get())).for_each(|index| {
+                bitmap.clear(index);
 
-                let pages = &sub_table[..count.get()];
-                pages.iter().for_each(FrameData::peek);
-
-                match pages
-                    .iter()
-                    .map(FrameData::data)
-                    .enumerate()
-                    .rfind(|(_, (locked, ty))| *locked || *ty != FrameType::Generic)
-                {
-                    Some((index, _)) => {
-                        pages.iter().for_each(FrameData::unpeek);
-                        let aligned_index = ((index + 1) + (alignment - 1)) / alignment;
-                        sub_table = &sub_table[aligned_index..];
-                    }
-
-                    None => {
-                        pages.iter().for_each(|frame_data| {
-                            frame_data.lock();
-                            frame_data.unpeek();
-                        });
-
-                        // Use wrapping arithmetic here to make any errors in computation painfully obvious due
-                        // to extremely unpredictable results.
-                        let start_index = self.table.len().wrapping_sub(sub_table.len());
-                        let start_address = start_index.wrapping_mul(0x1000);
-                        break Address::new(start_address).ok_or(Error::Unknown);
-                    }
-                }
-            }
+                let frame_data = &table[index];
+                frame_data.peek();
+                debug_assert_eq!(frame_data.data(), (false, FrameType::Generic));
+                frame_data.lock();
+                frame_data.unpeek();
+            });
+
+            Address::new(start_index * 0x1000).ok_or(Error::Unknown)
         })
     }
 
     pub fn lock_frame(&self, frame: Address<Frame>) -> Result<()> {
-        self.with_table(|table| {
+        self.with_bitmap(|table, bitmap| {
             let Some(frame_data) = table.get(frame.index()) else { return Err(Error::OutOfBounds) };
             frame_data.peek();
 
@@ -288,6 +528,7 @@ impl PhysicalMemoryManager<'_> {
             if !locked {
                 frame_data.lock();
                 frame_data.unpeek();
+                bitmap.clear(frame.index());
 
                 Ok(())
             } else {
@@ -299,7 +540,7 @@ impl PhysicalMemoryManager<'_> {
     }
 
     pub fn lock_frames(&self, base: Address<Frame>, count: usize) -> Result<()> {
-        self.with_table(|table| {
+        self.with_bitmap(|table, bitmap| {
             let start_index = base.index();
             let end_index = start_index + count;
             if (base.index() + count) > table.len() {
@@ -315,6 +556,7 @@ impl PhysicalMemoryManager<'_> {
                     frame_data.lock();
                     frame_data.unpeek();
                 });
+                (start_index..end_index).for_each(|index| bitmap.clear(index));
 
                 Ok(())
             } else {
@@ -326,42 +568,88 @@ impl PhysicalMemoryManager<'_> {
     }
 
     pub fn free_frame(&self, frame: Address<Frame>) -> Result<()> {
+        self.with_bitmap(|table, bitmap| Self::drop_reference(table, bitmap, frame, false).map(|_| ()))
+    }
+
+    /// Frees each of `frames` (as with [`Self::free_frame`]) at once, stopping at the first which fails to be freed.
+    pub fn free_frame_batch(&self, frames: impl IntoIterator<Item = Address<Frame>>) -> Result<()> {
+        self.with_bitmap(|table, bitmap| {
+            frames.into_iter().try_for_each(|frame| Self::drop_reference(table, bitmap, frame, false).map(|_| ()))
+        })
+    }
+
+    /// Drops a reference to a locked frame (as with [`Self::free_frame`]), except that if it's the last reference to
+    /// a generic frame, the frame is left locked, to be reused by the caller. Returns whether the frame was left
+    /// locked.
+    pub fn release_frame(&self, frame: Address<Frame>) -> Result<bool> {
+        self.with_bitmap(|table, bitmap| Self::drop_reference(table, bitmap, frame, true))
+    }
+
+    /// Adds a reference to a locked frame, so that it must be freed once more (with [`Self::free_frame`]) before
+    /// it's actually released.
+    pub fn share_frame(&self, frame: Address<Frame>) -> Result<()> {
         self.with_table(|table| {
             let Some(frame_data) = table.get(frame.index()) else { return Err(Error::OutOfBounds) };
 
             frame_data.peek();
 
-            match frame_data.data() {
-                (locked, _) if locked => {
-                    frame_data.free();
-                    frame_data.unpeek();
-
+            let result = match (frame_data.data(), frame_data.shared()) {
+                ((false, _), _) => Err(Error::NotLocked),
+                (_, FrameData::MAX_SHARED) => Err(Error::TooManyReferences),
+                (_, shared) => {
+                    frame_data.set_shared(shared + 1);
                     Ok(())
                 }
+            };
 
-                _ => {
-                    frame_data.unpeek();
+            frame_data.unpeek();
 
-                    Err(Error::NotLocked)
-                }
-            }
+            result
         })
     }
 
-    pub fn modify_type(&self, frame: Address<Frame>, new_type: FrameType, old_type: Option<FrameType>) -> Result<()> {
+    /// Returns the number of references held to a locked frame, or `0` if the frame isn't locked.
+    pub fn frame_references(&self, frame: Address<Frame>) -> Result<usize> {
         self.with_table(|table| {
             let Some(frame_data) = table.get(frame.index()) else { return Err(Error::OutOfBounds) };
 
             frame_data.peek();
 
-            let (_, ty) = frame_data.data();
+            let references = match frame_data.data() {
+                (true, _) => (frame_data.shared() as usize) + 1,
+                (false, _) => 0,
+            };
+
+            frame_data.unpeek();
+
+            Ok(references)
+        })
+    }
+
+    pub fn modify_type(&self, frame: Address<Frame>, new_type: FrameType, old_type: Option<FrameType>) -> Result<()> {
+        self.with_bitmap(|table, bitmap| {
+            let Some(frame_data) = table.get(frame.index()) else { return Err(Error::OutOfBounds) };
+
+            frame_data.peek();
+
+            let (locked, ty) = frame_data.data();
             if let Some(old_type) = old_type && old_type != ty {
-                return Err(Error::Unknown);
+                frame_data.unpeek();
+
+                return Err(Error::TypeMismatch);
             }
             frame_data.set_type(new_type);
 
             frame_data.unpeek();
 
+            // Unlocked frames become free (or cease to be) as they change to (or from) generic frames.
+            if !locked {
+                match new_type {
+                    FrameType::Generic => bitmap.set(frame.index()),
+                    _ => bitmap.clear(frame.index()),
+                }
+            }
+
             Ok(())
         })
     }
@@ -373,7 +661,8 @@ unsafe impl core::alloc::Allocator for &PhysicalMemoryManager<'_> {
         let physical_memory = self.physical_memory;
         self.next_frames(
             NonZeroUsize::new(layout.size() / 0x1000).unwrap(),
-            NonZeroUsize::new(layout.align() / 0x1000).unwrap_or(NonZeroUsize::MIN),
+            // `next_frames` takes its alignment in bytes.
+            NonZeroUsize::new(layout.align()).unwrap(),
         )
         .ok()
         .map(|address| {
@@ -0,0 +1,74 @@
+use crate::memory::{
+    address_space::{self, AddressSpace, MaxProtection, MmapFlags},
+    hhdm_address, Page,
+};
+use alloc::vec::Vec;
+use core::{alloc::Allocator, num::NonZeroUsize, ptr::NonNull};
+use lzstd::{Address, Frame, PAGE_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There were not enough free frames (or kernel memory) to create the object.
+    OutOfMemory,
+    /// The object could not be mapped into the address space.
+    AddressSpaceError(address_space::Error),
+}
+
+/// A set of frames which can be mapped into any number of address spaces.
+///
+/// The object holds a reference to each of its frames, and every mapping of the object holds another, so the
+/// frames are only freed once the object has been dropped and unmapped from every address space. Tasks refer to
+/// objects by handle (see [`crate::proc::handles`]).
+pub struct SharedMemory {
+    frames: Vec<Address<Frame>>,
+}
+
+impl SharedMemory {
+    /// Allocates a zeroed object of at least `len` bytes.
+    pub fn new(len: NonZeroUsize) -> Result<Self, Error> {
+        // Safety: Value is non-zero.
+        let page_count = lzstd::align_up(len.get(), unsafe { NonZeroUsize::new_unchecked(PAGE_SIZE) }) / PAGE_SIZE;
+
+        let mut frames = Vec::new();
+        frames.try_reserve_exact(page_count).map_err(|_| Error::OutOfMemory)?;
+        // Frames are pushed as they're allocated, so dropping the object on failure frees them.
+        let mut shared_memory = Self { frames };
+
+        for _ in 0..page_count {
+            let frame = crate::memory::next_frame().map_err(|_| Error::OutOfMemory)?;
+            // Safety: Pointer is guaranteed valid due HHDM guarantee from kernel, and renting guarantees from PMM.
+            unsafe { core::ptr::write_bytes(hhdm_address().as_ptr().add(frame.get()), 0, PAGE_SIZE) };
+
+            shared_memory.frames.push(frame);
+        }
+
+        Ok(shared_memory)
+    }
+
+    /// Size of the object, in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    /// Maps the object into `address_space` with the protection specified by `flags`, returning the mapped region.
+    /// The region is placed as with [`AddressSpace::mmap`], and can never be given more than `max_protection`.
+    pub fn map_into<A: Allocator + Clone>(
+        &self,
+        address_space: &mut AddressSpace<A>,
+        address: Option<Address<Page>>,
+        flags: MmapFlags,
+        max_protection: MaxProtection,
+    ) -> Result<NonNull<[u8]>, Error> {
+        address_space.map_frames(address, &self.frames, flags, max_protection).map_err(Error::AddressSpaceError)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            // Only drops this object's reference; the frame remains allocated for any existing mappings.
+            crate::memory::free_frame(frame).expect("shared memory frame was not locked");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::memory::{with_kmapper, Page, PageAttributes, Virtual};
+use core::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use lzstd::{Address, PAGE_SIZE};
+
+/// Base of the region of kernel memory which guarded stacks are mapped in (root table entry 509, with 4-level
+/// paging), which is otherwise unused by the kernel.
+const REGION_BASE: usize = 0xFFFF_FE80_0000_0000;
+/// Size of the region which guarded stacks are mapped in.
+const REGION_SIZE: usize = 1 << 39;
+/// Size of the region reserved for each stack, including its guard page.
+const SLOT_SIZE: usize = 0x40000;
+
+/// Index of the next unused stack slot. Slots are never reused, so no core can hold a stale TLB entry for a stack
+/// which has since been freed and replaced.
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The requested size, along with its guard page, exceeds the size of a stack slot.
+    TooLarge,
+    /// Every stack slot has been used.
+    Exhausted,
+    OutOfMemory,
+}
+
+/// A kernel stack, mapped in its own region of memory above an unmapped guard page, so that overflowing it faults,
+/// rather than silently corrupting whatever memory lies below it.
+pub struct GuardedStack {
+    base: Address<Page>,
+    pages: NonZeroUsize,
+}
+
+impl GuardedStack {
+    /// Maps a new stack of at least `size` bytes.
+    pub fn new(size: NonZeroUsize) -> Result<Self, Error> {
+        let pages = NonZeroUsize::new(size.get().div_ceil(PAGE_SIZE)).unwrap();
+        if ((pages.get() + 1) * PAGE_SIZE) > SLOT_SIZE {
+            return Err(Error::TooLarge);
+        }
+
+        let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        if slot >= (REGION_SIZE / SLOT_SIZE) {
+            return Err(Error::Exhausted);
+        }
+
+        // Stacks are mapped at the top of their slot, leaving (at least) the slot's lowest page unmapped as a guard.
+        let slot_top = REGION_BASE + ((slot + 1) * SLOT_SIZE);
+        let base = Address::new(slot_top - (pages.get() * PAGE_SIZE)).unwrap();
+
+        with_kmapper(|kmapper| {
+            for index in 0..pages.get() {
+                let page = Address::new_truncate(base.get() + (index * PAGE_SIZE));
+
+                if kmapper.auto_map(page, PageAttributes::RW | PageAttributes::GLOBAL).is_err() {
+                    for index in 0..index {
+                        let page = Address::new_truncate(base.get() + (index * PAGE_SIZE));
+                        // ### Safety: The page was mapped above, and has not yet been used.
+                        unsafe { kmapper.unmap(page, None, true) }.unwrap();
+                    }
+
+                    return Err(Error::OutOfMemory);
+                }
+            }
+
+            Ok(Self { base, pages })
+        })
+    }
+
+    /// Returns the address of the top of the stack (i.e. its initial stack pointer).
+    #[inline]
+    pub fn top(&self) -> Address<Virtual> {
+        Address::new_truncate(self.base.get() + (self.pages.get() * PAGE_SIZE))
+    }
+}
+
+impl Drop for GuardedStack {
+    fn drop(&mut self) {
+        with_kmapper(|kmapper| {
+            for index in 0..self.pages.get() {
+                let page = Address::new_truncate(self.base.get() + (index * PAGE_SIZE));
+                // ### Safety: The stack is no longer in use, as its owner is being dropped.
+                unsafe { kmapper.unmap(page, None, true) }.unwrap();
+            }
+        });
+    }
+}
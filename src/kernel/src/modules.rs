@@ -1,8 +1,33 @@
-use lzstd::{Address, Page};
+use crate::{
+    elf::{segment, Elf},
+    memory::{
+        address_space::{AddressSpace, MmapFlags},
+        hhdm_address, Page, PageAttributes, PhysicalAllocator,
+    },
+    proc::task::{EntryPoint, Task, TaskStack},
+};
+use core::{alloc::Layout, num::NonZeroUsize};
+use lzstd::{Address, PAGE_MASK, PAGE_SIZE};
 use try_alloc::boxed::TryBox;
 
+/// Base address of new task stacks, as specified in `ABI.md`.
+pub const TASK_STACK_BASE_ADDRESS: usize = 0x400000800000;
+// TODO make this a dynamic configuration
+pub const TASK_STACK_PAGE_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    NoAddressSpace,
+    NonCanonicalAddress,
+    /// A page would have to be both writable and executable, as a segment requires it or it's shared by segments
+    /// which require each.
+    WritableExecutable,
+    AddressSpaceError(crate::memory::address_space::Error),
+    TaskError(crate::proc::task::Error),
+}
+
 pub fn load_modules() {
-    let drivers_data = crate::boot::get_kernel_modules()
+    let Some(drivers_data) = crate::boot::get_kernel_modules()
         // Find the drives module, and map the `Option<>` to it.
         .and_then(|modules| {
             modules.iter().find(|module| module.path.to_str().unwrap().to_str().unwrap().ends_with("drivers"))
@@ -11,7 +36,10 @@ pub fn load_modules() {
         .map(|drivers_module| unsafe {
             core::slice::from_raw_parts(drivers_module.base.as_ptr().unwrap(), drivers_module.length as usize)
         })
-        .expect("no drivers provided");
+        else {
+            warn!("Bootloader provided no drivers module; no drivers will be loaded.");
+            return
+        };
 
     for (header, data) in lza::ArchiveReader::new(drivers_data) {
         // SAFETY: Value is non-zero.
@@ -33,12 +61,12 @@ pub fn load_modules() {
                 "Failed decompress driver blob:\n{:#?}\n{:#?}\nData Snippet: {:?}",
                 header,
                 inflate_result,
-                &data[..100]
+                &data[..core::cmp::min(data.len(), 100)]
             );
             continue;
         };
 
-        let Some(elf) = crate::elf::Elf::from_bytes(&*elf_buffer)
+        let Some(elf) = Elf::from_bytes(&*elf_buffer)
             else {
                 warn!("Failed parse driver blob into valid ELF: {:?}", header);
                 continue
@@ -46,143 +74,148 @@ pub fn load_modules() {
 
         info!("{:?}", elf);
 
-        /* load driver */
-        {
-            use crate::{elf::segment, memory::PageAttributes};
-            use lzstd::PageAlign;
-
-            // Create the driver's page manager from the kernel's higher-half table.
-            // ### Safety: Kernel guarantees HHDM to be valid.
-            let mut driver_page_manager = unsafe {
-                crate::memory::address_space::Mapper::new(
-                    4,
-                    crate::memory::hhdm_address(),
-                    Some(crate::memory::PagingRegister::read()),
-                )
-                .expect("failed to create page manager for driver")
-            };
+        match load_driver(&elf) {
+            Ok(task) => {
+                debug!("Loaded driver as task: {:?}", task.uuid());
+                crate::proc::queue_pending(task);
+            }
+
+            Err(err) => warn!("Failed to load driver {:?}: {:?}", header, err),
+        }
+    }
+}
+
+/// Creates a new user-mode task from the given ELF, mapping its loadable segments and
+/// a stack into the task's address space.
+fn load_driver(elf: &Elf) -> Result<Task, Error> {
+    let entry_address = Address::new(elf.get_entry_offset()).ok_or(Error::NonCanonicalAddress)?;
+    let stack_top = Address::new(TASK_STACK_BASE_ADDRESS + (TASK_STACK_PAGE_COUNT * PAGE_SIZE))
+        .ok_or(Error::NonCanonicalAddress)?;
+
+    let task = Task::new(
+        u8::MIN,
+        EntryPoint::Address(entry_address),
+        TaskStack::User(stack_top),
+        crate::cpu::user_arch_context(),
+    )
+    .map_err(Error::TaskError)?;
+
+    let result = crate::memory::address_space::with(&task.uuid(), |address_space| {
+        for segment in elf.iter_segments() {
+            trace!("{:?}", segment);
+
+            if segment.get_type() == segment::Type::Loadable {
+                map_segment(address_space, &segment)?;
+            }
+        }
+
+        // Map the task's stack. Demand pages are zeroed when they're first accessed.
+        address_space
+            .mmap(
+                Some(Address::new(TASK_STACK_BASE_ADDRESS).ok_or(Error::NonCanonicalAddress)?),
+                Layout::from_size_align(TASK_STACK_PAGE_COUNT * PAGE_SIZE, PAGE_SIZE).unwrap(),
+                MmapFlags::READ_WRITE | MmapFlags::FIXED_NOREPLACE,
+            )
+            .map_err(Error::AddressSpaceError)?;
+
+        Ok(())
+    })
+    .ok_or(Error::NoAddressSpace)
+    .flatten();
+
+    if let Err(err) = result {
+        // Dropping the task doesn't free its address space, or the frames already mapped into it.
+        // ### Safety: The task has never run, so its address space isn't active on any core.
+        let address_space = unsafe { crate::memory::address_space::unregister(&task.uuid()) };
+        drop(address_space);
+
+        return Err(err);
+    }
+
+    Ok(task)
+}
+
+fn map_segment(address_space: &mut AddressSpace<PhysicalAllocator>, segment: &segment::Segment) -> Result<(), Error> {
+    let memory_start = segment.get_virtual_address().map_or(0, |ptr| ptr.addr().get());
+    let memory_end = memory_start + segment.get_memory_layout().map_or(0, |layout| layout.size());
+
+    let segment_flags = segment.get_flags();
+
+    for page_base in ((memory_start & !PAGE_MASK)..memory_end).step_by(PAGE_SIZE) {
+        let page = Address::<Page>::new(page_base).ok_or(Error::NonCanonicalAddress)?;
 
-            let hhdm_address = crate::memory::hhdm_address();
-
-            // Iterate the segments, and allocate them.
-            for segment in elf.iter_segments() {
-                trace!("{:?}", segment);
-
-                match segment.get_type() {
-                    segment::Type::Loadable => {
-                        let memory_start = segment.get_virtual_address().unwrap().as_usize();
-                        let memory_end = memory_start + segment.get_memory_layout().unwrap().size();
-                        // ### Safety: Value provided is non-zero.
-                        let start_page_index = lzstd::align_down_div(memory_start, unsafe {
-                            core::num::NonZeroUsize::new_unchecked(0x1000)
-                        });
-                        // ### Safety: Value provided is non-zero.
-                        let end_page_index =
-                            lzstd::align_up_div(memory_end, unsafe { core::num::NonZeroUsize::new_unchecked(0x1000) });
-                        let mut data_offset = 0;
-
-                        for page_index in start_page_index..end_page_index {
-                            // REMARK: This doesn't support RWX pages. I'm not sure it ever should.
-                            let page_attributes = if segment.get_flags().contains(segment::Flags::EXECUTABLE) {
-                                PageAttributes::RX
-                            } else if segment.get_flags().contains(segment::Flags::WRITABLE) {
-                                PageAttributes::RW
-                            } else {
-                                PageAttributes::RO
-                            };
-
-                            let page =
-                                Address::<Page>::from_u64((page_index * 0x1000) as u64, Some(PageAlign::Align4KiB))
-                                    .unwrap();
-                            driver_page_manager.auto_map(page, page_attributes | PageAttributes::USER).unwrap();
-
-                            // ### Safety: HHDM is guaranteed by kernel to be valid, and the frame being pointed to was just allocated.
-                            let memory_hhdm = unsafe {
-                                core::slice::from_raw_parts_mut(
-                                    hhdm_address
-                                        .as_mut_ptr::<u8>()
-                                        .add(driver_page_manager.get_mapped_to(page).unwrap().as_usize()),
-                                    0x1000,
-                                )
-                            };
-
-                            // If the virtual address isn't page-aligned, then this allows us to start writing at
-                            // the correct address, rather than writing the wrong bytes at the lower page boundary.
-                            let memory_offset = memory_start.checked_sub(page_index * 0x1000).unwrap_or(0);
-                            // REMARK: This could likely be optimized to use memcpy / copy_nonoverlapping, but
-                            //         for now this approach suffices.
-                            for index in memory_offset..0x1000 {
-                                let data_value = segment.data().get(data_offset);
-                                memory_hhdm[index] = *data_value
-                                    // Handle zeroing of `.bss` segments.
-                                    .unwrap_or(&0);
-                                data_offset += 1;
-                            }
-                        }
-                    }
-
-                    _ => {}
-                }
+        if !address_space.is_mmapped(Address::new_truncate(page_base)) {
+            address_space
+                .mmap(
+                    Some(page),
+                    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap(),
+                    protection_flags(segment_flags)? | MmapFlags::NOT_DEMAND | MmapFlags::FIXED_NOREPLACE,
+                )
+                .map_err(Error::AddressSpaceError)?;
+
+            // ### Safety: Frame was just allocated, and is mapped into the HHDM.
+            unsafe { core::ptr::write_bytes(frame_ptr(address_space, page), 0, PAGE_SIZE) };
+        } else {
+            // Segments are not required to be page-aligned, so they may share a page with the previous segment, in
+            // which case the page must allow the accesses of both.
+            let attributes = address_space.mapper_mut().get_page_attributes(page).unwrap();
+            let mut merged_flags = segment_flags;
+            if attributes.contains(PageAttributes::WRITABLE) {
+                merged_flags.insert(segment::Flags::WRITABLE);
+            }
+            if !attributes.contains(PageAttributes::NO_EXECUTE) {
+                merged_flags.insert(segment::Flags::EXECUTABLE);
             }
 
-            // Push ELF as global task.
-            {
-                // let stack_address = {
-                //     const TASK_STACK_BASE_ADDRESS: Address<Page> = Address::<Page>::new_truncate(
-                //         Address::<Virtual>::new_truncate(128 << 39),
-                //         Some(PageAlign::Align2MiB),
-                //     );
-                //     // TODO make this a dynamic configuration
-                //     const TASK_STACK_PAGE_COUNT: usize = 2;
-
-                //     for page in (0..TASK_STACK_PAGE_COUNT)
-                //         .map(|offset| TASK_STACK_BASE_ADDRESS.forward_checked(offset).unwrap())
-                //     {
-                //         driver_page_manager
-                //             .map(
-                //                 page,
-                //                 Address::<Frame>::zero(),
-                //                 false,
-                //                 PageAttributes::WRITABLE
-                //                     | PageAttributes::NO_EXECUTE
-                //                     | PageAttributes::DEMAND
-                //                     | PageAttributes::USER
-                //                     | PageAttributes::HUGE,
-                //             )
-                //             .unwrap();
-                //     }
-
-                //     TASK_STACK_BASE_ADDRESS.forward_checked(TASK_STACK_PAGE_COUNT).unwrap()
-                // };
-
-                // TODO
-                // let task = crate::local_state::Task::new(
-                //     u8::MIN,
-                //     // TODO account for memory base when passing entry offset
-                //     crate::local_state::EntryPoint::Address(
-                //         Address::<Virtual>::new(elf.get_entry_offset() as u64).unwrap(),
-                //     ),
-                //     stack_address.address(),
-                //     {
-                //         #[cfg(target_arch = "x86_64")]
-                //         {
-                //             (
-                //                 crate::arch::x64::registers::GeneralRegisters::empty(),
-                //                 crate::arch::x64::registers::SpecialRegisters::flags_with_user_segments(
-                //                     crate::arch::x64::registers::RFlags::INTERRUPT_FLAG,
-                //                 ),
-                //             )
-                //         }
-                //     },
-                //     #[cfg(target_arch = "x86_64")]
-                //     {
-                //         // TODO do not error here ?
-                //         driver_page_manager.read_vmem_register().unwrap()
-                //     },
-                // );
-
-                // crate::local_state::queue_task(task);
+            address_space
+                .mprotect(
+                    Address::new_truncate(page_base),
+                    NonZeroUsize::new(PAGE_SIZE).unwrap(),
+                    protection_flags(merged_flags)?,
+                )
+                .map_err(Error::AddressSpaceError)?;
+        }
+
+        // If the virtual address isn't page-aligned, then this allows us to start writing at
+        // the correct address, rather than writing the wrong bytes at the lower page boundary.
+        let copy_start = core::cmp::max(page_base, memory_start);
+        let copy_end = core::cmp::min(page_base + PAGE_SIZE, memory_start + segment.data().len());
+        if copy_start < copy_end {
+            let data = &segment.data()[(copy_start - memory_start)..(copy_end - memory_start)];
+
+            // ### Safety: The frame is mapped in the HHDM, and `copy_start..copy_end` is bounded by the page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    frame_ptr(address_space, page).add(copy_start - page_base),
+                    data.len(),
+                );
             }
         }
+        // Any remaining bytes (i.e. `.bss`) were zeroed when the page was mapped.
+    }
+
+    Ok(())
+}
+
+/// Protection of pages mapping a segment with `flags`.
+fn protection_flags(flags: segment::Flags) -> Result<MmapFlags, Error> {
+    // REMARK: This doesn't support RWX pages. I'm not sure it ever should.
+    if flags.contains(segment::Flags::EXECUTABLE | segment::Flags::WRITABLE) {
+        Err(Error::WritableExecutable)
+    } else if flags.contains(segment::Flags::EXECUTABLE) {
+        Ok(MmapFlags::READ_EXECUTE)
+    } else if flags.contains(segment::Flags::WRITABLE) {
+        Ok(MmapFlags::READ_WRITE)
+    } else {
+        Ok(MmapFlags::READ)
     }
 }
+
+/// Returns a pointer to the HHDM mapping of the frame `page` is mapped to.
+fn frame_ptr(address_space: &mut AddressSpace<PhysicalAllocator>, page: Address<Page>) -> *mut u8 {
+    let frame = address_space.mapper_mut().get_mapped_to(page).unwrap();
+
+    // ### Safety: HHDM is guaranteed by kernel to be valid, and the frame is within physical memory.
+    unsafe { hhdm_address().as_ptr().add(frame.get()) }
+}
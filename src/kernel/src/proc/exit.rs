@@ -0,0 +1,105 @@
+use crate::{
+    interrupts::InterruptCell,
+    proc::{
+        handles,
+        task::{State, Task},
+        wait::WaitQueue,
+    },
+};
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use uuid::Uuid;
+
+/// Tasks which may be reaped by their parent, keyed by task ID, along with the ID of the parent and the task's exit
+/// code once it's exited. Tasks without a parent are never recorded, and a task's children are dropped as it exits.
+static CHILDREN: InterruptCell<Mutex<BTreeMap<Uuid, (Uuid, Option<u32>)>>> =
+    InterruptCell::new(Mutex::new(BTreeMap::new()));
+
+/// Woken whenever a task with a parent exits, so parents can wait for their children's exit codes.
+pub static EXITED: WaitQueue = WaitQueue::new();
+
+/// Exits the current task with `code`: its handles are closed, its address space (and every frame mapped in it) is
+/// freed, and its exit code is recorded for its parent, if it has one. The next task is then switched to.
+///
+/// ### Safety
+///
+/// Caller must ensure interrupts are disabled, and that the current stack isn't the task's own (e.g. it's a system
+/// call stack), as the task's stack is freed.
+pub unsafe extern "sysv64" fn exit_current(code: u32) -> ! {
+    debug_assert!(!crate::interrupts::are_enabled());
+
+    let mut task = crate::local_state::take_current_task().expect("no current task to exit");
+    task.set_state(State::Exited);
+    let uuid = task.uuid();
+
+    debug!("Task {:?} exited with code {}.", uuid, code);
+
+    // Leave the task's address space, so it can be freed.
+    // ### Safety: The kernel's page tables map the entire higher half, so every kernel reference remains valid.
+    crate::memory::with_kmapper(|kmapper| unsafe { kmapper.commit_vmem_register() }).unwrap();
+
+    // The task will never reply to the calls it's received.
+    crate::ipc::endpoint::abandon_replies(&uuid);
+
+    // Bind the removed objects, so they're dropped outside of their locks.
+    let handle_table = handles::remove_table(&uuid);
+    drop(handle_table);
+    // ### Safety: The task's address space was only active on this core, which has just left it.
+    let address_space = unsafe { crate::memory::address_space::unregister(&uuid) };
+    drop(address_space);
+
+    let has_parent = CHILDREN.with(|children| {
+        let mut children = children.lock();
+
+        // The task's own children can no longer be reaped.
+        children.retain(|_, (parent, _)| *parent != uuid);
+
+        // The task is only recorded if it has a parent which hasn't yet exited.
+        children.get_mut(&uuid).map(|(_, exit_code)| *exit_code = Some(code)).is_some()
+    });
+
+    // Frees the task's kernel stack, if it has one.
+    drop(task);
+
+    if has_parent {
+        EXITED.wake_all();
+    }
+
+    let mut ctrl_flow_context = crate::cpu::ControlContext { ip: 0, sp: 0 };
+    let mut arch_context = crate::cpu::default_arch_context();
+    crate::local_state::next_task(&mut ctrl_flow_context, &mut arch_context);
+    crate::cpu::enter_context(ctrl_flow_context, arch_context)
+}
+
+/// Records `task` as a child of the task `parent`, which may reap its exit code (see [`reap`]).
+pub fn register_child(task: &mut Task, parent: Uuid) {
+    task.set_parent(parent);
+    CHILDREN.with(|children| {
+        children.lock().insert(task.uuid(), (parent, None));
+    });
+}
+
+/// Removes and returns the exit code of the task `uuid`, if it has exited. Returns `Err(())` if the task isn't a
+/// child of `parent` (or has already been reaped).
+pub fn reap(uuid: &Uuid, parent: &Uuid) -> Result<Option<u32>, ()> {
+    CHILDREN.with(|children| {
+        let mut children = children.lock();
+
+        match children.get(uuid).copied() {
+            Some((task_parent, exit_code)) if task_parent == *parent => {
+                if exit_code.is_some() {
+                    children.remove(uuid);
+                }
+
+                Ok(exit_code)
+            }
+
+            _ => Err(()),
+        }
+    })
+}
+
+/// Indicates whether the task `uuid` has exited, or is otherwise no longer waiting to be reaped.
+pub fn has_exited(uuid: &Uuid) -> bool {
+    CHILDREN.with(|children| !matches!(children.lock().get(uuid), Some((_, None))))
+}
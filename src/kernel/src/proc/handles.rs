@@ -0,0 +1,127 @@
+use crate::{
+    interrupts::InterruptCell,
+    ipc::{channel, endpoint},
+    memory::shm,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+use uuid::Uuid;
+
+pub use abi::{Handle, HandleRights as Rights};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Every handle value is in use.
+    Exhausted,
+    /// There wasn't enough kernel memory to insert the handle.
+    OutOfMemory,
+    /// The handle doesn't refer to any object.
+    NotFound,
+    /// The handle lacks the rights required by the operation.
+    AccessDenied,
+}
+
+/// A kernel object which can be referred to by handle.
+#[derive(Clone)]
+pub enum Object {
+    Channel(Arc<channel::Endpoint>),
+    Endpoint(Arc<endpoint::Endpoint>),
+    SharedMemory(Arc<shm::SharedMemory>),
+}
+
+/// An object, along with the operations its handle permits.
+#[derive(Clone)]
+pub struct Entry {
+    pub object: Object,
+    pub rights: Rights,
+}
+
+/// Kernel objects held by a task, keyed by handle.
+///
+/// Entries are kept in a vector sorted by handle, rather than a tree, so the memory for an insertion can be reserved
+/// (see [`HandleTable::reserve`]), and running out of memory fails the insertion rather than aborting.
+pub struct HandleTable {
+    entries: Vec<(Handle, Entry)>,
+    next_handle: Handle,
+}
+
+impl HandleTable {
+    const fn new() -> Self {
+        Self { entries: Vec::new(), next_handle: 1 }
+    }
+
+    /// Ensures `additional` handles can be inserted without allocating, so inserting several handles can't fail
+    /// partway for lack of memory.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+        self.entries.try_reserve(additional).map_err(|_| Error::OutOfMemory)
+    }
+
+    /// Inserts `object` into the table with `rights`, returning its handle.
+    pub fn insert(&mut self, object: Object, rights: Rights) -> Result<Handle, Error> {
+        // Search for an unused handle, starting from the most recently allocated, so handles aren't quickly reused.
+        let (handle, index) = (self.next_handle..=Handle::MAX)
+            .chain(1..self.next_handle)
+            .find_map(|handle| self.index_of(handle).err().map(|index| (handle, index)))
+            .ok_or(Error::Exhausted)?;
+
+        self.reserve(1)?;
+        self.entries.insert(index, (handle, Entry { object, rights }));
+        self.next_handle = handle.checked_add(1).unwrap_or(1);
+
+        Ok(handle)
+    }
+
+    /// Returns the entry of `handle`, if the handle has every right in `required`.
+    pub fn get(&self, handle: Handle, required: Rights) -> Result<&Entry, Error> {
+        let entry = self.index_of(handle).map(|index| &self.entries[index].1).map_err(|_| Error::NotFound)?;
+
+        if entry.rights.contains(required) {
+            Ok(entry)
+        } else {
+            Err(Error::AccessDenied)
+        }
+    }
+
+    /// Inserts another handle to the object referred to by `handle`, with `rights`. The handle must have
+    /// [`Rights::DUPLICATE`], and `rights` must be a subset of its own.
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> Result<Handle, Error> {
+        let entry = self.get(handle, Rights::empty())?;
+        if !entry.rights.contains(Rights::DUPLICATE | rights) {
+            return Err(Error::AccessDenied);
+        }
+
+        let object = entry.object.clone();
+        self.insert(object, rights)
+    }
+
+    /// Removes `handle` from the table, returning its entry.
+    pub fn remove(&mut self, handle: Handle) -> Result<Entry, Error> {
+        let index = self.index_of(handle).map_err(|_| Error::NotFound)?;
+
+        Ok(self.entries.remove(index).1)
+    }
+
+    /// Returns the index of `handle`'s entry, or otherwise the index its entry would be inserted at.
+    fn index_of(&self, handle: Handle) -> Result<usize, usize> {
+        self.entries.binary_search_by_key(&handle, |(handle, _)| *handle)
+    }
+}
+
+static HANDLE_TABLES: InterruptCell<Mutex<BTreeMap<Uuid, HandleTable>>> =
+    InterruptCell::new(Mutex::new(BTreeMap::new()));
+
+/// Runs `func` with the handle table of the task `uuid`, creating the table if it doesn't yet exist.
+///
+/// Objects removed within `func` should be returned and dropped afterwards, as dropping an object may wake tasks,
+/// and this lock should be held only briefly.
+pub fn with<T>(uuid: &Uuid, func: impl FnOnce(&mut HandleTable) -> T) -> T {
+    HANDLE_TABLES.with(|handle_tables| {
+        let mut handle_tables = handle_tables.lock();
+        func(handle_tables.entry(*uuid).or_insert_with(HandleTable::new))
+    })
+}
+
+/// Removes the handle table of the task `uuid`, returning it so its objects can be dropped outside of the lock.
+pub fn remove_table(uuid: &Uuid) -> Option<HandleTable> {
+    HANDLE_TABLES.with(|handle_tables| handle_tables.lock().remove(uuid))
+}
@@ -1,4 +1,11 @@
 mod scheduling;
 
 pub use scheduling::*;
+pub use spawn::{spawn, JoinHandle};
+pub mod exit;
+pub mod handles;
+pub mod spawn;
+pub mod stats;
 pub mod task;
+pub mod timer;
+pub mod wait;
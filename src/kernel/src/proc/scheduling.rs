@@ -1,29 +1,155 @@
-use crate::proc::task::Task;
-use alloc::collections::BinaryHeap;
+use crate::{
+    interrupts::{InterruptCell, Vector},
+    memory::PagingRegister,
+    proc::{
+        task::{Affinity, State, Task},
+        timer::TimerQueue,
+    },
+};
+use alloc::{
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    sync::Arc,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use try_alloc::boxed::TryBox;
+
+/// Tasks which have been created or woken before any core began scheduling, to be placed by the first cores which do.
+static PENDING_TASKS: InterruptCell<Mutex<VecDeque<Task>>> = InterruptCell::new(Mutex::new(VecDeque::new()));
+
+/// Scheduling state of each core which is currently scheduling tasks, keyed by core ID.
+static CORES: InterruptCell<Mutex<BTreeMap<u32, Arc<Core>>>> = InterruptCell::new(Mutex::new(BTreeMap::new()));
+
+/// Scheduling state of a core which other cores can access, to place and migrate tasks.
+struct Core {
+    /// Tasks placed on the core by other cores, which it claims the next time it schedules.
+    inbox: Mutex<VecDeque<Task>>,
+    /// Total weight (see [`weight`]) of every task assigned to the core: running, ready, or in its inbox.
+    load: AtomicU64,
+    /// Whether the core is running its idle task.
+    idle: AtomicBool,
+}
+
+/// Returns the load a task contributes to the core it's assigned to. Every task adds to its core's load, so that
+/// tasks of the lowest priority are still spread across cores.
+#[inline]
+const fn weight(task: &Task) -> u64 {
+    (task.priority() as u64) + 1
+}
+
+/// Returns the ID and state of the least loaded scheduling core which `affinity` allows, preferring idle cores.
+fn least_loaded(cores: &BTreeMap<u32, Arc<Core>>, affinity: Affinity) -> Option<(u32, &Arc<Core>)> {
+    cores
+        .iter()
+        .filter(|(core_id, _)| affinity.allows(**core_id))
+        .min_by_key(|(_, core)| (!core.idle.load(Ordering::Relaxed), core.load.load(Ordering::Relaxed)))
+        .map(|(core_id, core)| (*core_id, core))
+}
+
+/// Places `task` in the inbox of `core`, interrupting the core if it's idle so it's picked up immediately, rather
+/// than at the end of the core's current time slice.
+fn place(core_id: u32, core: &Core, task: Task) {
+    core.load.fetch_add(weight(&task), Ordering::Relaxed);
+    core.inbox.lock().push_back(task);
+
+    // Pairs with the fence in `Scheduler::next_task`, so either the core claims the task before it idles, or it's
+    // seen to be idle here.
+    if core.idle.swap(false, Ordering::SeqCst) {
+        crate::local_state::send_ipi(core_id, Vector::Wake);
+    }
+}
+
+/// Queues a task to run on the least loaded core its affinity allows.
+pub fn queue_pending(mut task: Task) {
+    task.set_state(State::Ready);
+
+    CORES.with(|cores| {
+        let cores = cores.lock();
+
+        match least_loaded(&cores, task.affinity()) {
+            Some((core_id, core)) => place(core_id, core, task),
+            None => PENDING_TASKS.with(|pending_tasks| pending_tasks.lock().push_back(task)),
+        }
+    });
+}
+
+/// Indicates whether any scheduling core is allowed by `affinity`, so a task with it could be run.
+pub fn is_schedulable(affinity: Affinity) -> bool {
+    CORES.with(|cores| cores.lock().keys().any(|core_id| affinity.allows(*core_id)))
+}
 
 pub struct Scheduler {
     enabled: bool,
-    total_priority: u64,
+    core_id: u32,
+    core: Arc<Core>,
     idle_task: Task,
     cur_task: Option<Task>,
     tasks: BinaryHeap<Task>,
+    /// Tasks sleeping on this core, until their deadlines.
+    timers: TimerQueue,
+    /// Scheduling decisions made since this core last balanced its load with other cores.
+    balance_ticks: u16,
+    /// TLB tag of the address space which last used each PCID on this core, if PCIDs are enabled.
+    pcid_tags: Option<TryBox<[u64]>>,
+    /// Whether the current task's extended state is loaded on this core (i.e. whether `CR0.TS` is clear).
+    extended_state_loaded: bool,
 }
 
 impl Scheduler {
-    pub fn new(enabled: bool, idle_task: Task) -> Self {
-        Self { enabled, total_priority: 0, idle_task, cur_task: None, tasks: BinaryHeap::new() }
+    /// Number of scheduling decisions between each attempt to balance load with other cores.
+    const BALANCE_INTERVAL: u16 = 4;
+
+    pub fn new(enabled: bool, core_id: u32, idle_task: Task) -> Self {
+        let pcid_tags = if crate::memory::pcid_enabled() { TryBox::new_slice(4096, 0u64).ok() } else { None };
+        let Ok(core) = Arc::try_new(Core {
+            inbox: Mutex::new(VecDeque::new()),
+            load: AtomicU64::new(0),
+            idle: AtomicBool::new(false),
+        }) else {
+            // ### Safety: Schedulers are only created while initializing their core.
+            unsafe { crate::memory::out_of_memory() }
+        };
+
+        // The idle task's running time isn't accounted, so it isn't reported alongside other tasks.
+        crate::proc::stats::unregister(&idle_task.uuid());
+
+        let mut scheduler = Self {
+            enabled: false,
+            core_id,
+            core,
+            idle_task,
+            cur_task: None,
+            tasks: BinaryHeap::new(),
+            timers: TimerQueue::new(),
+            balance_ticks: 0,
+            pcid_tags,
+            extended_state_loaded: false,
+        };
+
+        if enabled {
+            scheduler.enable();
+        }
+
+        scheduler
     }
 
-    /// Enables the scheduler to pop tasks.
-    #[inline]
+    /// Enables the scheduler to pop tasks, and allows tasks to be placed on this core.
     pub fn enable(&mut self) {
         self.enabled = true;
+        CORES.with(|cores| cores.lock().insert(self.core_id, self.core.clone()));
     }
 
-    /// Disables scheduler from popping tasks. Any task pops which are already in-flight will not be cancelled.
-    #[inline]
+    /// Disables scheduler from popping tasks. Any task pops which are already in-flight will not be cancelled. Tasks
+    /// placed on this core, but not yet claimed, are placed elsewhere.
     pub fn disable(&mut self) {
         self.enabled = false;
+        CORES.with(|cores| cores.lock().remove(&self.core_id));
+
+        let inbox = core::mem::take(&mut *self.core.inbox.lock());
+        for task in inbox {
+            self.core.load.fetch_sub(weight(&task), Ordering::Relaxed);
+            queue_pending(task);
+        }
     }
 
     /// Indicates whether the scheduler is enabled.
@@ -32,9 +158,15 @@ impl Scheduler {
         self.enabled
     }
 
-    /// Pushes a new task to the scheduling queue.
+    /// Pushes a new task to the scheduling queue, assigning it to this core.
     pub fn push_task(&mut self, task: Task) {
-        self.total_priority += task.priority() as u64;
+        self.core.load.fetch_add(weight(&task), Ordering::Relaxed);
+        self.requeue_task(task);
+    }
+
+    /// Pushes a task already assigned to this core back to the scheduling queue.
+    fn requeue_task(&mut self, mut task: Task) {
+        task.set_state(State::Ready);
         self.tasks.push(task);
     }
 
@@ -42,18 +174,16 @@ impl Scheduler {
     /// the task queue. Returns `None` if the queue is empty.
     pub fn pop_task(&mut self) -> Option<Task> {
         if self.enabled {
-            self.tasks.pop().map(|task| {
-                self.total_priority -= task.priority() as u64;
-                task
-            })
+            self.tasks.pop()
         } else {
             None
         }
     }
 
+    /// Returns the total weight of the tasks assigned to this core, as used to place and balance tasks.
     #[inline]
-    pub const fn get_total_priority(&self) -> u64 {
-        self.total_priority
+    pub fn get_total_load(&self) -> u64 {
+        self.core.load.load(Ordering::Relaxed)
     }
 
     #[inline]
@@ -61,6 +191,81 @@ impl Scheduler {
         self.cur_task.as_ref()
     }
 
+    #[inline]
+    pub fn current_task_mut(&mut self) -> Option<&mut Task> {
+        self.cur_task.as_mut()
+    }
+
+    /// Queues `task` (which must have been taken from the scheduler, see [`Scheduler::take_current_task`]) to be
+    /// woken once `deadline` has passed, in microseconds since boot.
+    pub fn sleep_task(&mut self, deadline: u64, task: Task) {
+        self.timers.push(deadline, task);
+    }
+
+    /// Removes the current task from the scheduler to wait, so it isn't requeued by the next call to
+    /// [`Scheduler::next_task`]. The task's saved contexts are not updated.
+    pub fn take_current_task(&mut self) -> Option<Task> {
+        let mut task = self.cur_task.take()?;
+        task.account(crate::time::TSC.uptime_ticks());
+        self.save_extended_state(&mut task);
+        self.core.load.fetch_sub(weight(&task), Ordering::Relaxed);
+        task.set_state(State::Blocked);
+
+        Some(task)
+    }
+
+    /// Restores the current task's extended state, so it can use extended state until it's switched away from.
+    /// Returns `false` if there's no current task, or its extended state couldn't be allocated.
+    pub fn restore_extended_state(&mut self) -> bool {
+        let Some(task) = self.cur_task.as_mut() else { return false };
+        let Ok(extended_state) = task.extended_state_mut() else { return false };
+
+        // ### Safety: Only the current task can use the extended state loaded here, and it's saved again before any
+        //             other task is switched to.
+        unsafe {
+            crate::cpu::allow_extended_state();
+            extended_state.restore();
+        }
+
+        self.extended_state_loaded = true;
+
+        true
+    }
+
+    /// Saves `task`'s extended state, if it was loaded on this core, so the next task to use extended state traps.
+    fn save_extended_state(&mut self, task: &mut Task) {
+        if core::mem::take(&mut self.extended_state_loaded) {
+            // ### Safety: `task`'s extended state was loaded, so it's been allocated and `CR0.TS` is clear.
+            unsafe { task.extended_state_mut().unwrap().save() };
+            crate::cpu::trap_extended_state();
+        }
+    }
+
+    /// Switches directly to `task`, bypassing the scheduling queue. The current task must already have been taken
+    /// (see [`Scheduler::take_current_task`]), and the remainder of its time slice is donated to `task`.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure that context switching to `task` will not cause undefined behaviour.
+    pub unsafe fn switch_to_task(
+        &mut self,
+        mut task: Task,
+        ctrl_flow_context: &mut crate::cpu::ControlContext,
+        arch_context: &mut crate::cpu::ArchContext,
+    ) {
+        debug_assert!(self.cur_task.is_none());
+
+        self.set_idle(false);
+        self.core.load.fetch_add(weight(&task), Ordering::Relaxed);
+        task.begin_run(crate::time::TSC.uptime_ticks());
+        *ctrl_flow_context = task.ctrl_flow_context;
+        *arch_context = task.arch_context;
+        self.switch_address_space(task.root_page_table_args());
+
+        task.set_state(State::Running);
+        self.cur_task = Some(task);
+    }
+
     /// Attempts to schedule the next task in the local task queue.
     pub fn next_task(
         &mut self,
@@ -71,31 +276,64 @@ impl Scheduler {
 
         debug_assert!(!crate::interrupts::are_enabled());
 
+        let now = crate::time::TSC.uptime_ticks();
+
         // Move the current task, if any, back into the scheduler queue.
         if let Some(mut cur_task) = self.cur_task.take() {
+            cur_task.account(now);
             cur_task.ctrl_flow_context = *ctrl_flow_context;
             cur_task.arch_context = *arch_context;
-            // TODO cur_task.root_page_table_args = PagingRegister::read();
+            self.save_extended_state(&mut cur_task);
 
-            self.push_task(cur_task);
+            self.requeue_task(cur_task);
         }
 
-        // {
-        //     let mut waiting_tasks = WAITING_TASKS.lock();
-        //     if waiting_tasks.len() > 0 && let Some(new_task) = waiting_tasks.pop_front() {
-        //         self.push_task(new_task);
-        //     }
-        // }
+        // Wake the tasks whose deadlines have passed.
+        let now_us = crate::time::TSC.ticks_to_us(now);
+        while let Some(task) = self.timers.pop_expired(now_us) {
+            queue_pending(task);
+        }
+
+        // Place the tasks queued before any core was scheduling, if any exist. This core is now scheduling, so
+        // there's always somewhere to place them.
+        if self.enabled {
+            let pending_tasks = PENDING_TASKS.with(|pending_tasks| core::mem::take(&mut *pending_tasks.lock()));
+            pending_tasks.into_iter().for_each(queue_pending);
+        }
+
+        self.claim_inbox();
+
+        if self.enabled {
+            self.balance_ticks += 1;
+
+            if self.balance_ticks >= Self::BALANCE_INTERVAL {
+                self.balance_ticks = 0;
+                self.balance();
+            }
+        }
+
+        let next_task = self.pop_task().or_else(|| {
+            self.set_idle(true);
+            // Cores placing a task on this core after its inbox was claimed, but before it was marked idle, won't
+            // interrupt it, so the inbox must be claimed again.
+            core::sync::atomic::fence(Ordering::SeqCst);
+            self.claim_inbox();
+
+            self.pop_task()
+        });
 
         unsafe {
-            if let Some(next_task) = self.pop_task() {
+            if let Some(mut next_task) = next_task {
+                self.set_idle(false);
                 // Modify interrupt contexts (usually, the registers).
                 *ctrl_flow_context = next_task.ctrl_flow_context;
                 *arch_context = next_task.arch_context;
 
                 // Set current page tables.
-                // TODO PagingRegister::write(&next_task.root_page_table_args);
+                self.switch_address_space(next_task.root_page_table_args());
 
+                next_task.begin_run(now);
+                next_task.set_state(State::Running);
                 self.cur_task = Some(next_task);
             } else {
                 let default_task = &self.idle_task;
@@ -105,10 +343,96 @@ impl Scheduler {
                 *arch_context = default_task.arch_context;
 
                 // Set current page tables.
-                // TODO PagingRegister::write(&default_task.root_page_table_args);
+                let root_page_table_args = *default_task.root_page_table_args();
+                self.switch_address_space(&root_page_table_args);
             };
 
-            crate::local_state::preemption_wait(core::num::NonZeroU16::new_unchecked(TIME_SLICE));
+            // Only preempt if there's a task to be preempted, or other tasks which could run; an idle core is woken
+            // by its next timer, or otherwise by other cores placing tasks on it.
+            let time_slice = (self.cur_task.is_some() || !self.tasks.is_empty())
+                .then_some(TIME_SLICE)
+                .and_then(core::num::NonZeroU16::new);
+            crate::local_state::preemption_wait(time_slice, self.timers.next_deadline());
+        }
+    }
+
+    /// Claims the tasks placed on this core by other cores.
+    fn claim_inbox(&mut self) {
+        while let Some(task) = self.core.inbox.lock().pop_front() {
+            self.requeue_task(task);
+        }
+    }
+
+    /// Records whether this core is running its idle task, so wakeups on other cores can interrupt it.
+    fn set_idle(&self, idle: bool) {
+        self.core.idle.store(idle, Ordering::SeqCst);
+    }
+
+    /// Moves a ready task to the least loaded other core, if doing so lessens the imbalance between the cores. Each
+    /// call moves at most one task, so load is spread gradually, rather than tasks bouncing between cores.
+    fn balance(&mut self) {
+        if self.tasks.is_empty() {
+            return;
+        }
+
+        CORES.with(|cores| {
+            let cores = cores.lock();
+            let load = self.core.load.load(Ordering::Relaxed);
+
+            let Some((target_id, target)) = cores
+                .iter()
+                .filter(|(core_id, _)| **core_id != self.core_id)
+                .min_by_key(|(_, core)| core.load.load(Ordering::Relaxed))
+            else {
+                return;
+            };
+            let imbalance = load.saturating_sub(target.load.load(Ordering::Relaxed));
+
+            // Moving a task of weight `w` changes the imbalance `i` to `i - 2w`, which is only an improvement while
+            // `w < i`. Idle cores are always given work, however.
+            let is_idle = target.idle.load(Ordering::Relaxed);
+            let is_movable = |task: &Task| task.affinity().allows(*target_id) && (is_idle || weight(task) < imbalance);
+
+            if !self.tasks.iter().any(is_movable) {
+                return;
+            }
+
+            let mut tasks = core::mem::take(&mut self.tasks).into_vec();
+            let task = tasks.swap_remove(tasks.iter().position(is_movable).unwrap());
+            self.tasks = BinaryHeap::from(tasks);
+
+            self.core.load.fetch_sub(weight(&task), Ordering::Relaxed);
+            place(*target_id, target, task);
+        });
+    }
+
+    /// Switches the current core to the given address space. If PCIDs are enabled, and the TLB entries tagged with
+    /// the address space's PCID on this core are still current (see [`crate::memory::address_space::tlb_tag`]),
+    /// they are preserved.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure switching address spaces will not invalidate any live memory references.
+    unsafe fn switch_address_space(&mut self, root_page_table_args: &PagingRegister) {
+        // The higher half is shared by all address spaces, so there's no need to switch to the active root.
+        if PagingRegister::read().frame() == root_page_table_args.frame() {
+            return;
+        }
+
+        match self.pcid_tags.as_mut() {
+            Some(pcid_tags) if root_page_table_args.pcid() > 0 => {
+                let pcid_tag = &mut pcid_tags[root_page_table_args.pcid() as usize];
+                let tlb_tag = crate::memory::address_space::tlb_tag(root_page_table_args.pcid());
+
+                if *pcid_tag == tlb_tag {
+                    PagingRegister::write_preserving(root_page_table_args);
+                } else {
+                    *pcid_tag = tlb_tag;
+                    PagingRegister::write(root_page_table_args);
+                }
+            }
+
+            _ => PagingRegister::write(root_page_table_args),
         }
     }
 }
@@ -0,0 +1,125 @@
+use crate::{
+    interrupts::InterruptCell,
+    memory::stack::{self, GuardedStack},
+    proc::{
+        task::{self, EntryPoint, Task, TaskStack},
+        wait::WaitQueue,
+    },
+};
+use alloc::{boxed::Box, sync::Arc};
+use core::num::NonZeroUsize;
+use spin::Mutex;
+use uuid::Uuid;
+
+/// Size of the stack of each spawned task.
+pub const STACK_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    OutOfMemory,
+    /// No more guarded stacks can be allocated.
+    StackExhausted,
+}
+
+impl From<task::Error> for Error {
+    fn from(_: task::Error) -> Self {
+        Error::OutOfMemory
+    }
+}
+
+impl From<stack::Error> for Error {
+    fn from(err: stack::Error) -> Self {
+        match err {
+            stack::Error::OutOfMemory => Error::OutOfMemory,
+            stack::Error::TooLarge | stack::Error::Exhausted => Error::StackExhausted,
+        }
+    }
+}
+
+/// State shared between a spawned task and its [`JoinHandle`].
+struct Packet<T> {
+    result: InterruptCell<Mutex<Option<T>>>,
+    finished: WaitQueue,
+}
+
+impl<T> Packet<T> {
+    fn is_finished(&self) -> bool {
+        self.result.with(|result| result.lock().is_some())
+    }
+
+    fn take(&self) -> Option<T> {
+        self.result.with(|result| result.lock().take())
+    }
+}
+
+/// Owned permission to join a spawned task, and take its result.
+pub struct JoinHandle<T> {
+    uuid: Uuid,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the ID of the spawned task.
+    #[inline]
+    pub const fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Indicates whether the spawned task has finished running its closure.
+    pub fn is_finished(&self) -> bool {
+        self.packet.is_finished()
+    }
+
+    /// Returns the task's result if it has finished, or otherwise returns the handle.
+    pub fn try_join(self) -> Result<T, Self> {
+        self.packet.take().ok_or(self)
+    }
+
+    /// Blocks the current kernel task until the spawned task has finished, returning its result.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure it's running in a kernel task, outside of any system call or interrupt handler.
+    pub unsafe fn join(self) -> T {
+        self.packet.finished.sleep_while(|| !self.packet.is_finished());
+
+        self.packet.take().unwrap()
+    }
+}
+
+/// Spawns a kernel task named `name`, which runs `func` on its own guarded stack, then exits. The task is queued to
+/// run immediately, and its result can be taken via the returned [`JoinHandle`].
+pub fn spawn<T: Send + 'static>(
+    name: &'static str,
+    priority: u8,
+    func: impl FnOnce() -> T + Send + 'static,
+) -> Result<JoinHandle<T>, Error> {
+    let packet = Arc::try_new(Packet { result: InterruptCell::new(Mutex::new(None)), finished: WaitQueue::new() })
+        .map_err(|_| Error::OutOfMemory)?;
+    let stack = GuardedStack::new(NonZeroUsize::new(STACK_SIZE).unwrap())?;
+
+    let task_packet = packet.clone();
+    let closure = Box::try_new(move || {
+        let result = func();
+
+        task_packet.result.with(|packet_result| *packet_result.lock() = Some(result));
+        task_packet.finished.wake_all();
+
+        0
+    })
+    .map_err(|_| Error::OutOfMemory)?;
+
+    let mut task = Task::new(
+        priority,
+        EntryPoint::Closure(closure),
+        TaskStack::Guarded(stack),
+        crate::cpu::default_arch_context(),
+    )?;
+    task.set_name(name);
+
+    let uuid = task.uuid();
+    trace!("Spawned task {:?} ({}).", uuid, name);
+    crate::proc::queue_pending(task);
+
+    Ok(JoinHandle { uuid, packet })
+}
@@ -0,0 +1,117 @@
+use crate::{interrupts::InterruptCell, proc::task::State};
+use abi::{TaskInfo, TaskState};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use uuid::Uuid;
+
+/// Statistics of every task which hasn't yet been dropped, keyed by task ID.
+static STATS: InterruptCell<Mutex<BTreeMap<Uuid, Arc<Stats>>>> = InterruptCell::new(Mutex::new(BTreeMap::new()));
+
+/// Scheduling statistics of a task. These are written only by the task's owner, but can be read at any time (see
+/// [`snapshot`]), so they're kept apart from the task itself.
+pub struct Stats {
+    name: Once<&'static str>,
+    priority: u8,
+    /// The task's [`TaskState`].
+    state: AtomicU64,
+    switches: AtomicU64,
+    /// TSC ticks spent running in userspace.
+    user_ticks: AtomicU64,
+    /// TSC ticks spent running in the kernel.
+    kernel_ticks: AtomicU64,
+    /// TSC ticks since boot at which the task last began running.
+    last_run: AtomicU64,
+}
+
+impl Stats {
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> {
+        self.name.get().copied()
+    }
+
+    /// Names the task, if it isn't already named.
+    #[inline]
+    pub fn set_name(&self, name: &'static str) {
+        self.name.call_once(|| name);
+    }
+
+    pub fn set_state(&self, state: State) {
+        let state = match state {
+            State::Running => TaskState::Running,
+            State::Ready => TaskState::Ready,
+            State::Blocked => TaskState::Blocked,
+            State::Exited => TaskState::Exited,
+        };
+
+        self.state.store(state as u64, Ordering::Relaxed);
+    }
+
+    /// Records the task being switched to at `now`, in TSC ticks since boot.
+    pub fn record_switch(&self, now: u64) {
+        self.switches.fetch_add(1, Ordering::Relaxed);
+        self.last_run.store(now, Ordering::Relaxed);
+    }
+
+    /// Adds `ticks` to the task's kernel time if `in_kernel`, or otherwise its user time.
+    pub fn charge(&self, ticks: u64, in_kernel: bool) {
+        let total = if in_kernel { &self.kernel_ticks } else { &self.user_ticks };
+        total.fetch_add(ticks, Ordering::Relaxed);
+    }
+
+    fn info(&self, uuid: &Uuid) -> TaskInfo {
+        let tsc = &crate::time::TSC;
+
+        let mut name = [0u8; 16];
+        let name_bytes = self.name().unwrap_or("").as_bytes();
+        let name_len = core::cmp::min(name.len(), name_bytes.len());
+        name[..name_len].copy_from_slice(&name_bytes[..name_len]);
+
+        TaskInfo {
+            id: *uuid.as_bytes(),
+            name,
+            priority: self.priority as u64,
+            state: self.state.load(Ordering::Relaxed),
+            switches: self.switches.load(Ordering::Relaxed),
+            user_us: tsc.ticks_to_us(self.user_ticks.load(Ordering::Relaxed)),
+            kernel_us: tsc.ticks_to_us(self.kernel_ticks.load(Ordering::Relaxed)),
+            last_run_us: tsc.ticks_to_us(self.last_run.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Creates and registers the statistics of the task `uuid`.
+pub fn register(uuid: Uuid, priority: u8) -> Arc<Stats> {
+    let stats = Arc::new(Stats {
+        name: Once::new(),
+        priority,
+        state: AtomicU64::new(TaskState::Ready as u64),
+        switches: AtomicU64::new(0),
+        user_ticks: AtomicU64::new(0),
+        kernel_ticks: AtomicU64::new(0),
+        last_run: AtomicU64::new(0),
+    });
+
+    STATS.with(|all_stats| all_stats.lock().insert(uuid, stats.clone()));
+
+    stats
+}
+
+/// Removes the statistics of the task `uuid`, so it's no longer reported.
+pub fn unregister(uuid: &Uuid) {
+    let stats = STATS.with(|all_stats| all_stats.lock().remove(uuid));
+    drop(stats);
+}
+
+/// Returns a snapshot of the statistics of every task.
+pub fn snapshot() -> Result<Vec<TaskInfo>, alloc::collections::TryReserveError> {
+    STATS.with(|all_stats| {
+        let all_stats = all_stats.lock();
+
+        let mut infos = Vec::new();
+        infos.try_reserve_exact(all_stats.len())?;
+        infos.extend(all_stats.iter().map(|(uuid, stats)| stats.info(uuid)));
+
+        Ok(infos)
+    })
+}
@@ -1,17 +1,127 @@
-use core::num::NonZeroUsize;
+use core::{alloc::AllocError, num::NonZeroUsize};
 
-use crate::memory::Stack;
+use crate::{
+    cpu::ExtendedState,
+    memory::{address_space, stack::GuardedStack, PagingRegister, Stack, Virtual},
+    proc::stats::{self, Stats},
+};
+use alloc::{boxed::Box, sync::Arc};
+use lzstd::Address;
 use uuid::Uuid;
 
-type EntryPoint = fn() -> u32;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The closure of an [`EntryPoint::Closure`] couldn't be passed to the task.
+    OutOfMemory,
+    /// The task's address space couldn't be created.
+    AddressSpaceError(address_space::Error),
+}
+
+/// A closure run by a kernel task (see [`EntryPoint::Closure`]).
+pub type KernelClosure = Box<dyn FnOnce() -> u32 + Send>;
+
+/// Where a task begins execution.
+pub enum EntryPoint {
+    /// A kernel function, run in the kernel's privilege level. The task exits with the function's return value.
+    Function(fn() -> u32),
+    /// A kernel closure, run in the kernel's privilege level. The task exits with the closure's return value.
+    Closure(KernelClosure),
+    /// An arbitrary virtual address, typically the entry point of a loaded executable.
+    Address(Address<Virtual>),
+}
+
+/// Entry point of every [`EntryPoint::Function`] task, which exits the task once its function returns.
+extern "sysv64" fn kernel_task_entry(function: fn() -> u32) -> ! {
+    let code = function();
+
+    // ### Safety: Kernel tasks run this function on their own stacks, outside of any system call.
+    unsafe { crate::cpu::exit_kernel_task(code) }
+}
+
+/// Entry point of every [`EntryPoint::Closure`] task, which exits the task once its closure returns.
+extern "sysv64" fn closure_task_entry(closure: *mut KernelClosure) -> ! {
+    // ### Safety: The closure was leaked by `Task::new` to be run solely by this task.
+    let closure = unsafe { Box::from_raw(closure) };
+    let code = closure();
+
+    // ### Safety: Kernel tasks run this function on their own stacks, outside of any system call.
+    unsafe { crate::cpu::exit_kernel_task(code) }
+}
+
+/// The stack a task begins execution with.
+pub enum TaskStack {
+    /// A kernel-allocated stack, owned by the task.
+    Kernel(Stack),
+    /// A kernel stack with a guard page, owned by the task.
+    Guarded(GuardedStack),
+    /// A stack already mapped within the task's address space, given as its initial stack pointer.
+    User(Address<Virtual>),
+}
+
+impl TaskStack {
+    fn stack_pointer(&self) -> u64 {
+        match self {
+            // Safety: Stack pointer is valid for its length.
+            TaskStack::Kernel(stack) => unsafe { stack.as_ptr().add(stack.len() & !0xF).addr() as u64 },
+            TaskStack::Guarded(stack) => (stack.top().get() & !0xF) as u64,
+            TaskStack::User(address) => (address.get() & !0xF) as u64,
+        }
+    }
+}
+
+/// Scheduling state of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The task is executing on a core.
+    Running,
+    /// The task is queued to run.
+    Ready,
+    /// The task is waiting to be woken (e.g. on a [`crate::proc::wait::WaitQueue`]).
+    Blocked,
+    /// The task has finished executing, and will not run again.
+    Exited,
+}
+
+/// Set of cores a task may run on, as a bitmask of core IDs (bit `n` allows the core with ID `n`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Affinity(u64);
+
+impl Affinity {
+    /// Allows every core, including those with IDs beyond the range of the bitmask.
+    pub const ALL: Self = Self(u64::MAX);
+
+    #[inline]
+    pub const fn new(mask: u64) -> Self {
+        Self(mask)
+    }
+
+    /// Indicates whether the core `core_id` is allowed.
+    #[inline]
+    pub const fn allows(self, core_id: u32) -> bool {
+        self.0 == u64::MAX || (core_id < u64::BITS && (self.0 & (1 << core_id)) > 0)
+    }
+}
 
 /// Representation object for different contexts of execution in the CPU.
 pub struct Task {
     uuid: Uuid,
+    parent: Option<Uuid>,
     prio: u8,
-    last_run: u32,
-    stack: Stack,
-    //pcid: Option<PCID>,
+    affinity: Affinity,
+    state: State,
+    /// TSC ticks since boot at which the task last began running.
+    last_run: u64,
+    /// TSC ticks since boot up to which the task's running time has been accounted.
+    accounted_until: u64,
+    /// Whether the task runs in the kernel's privilege level.
+    is_kernel: bool,
+    /// Whether the task is executing a system call.
+    in_syscall: bool,
+    stats: Arc<Stats>,
+    /// Saved extended state, allocated once the task first uses it.
+    extended_state: Option<ExtendedState>,
+    stack: TaskStack,
+    root_page_table_args: PagingRegister,
     pub ctrl_flow_context: crate::cpu::ControlContext,
     pub arch_context: crate::cpu::ArchContext,
 }
@@ -20,22 +130,97 @@ pub struct Task {
 unsafe impl Send for Task {}
 
 impl Task {
-    pub fn new(priority: u8, entry: EntryPoint, stack: Stack, arch_context: crate::cpu::ArchContext) -> Self {
+    pub fn new(
+        priority: u8,
+        entry: EntryPoint,
+        stack: TaskStack,
+        mut arch_context: crate::cpu::ArchContext,
+    ) -> Result<Self, Error> {
+        let uuid = uuid::Uuid::new_v4();
+
+        // Register the address space for this task, spanning the lower half of virtual memory.
+        address_space::register(uuid, NonZeroUsize::new(1 << 47).unwrap()).map_err(Error::AddressSpaceError)?;
+        let root_page_table_args = address_space::with(&uuid, |address_space| address_space.paging_register()).unwrap();
+
+        let is_kernel = !matches!(entry, EntryPoint::Address(_));
+        let (ip, sp) = match entry {
+            EntryPoint::Function(function) => {
+                crate::cpu::set_entry_argument(&mut arch_context, function as usize as u64);
+
+                // The entry function is jumped to rather than called, so account for the return address it expects.
+                (kernel_task_entry as usize as u64, stack.stack_pointer() - 8)
+            }
+
+            EntryPoint::Closure(closure) => {
+                // The closure's box is a fat pointer, so it's boxed again to be passed in a single register.
+                let Ok(closure) = Box::try_new(closure) else {
+                    // ### Safety: The task has never run, so its address space isn't active on any core.
+                    let address_space = unsafe { address_space::unregister(&uuid) };
+                    drop(address_space);
+
+                    return Err(Error::OutOfMemory);
+                };
+                let closure = Box::into_raw(closure);
+                crate::cpu::set_entry_argument(&mut arch_context, closure as usize as u64);
+
+                (closure_task_entry as usize as u64, stack.stack_pointer() - 8)
+            }
+
+            EntryPoint::Address(address) => (address.get() as u64, stack.stack_pointer()),
+        };
+
+        Ok(Self::from_parts(
+            uuid,
+            priority,
+            is_kernel,
+            stack,
+            root_page_table_args,
+            crate::cpu::ControlContext { ip, sp },
+            arch_context,
+        ))
+    }
+
+    /// Creates a user task which begins execution with the given contexts, in a copy-on-write clone of the address
+    /// space of the task `source` (see [`address_space::register_clone`]).
+    pub fn new_clone(
+        source: &Uuid,
+        priority: u8,
+        ctrl_flow_context: crate::cpu::ControlContext,
+        arch_context: crate::cpu::ArchContext,
+    ) -> Result<Self, Error> {
         let uuid = uuid::Uuid::new_v4();
 
-        // Register the address space for this task.
-        // TODO somehow choose the size of the address space in a meaningful way?
-        crate::memory::address_space::register(uuid, NonZeroUsize::new((1 << 48) - 1).unwrap()).unwrap();
+        address_space::register_clone(uuid, source).map_err(Error::AddressSpaceError)?;
+        let root_page_table_args = address_space::with(&uuid, |address_space| address_space.paging_register()).unwrap();
+        let stack = TaskStack::User(Address::new_truncate(ctrl_flow_context.sp as usize));
 
-        // Safety: Stack pointer is valid for its length.
-        let sp = unsafe { stack.as_ptr().add(stack.len() & !0xF).addr() } as u64;
+        Ok(Self::from_parts(uuid, priority, false, stack, root_page_table_args, ctrl_flow_context, arch_context))
+    }
 
+    fn from_parts(
+        uuid: Uuid,
+        priority: u8,
+        is_kernel: bool,
+        stack: TaskStack,
+        root_page_table_args: PagingRegister,
+        ctrl_flow_context: crate::cpu::ControlContext,
+        arch_context: crate::cpu::ArchContext,
+    ) -> Self {
         Self {
             uuid,
+            parent: None,
             prio: priority,
+            affinity: Affinity::ALL,
+            state: State::Ready,
             last_run: 0,
+            accounted_until: 0,
+            is_kernel,
+            in_syscall: false,
+            stats: stats::register(uuid, priority),
+            extended_state: None,
             stack,
-            ctrl_flow_context: crate::cpu::ControlContext { ip: entry as usize as u64, sp },
+            root_page_table_args,
+            ctrl_flow_context,
             arch_context,
         }
     }
@@ -46,30 +231,121 @@ impl Task {
         self.uuid
     }
 
+    /// Returns this task's name, if it was given one.
+    #[inline]
+    pub fn name(&self) -> Option<&'static str> {
+        self.stats.name()
+    }
+
+    #[inline]
+    pub fn set_name(&mut self, name: &'static str) {
+        self.stats.set_name(name);
+    }
+
+    /// Returns the ID of the task which may reap this task's exit code, if any (see [`crate::proc::exit::reap`]).
+    #[inline]
+    pub const fn parent(&self) -> Option<Uuid> {
+        self.parent
+    }
+
+    /// Sets the task which may reap this task's exit code. Use [`crate::proc::exit::register_child`], which also
+    /// records the task so it can be reaped.
+    #[inline]
+    pub(super) fn set_parent(&mut self, parent: Uuid) {
+        self.parent = Some(parent);
+    }
+
     /// Returns the [`TaskPriority`] struct for this task.
     #[inline]
     pub const fn priority(&self) -> u8 {
         self.prio
     }
 
+    /// Returns the set of cores this task may run on.
+    #[inline]
+    pub const fn affinity(&self) -> Affinity {
+        self.affinity
+    }
+
+    /// Restricts the cores this task may run on. This takes effect the next time the task is placed on a core (see
+    /// [`crate::proc::queue_pending`]).
+    #[inline]
+    pub fn set_affinity(&mut self, affinity: Affinity) {
+        self.affinity = affinity;
+    }
+
+    /// Returns the scheduling state of this task.
+    #[inline]
+    pub const fn state(&self) -> State {
+        self.state
+    }
+
+    #[inline]
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.stats.set_state(state);
+    }
+
+    /// Returns the TSC ticks since boot at which this task last began running.
     #[inline]
-    pub const fn last_run(&self) -> u32 {
+    pub const fn last_run(&self) -> u64 {
         self.last_run
     }
-}
 
-impl Ord for Task {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        use core::cmp::Ordering;
+    /// Records this task beginning to run at `now`, in TSC ticks since boot.
+    pub fn begin_run(&mut self, now: u64) {
+        self.last_run = now;
+        self.accounted_until = now;
+        // Tasks which were switched away from within a system call resume in userspace (or restart the call).
+        self.in_syscall = false;
 
-        let last_run_cmp = self.last_run().cmp(&other.last_run());
-        let priority_cmp = self.priority().cmp(&other.priority());
+        self.stats.record_switch(now);
+    }
+
+    /// Accounts the time this task has run up to `now` (in TSC ticks since boot) as kernel or user time, according
+    /// to where it's been running.
+    pub fn account(&mut self, now: u64) {
+        let ticks = now.saturating_sub(self.accounted_until);
+        self.accounted_until = now;
 
-        match (priority_cmp, last_run_cmp) {
-            (Ordering::Greater, Ordering::Greater) => Ordering::Greater,
-            (Ordering::Greater, _) => Ordering::Less,
-            (Ordering::Equal | Ordering::Less, ordering) => ordering,
+        self.stats.charge(ticks, self.is_kernel || self.in_syscall);
+    }
+
+    /// Records this task entering or leaving a system call at `now` (in TSC ticks since boot), so the time spent
+    /// within it is accounted as kernel time.
+    pub fn set_in_syscall(&mut self, in_syscall: bool, now: u64) {
+        self.account(now);
+        self.in_syscall = in_syscall;
+    }
+
+    /// Returns this task's saved extended state, allocating it in its initial configuration if the task hasn't yet
+    /// used extended state.
+    pub fn extended_state_mut(&mut self) -> Result<&mut ExtendedState, AllocError> {
+        if self.extended_state.is_none() {
+            self.extended_state = Some(ExtendedState::new()?);
         }
+
+        Ok(self.extended_state.as_mut().unwrap())
+    }
+
+    /// Returns the paging register value for this task's address space.
+    #[inline]
+    pub const fn root_page_table_args(&self) -> &PagingRegister {
+        &self.root_page_table_args
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        stats::unregister(&self.uuid);
+    }
+}
+
+impl Ord for Task {
+    /// Orders tasks by which should run first: tasks of higher priority, then whichever has waited the longest
+    /// since it last ran.
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.priority().cmp(&other.priority()).then_with(|| other.last_run().cmp(&self.last_run()))
     }
 }
 
@@ -89,6 +365,11 @@ impl PartialEq for Task {
 
 impl core::fmt::Debug for Task {
     fn fmt(&self, formatter: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        formatter.debug_struct("Task").field("Priority", &self.prio).finish()
+        formatter
+            .debug_struct("Task")
+            .field("Name", &self.name())
+            .field("Priority", &self.prio)
+            .field("State", &self.state)
+            .finish()
     }
 }
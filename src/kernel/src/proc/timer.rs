@@ -0,0 +1,87 @@
+use crate::proc::task::Task;
+use alloc::collections::BinaryHeap;
+
+/// A task sleeping until its deadline.
+struct Timer {
+    /// Microseconds since boot (see [`crate::time::Tsc::uptime_us`]) at which the task is woken.
+    deadline: u64,
+    task: Task,
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        // Reversed, so the heap yields the earliest deadline first.
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+/// Tasks sleeping on a core, ordered by deadline.
+pub struct TimerQueue {
+    timers: BinaryHeap<Timer>,
+}
+
+impl TimerQueue {
+    pub const fn new() -> Self {
+        Self { timers: BinaryHeap::new() }
+    }
+
+    /// Queues `task` to be woken once `deadline` has passed.
+    pub fn push(&mut self, deadline: u64, task: Task) {
+        self.timers.push(Timer { deadline, task });
+    }
+
+    /// Returns the earliest deadline of any sleeping task.
+    #[inline]
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.timers.peek().map(|timer| timer.deadline)
+    }
+
+    /// Removes and returns a task whose deadline is at or before `now`, if any.
+    pub fn pop_expired(&mut self, now: u64) -> Option<Task> {
+        match self.next_deadline() {
+            Some(deadline) if deadline <= now => self.timers.pop().map(|timer| timer.task),
+            _ => None,
+        }
+    }
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocks the current kernel task until `deadline`, in microseconds since boot (see
+/// [`crate::time::Tsc::uptime_us`]).
+///
+/// ### Safety
+///
+/// Caller must ensure it's running in a kernel task, outside of any system call or interrupt handler.
+pub unsafe fn sleep_until(deadline: u64) {
+    while crate::time::TSC.uptime_us() < deadline {
+        crate::local_state::sleep_on_timer(deadline);
+    }
+}
+
+/// Blocks the current kernel task for at least `microseconds`.
+///
+/// ### Safety
+///
+/// Caller must ensure it's running in a kernel task, outside of any system call or interrupt handler.
+pub unsafe fn sleep_us(microseconds: u64) {
+    sleep_until(crate::time::TSC.uptime_us().saturating_add(microseconds));
+}
@@ -0,0 +1,75 @@
+use crate::{interrupts::InterruptCell, proc::task::Task};
+use alloc::collections::VecDeque;
+use spin::Mutex;
+
+/// Tasks blocked until some condition no longer holds, to be woken by whichever task changes it.
+///
+/// Wakers must update the condition's state before waking the queue. As the condition is checked with the queue
+/// locked, a task can't begin waiting after the condition has changed, but before it's woken.
+pub struct WaitQueue {
+    tasks: InterruptCell<Mutex<VecDeque<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { tasks: InterruptCell::new(Mutex::new(VecDeque::new())) }
+    }
+
+    /// If `condition` holds, calls `waiter` (e.g. to take the current task out of the scheduler) and queues the task
+    /// it returns to be woken. Returns whether a task is now waiting.
+    pub fn wait_while(&self, condition: impl FnOnce() -> bool, waiter: impl FnOnce() -> Option<Task>) -> bool {
+        self.tasks.with(|tasks| {
+            let mut tasks = tasks.lock();
+
+            match condition().then(waiter).flatten() {
+                Some(task) => {
+                    tasks.push_back(task);
+                    true
+                }
+
+                None => false,
+            }
+        })
+    }
+
+    /// Blocks the current kernel task until `condition` no longer holds.
+    ///
+    /// Unlike system calls (which wait with [`WaitQueue::wait_while`], to be resumed with the appropriate context),
+    /// kernel tasks run on their own stacks, so they're simply descheduled in place, and resume here once woken.
+    ///
+    /// ### Safety
+    ///
+    /// Caller must ensure it's running in a kernel task, outside of any system call or interrupt handler.
+    pub unsafe fn sleep_while(&self, condition: impl Fn() -> bool) {
+        while condition() {
+            crate::local_state::wait_on(self, &condition);
+        }
+    }
+
+    /// Wakes the task which has been waiting the longest. Returns whether any task was woken.
+    pub fn wake_one(&self) -> bool {
+        self.tasks.with(|tasks| tasks.lock().pop_front()).map(crate::proc::queue_pending).is_some()
+    }
+
+    /// Wakes every waiting task, returning how many were woken.
+    pub fn wake_all(&self) -> usize {
+        let tasks = self.tasks.with(|tasks| core::mem::take(&mut *tasks.lock()));
+        let count = tasks.len();
+        tasks.into_iter().for_each(crate::proc::queue_pending);
+
+        count
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WaitQueue {
+    fn drop(&mut self) {
+        // Tasks can't be left waiting on a queue which can no longer be woken.
+        self.wake_all();
+    }
+}
@@ -83,8 +83,67 @@ mod clock {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+mod tsc {
+    /// The timestamp counter, used as a monotonic time source.
+    pub static TSC: spin::Lazy<Tsc> = spin::Lazy::new(|| {
+        crate::interrupts::without(|| {
+            let frequency = crate::arch::x64::cpuid::CPUID
+                .get_tsc_info()
+                .and_then(|tsc_info| tsc_info.tsc_frequency())
+                .unwrap_or_else(|| {
+                    // The TSC frequency isn't enumerated, so calibrate it against the system clock.
+                    let start_timestamp = read_timestamp();
+                    super::SYSTEM_CLOCK.spin_wait_us(super::US_WAIT);
+                    let end_timestamp = read_timestamp();
+
+                    (end_timestamp - start_timestamp) * (super::US_FREQ_FACTOR as u64)
+                });
+
+            Tsc { frequency, base_timestamp: read_timestamp() }
+        })
+    });
+
+    #[inline]
+    fn read_timestamp() -> u64 {
+        // ### Safety: `rdtsc` has no side effects.
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    pub struct Tsc {
+        frequency: u64,
+        base_timestamp: u64,
+    }
+
+    impl Tsc {
+        #[inline]
+        pub const fn frequency(&self) -> u64 {
+            self.frequency
+        }
+
+        /// Returns the number of ticks elapsed since the TSC was first initialized, which is shortly after boot.
+        #[inline]
+        pub fn uptime_ticks(&self) -> u64 {
+            read_timestamp().saturating_sub(self.base_timestamp)
+        }
+
+        /// Converts a number of TSC ticks to microseconds.
+        #[inline]
+        pub fn ticks_to_us(&self, ticks: u64) -> u64 {
+            ((ticks as u128) * (super::US_PER_SEC as u128) / (self.frequency as u128)) as u64
+        }
+
+        /// Returns the number of microseconds elapsed since the TSC was first initialized, which is shortly after boot.
+        pub fn uptime_us(&self) -> u64 {
+            self.ticks_to_us(self.uptime_ticks())
+        }
+    }
+}
+
 pub(self) const US_PER_SEC: u32 = 1000000;
 pub(self) const US_WAIT: u32 = 10000;
 pub(self) const US_FREQ_FACTOR: u32 = US_PER_SEC / US_WAIT;
 
 pub use clock::*;
+#[cfg(target_arch = "x86_64")]
+pub use tsc::*;
@@ -0,0 +1,296 @@
+#![no_std]
+
+//! Definitions of the system call interface, shared by the kernel and userspace.
+//!
+//! A system call is made with the `syscall` instruction. The vector is passed in `rdi`, and up to five arguments
+//! are passed in `rsi`, `rdx`, `r10`, `r8`, and `r9` (in that order). The result is returned as a [`SyscallReturn`].
+
+/// Defines a `#[repr(u64)]` enum which can be fallibly converted from its raw value.
+macro_rules! raw_enum {
+    ($(#[$meta:meta])* pub enum $name:ident { $($(#[$variant_meta:meta])* $variant:ident = $value:literal,)* }) => {
+        $(#[$meta])*
+        #[repr(u64)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant = $value,)*
+        }
+
+        impl TryFrom<u64> for $name {
+            type Error = u64;
+
+            fn try_from(value: u64) -> Result<Self, u64> {
+                match value {
+                    $($value => Ok($name::$variant),)*
+                    value => Err(value),
+                }
+            }
+        }
+    };
+}
+
+raw_enum! {
+    /// System call vectors, passed in `rdi`.
+    pub enum Vector {
+        /// Gives up the remainder of the current task's time slice.
+        Yield = 0x001,
+        /// Writes the 16-byte ID of the current task to the pointer in `rsi`.
+        TaskId = 0x002,
+        /// Exits the current task with the 32-bit exit code in `rsi`. Never returns.
+        Exit = 0x003,
+        /// Restricts the current task to the cores set in the bitmask in `rsi` (bit `n` allows the core with ID
+        /// `n`, and a mask of all ones allows every core). If the current core isn't allowed, the task moves to one
+        /// which is before returning. Fails with [`Error::InvalidArgument`] if no running core is allowed.
+        SetAffinity = 0x004,
+        /// Writes a [`TaskInfo`] for each task to the buffer pointed to by `rsi`, with room for the number of records
+        /// in `rdx`, returning the total number of tasks. If that exceeds the buffer's capacity, only as many records
+        /// as fit are written.
+        TaskStats = 0x005,
+        /// Creates a child of the current task in a copy-on-write clone of its address space, writing the child's
+        /// 16-byte ID to the pointer in `rsi`. Returns 1 to the current task, and 0 to the child, which resumes from
+        /// the same system call with no handles.
+        TaskClone = 0x006,
+        /// Blocks until the child task whose 16-byte ID is pointed to by `rsi` exits, returning its exit code. Fails
+        /// with [`Error::InvalidArgument`] if the task isn't a child of the current task, or was already reaped.
+        TaskWait = 0x007,
+
+        /// Logs the null-terminated string pointed to by `rdx`, with the [`LogLevel`] in `rsi`.
+        Log = 0x100,
+
+        /// Maps a region of memory with the size in `rsi`, alignment in `rdx`, and [`MmapFlags`] in `r10`,
+        /// returning its address. If `r8` is non-zero, it's the page-aligned address to place the region at (see
+        /// [`MmapFlags::FIXED`] and [`MmapFlags::FIXED_NOREPLACE`]), or otherwise a hint.
+        Mmap = 0x200,
+        /// Unmaps the page-aligned address in `rsi` and the `rdx` bytes following it.
+        Munmap = 0x201,
+        /// Changes the protection of the page-aligned address in `rsi` and the `rdx` bytes following it to the
+        /// [`MmapFlags`] in `r10`. Shared memory can't be given protection its handle didn't grant when it was mapped.
+        Mprotect = 0x202,
+        /// Creates a zeroed shared memory object of at least the size in `rsi`, returning its handle. The object's
+        /// memory is freed once every handle to it has been closed, and it's been unmapped from every address space.
+        ShmCreate = 0x210,
+        /// Maps the shared memory object with the handle in `rsi`, with the protection of the [`MmapFlags`] in
+        /// `rdx`, returning its address. Placement follows [`Vector::Mmap`], with the address in `r10`. The handle
+        /// requires [`HandleRights::MAP`], along with the rights corresponding to the requested protection.
+        ShmMap = 0x211,
+
+        /// Returns the number of microseconds elapsed since boot.
+        Uptime = 0x300,
+        /// Blocks the current task until at least the number of microseconds in `rsi` have elapsed.
+        Sleep = 0x301,
+
+        /// Creates a pair of connected channel endpoints, returning their handles in the low and high 32 bits.
+        ChannelCreate = 0x400,
+        /// Sends the message pointed to by `rdx`, of the length in `r10` (at most [`MAX_MESSAGE_LEN`]), through the
+        /// channel endpoint with the handle in `rsi`. Blocks while the peer's queue is full, unless the
+        /// [`ChannelFlags`] in `r8` specify otherwise. If `r9` is a non-zero handle (with
+        /// [`HandleRights::TRANSFER`]), it's moved out of the sender's handle table along with the message.
+        ChannelSend = 0x401,
+        /// Receives a message from the channel endpoint with the handle in `rsi` into the buffer pointed to by `rdx`,
+        /// of the length in `r10`. Returns the message's length in the low 32 bits, and the handle of the object
+        /// transferred with it (or `0`, if none was) in the high 32 bits. Blocks while no message is queued, unless
+        /// the [`ChannelFlags`] in `r8` specify otherwise.
+        ChannelRecv = 0x402,
+
+        /// Creates a call/reply endpoint, returning its handle.
+        EndpointCreate = 0x410,
+        /// Calls the endpoint with the handle in `rsi`, sending the [`RegisterMessage`] in `rdx`, `r10`, `r8`, and
+        /// `r9`. Blocks until a server replies, and returns its reply in the same registers.
+        EndpointCall = 0x411,
+        /// Receives a call from the endpoint with the handle in `rsi`, blocking until one is made. The call's
+        /// [`RegisterMessage`] is returned in `rdx`, `r10`, `r8`, and `r9`. Receiving again before replying
+        /// abandons the previous caller, whose call fails with [`Error::PeerClosed`].
+        EndpointRecv = 0x412,
+        /// Replies to the last call received from the endpoint with the handle in `rsi`, with the
+        /// [`RegisterMessage`] in `rdx`, `r10`, `r8`, and `r9`.
+        EndpointReply = 0x413,
+        /// Combines [`Vector::EndpointReply`] and [`Vector::EndpointRecv`]. If no other call is waiting, the
+        /// caller runs immediately, on the remainder of the server's time slice.
+        EndpointReplyRecv = 0x414,
+
+        /// Duplicates the handle in `rsi`, returning a new handle to the same object with the [`HandleRights`] in
+        /// `rdx`. The handle requires [`HandleRights::DUPLICATE`], and the new rights must be a subset of its own.
+        HandleDuplicate = 0x500,
+        /// Closes the handle in `rsi`. The object is dropped once no other references to it exist.
+        HandleClose = 0x501,
+    }
+}
+
+raw_enum! {
+    /// Errors returned by system calls, in `rax`.
+    pub enum Error {
+        /// The system call vector does not correspond to any system call.
+        InvalidVector = 1,
+        /// An argument is out of range, or otherwise malformed.
+        InvalidArgument = 2,
+        /// A pointer argument is null, outside of userspace, or not mapped with the required access.
+        InvalidPointer = 3,
+        /// The kernel failed to allocate the memory required to complete the system call.
+        OutOfMemory = 4,
+        /// The system call was made outside of any task.
+        NoTask = 5,
+        /// A handle argument doesn't refer to an object of the required type.
+        InvalidHandle = 6,
+        /// The operation would block, but blocking wasn't permitted.
+        WouldBlock = 7,
+        /// The other end of the channel has been closed.
+        PeerClosed = 8,
+        /// The provided buffer is too small for the received data, which remains queued.
+        BufferTooSmall = 9,
+        /// The handle argument lacks the [`HandleRights`] required by the operation.
+        AccessDenied = 10,
+        /// A fixed mapping overlaps memory which is already mapped (see [`MmapFlags::FIXED_NOREPLACE`]).
+        AlreadyMapped = 11,
+    }
+}
+
+raw_enum! {
+    /// Scheduling states of a task, as reported in [`TaskInfo`].
+    pub enum TaskState {
+        /// The task is executing on a core.
+        Running = 0,
+        /// The task is queued to run.
+        Ready = 1,
+        /// The task is waiting to be woken.
+        Blocked = 2,
+        /// The task has finished executing, and will not run again.
+        Exited = 3,
+    }
+}
+
+raw_enum! {
+    /// Levels for [`Vector::Log`].
+    pub enum LogLevel {
+        Error = 1,
+        Warn = 2,
+        Info = 3,
+        Debug = 4,
+        Trace = 5,
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags for [`Vector::Mmap`].
+    pub struct MmapFlags : usize {
+        const READ = 0b1;
+        const READ_WRITE = 0b11;
+        const READ_EXECUTE = 0b111;
+        const NOT_DEMAND = 0b1000;
+        /// Place the mapping exactly at the given address, replacing any existing mappings.
+        const FIXED = 0b1_0000;
+        /// Place the mapping exactly at the given address, failing if it overlaps any existing mappings.
+        const FIXED_NOREPLACE = 0b10_0000;
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags for [`Vector::ChannelSend`] and [`Vector::ChannelRecv`].
+    pub struct ChannelFlags : usize {
+        /// Fail with [`Error::WouldBlock`] rather than blocking.
+        const NONBLOCKING = 0b1;
+    }
+}
+
+bitflags::bitflags! {
+    /// Operations permitted on the object referred to by a handle. Rights which don't apply to the object's type
+    /// are ignored.
+    pub struct HandleRights : u32 {
+        /// Receive from channels, and receive (and reply to) calls on endpoints. Map memory as readable.
+        const READ = 0b1;
+        /// Send through channels, and call endpoints. Map memory as writable.
+        const WRITE = 0b10;
+        /// Map memory as executable.
+        const EXECUTE = 0b100;
+        /// Map memory objects into an address space.
+        const MAP = 0b1000;
+        /// Duplicate the handle (see [`Vector::HandleDuplicate`]).
+        const DUPLICATE = 0b1_0000;
+        /// Move the handle to another task (see [`Vector::ChannelSend`]).
+        const TRANSFER = 0b10_0000;
+    }
+}
+
+/// Small integer which refers to a kernel object, within the handle table of a single task. Handle `0` never
+/// refers to any object.
+pub type Handle = u32;
+
+/// Message transferred entirely in registers by endpoint calls and replies.
+pub type RegisterMessage = [u64; 4];
+
+/// Maximum length of a single channel message, in bytes.
+pub const MAX_MESSAGE_LEN: usize = 0x1000;
+
+/// Snapshot of a task's scheduling state and accounting, returned by [`Vector::TaskStats`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskInfo {
+    /// ID of the task.
+    pub id: [u8; 16],
+    /// Name of the task (if it has one), padded with null bytes.
+    pub name: [u8; 16],
+    pub priority: u64,
+    /// The task's [`TaskState`].
+    pub state: u64,
+    /// Number of times the task has been switched to.
+    pub switches: u64,
+    /// Microseconds the task has spent running in userspace.
+    pub user_us: u64,
+    /// Microseconds the task has spent running in the kernel, including in system calls.
+    pub kernel_us: u64,
+    /// Microseconds since boot at which the task last began running.
+    pub last_run_us: u64,
+}
+
+/// Registers returned to the caller of a system call.
+///
+/// On success, `rax` is `0` and `rdx` holds the system call's return value (if any). On failure, `rax` holds the
+/// [`Error`] value, and `rdx` is `0`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallReturn {
+    pub rax: u64,
+    pub rdx: u64,
+}
+
+impl SyscallReturn {
+    /// Converts the returned registers into a result. An error code not known to this version of the ABI is
+    /// returned as-is, in the `Err` variant of the error.
+    pub fn into_result(self) -> Result<u64, Result<Error, u64>> {
+        match self.rax {
+            0 => Ok(self.rdx),
+            rax => Err(Error::try_from(rax)),
+        }
+    }
+}
+
+impl From<Result<u64, Error>> for SyscallReturn {
+    fn from(result: Result<u64, Error>) -> Self {
+        match result {
+            Ok(value) => Self { rax: 0, rdx: value },
+            Err(err) => Self { rax: err as u64, rdx: 0 },
+        }
+    }
+}
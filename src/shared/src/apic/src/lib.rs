@@ -246,9 +246,21 @@ impl Apic {
         ErrorStatusFlags::from_bits_truncate(self.read_register(Register::ERR) as u8)
     }
 
-    #[inline]
+    /// Sends an interprocessor interrupt, as described by `interrupt_command`.
     pub unsafe fn send_int_cmd(&self, interrupt_command: InterruptCommand) {
-        self.write_register(Register::ICR, interrupt_command.get_raw());
+        let raw = interrupt_command.get_raw();
+
+        match self.0 {
+            // The xAPIC ICR is split across two registers, with an 8-bit destination in the high bits of the upper
+            // register. Writing the lower register sends the interrupt, so it must be written last.
+            Type::xAPIC(address) => {
+                let destination = raw.get_bits(32..40) as u32;
+                address.add(Register::ICR.xapic_offset() + 0x10).cast::<u32>().write_volatile(destination << 24);
+                address.add(Register::ICR.xapic_offset()).cast::<u32>().write_volatile(raw as u32);
+            }
+
+            Type::x2APIC => msr::wrmsr(Register::ICR.x2apic_msr(), raw),
+        }
     }
 
     #[inline]
@@ -2,117 +2,261 @@
 #![feature(
     allocator_api,                  // #32838 <https://github.com/rust-lang/rust/issues/32838>
     strict_provenance,              // #95228 <https://github.com/rust-lang/rust/issues/95228>
-    nonnull_slice_from_raw_parts,   // #71941 <https://github.com/rust-lang/rust/issues/71941>
-    pointer_is_aligned,             // #96284 <https://github.com/rust-lang/rust/issues/96284>
-    ptr_as_uninit,                  // #75402 <https://github.com/rust-lang/rust/issues/75402>
     slice_ptr_get,                  // #74265 <https://github.com/rust-lang/rust/issues/74265>
-    maybe_uninit_slice,             // #63569 <https://github.com/rust-lang/rust/issues/63569>
     int_roundings,                  // #88581 <https://github.com/rust-lang/rust/issues/88581>
-    nonzero_min_max,                // #89065 <https://github.com/rust-lang/rust/issues/89065>
     const_mut_refs,
-    const_option,
-    const_option_ext,
-    ptr_metadata,
 )]
 
+#[macro_use]
+extern crate log;
+
 use core::{
     alloc::{AllocError, Allocator, Layout},
+    num::NonZeroUsize,
     ptr::NonNull,
 };
 use spin::Mutex;
-use try_alloc::vec::TryVec;
 
+/// Shift of the smallest size class, which must fit a free item's link.
+const MIN_SIZE_SHIFT: u32 = 4;
+/// Shift of the largest size class which can be requested.
+const MAX_SIZE_SHIFT: u32 = 12;
+const CLASS_COUNT: usize = (MAX_SIZE_SHIFT - MIN_SIZE_SHIFT + 1) as usize;
+
+/// Minimum length of a slab.
+///
 /// ## Remark
-/// This align shouldn't be constant; it needs to be dynamic based upon:
+/// This shouldn't be constant; it needs to be dynamic based upon:
 /// * Cache line size
 /// * Available memory
-/// * Desired memory profile    
-const SLAB_LENGTH: usize = 0x4000;
+/// * Desired memory profile
+const MIN_SLAB_LENGTH: usize = 0x1000;
+/// Minimum number of items which fit in a slab (the slab's header may take the place of one more).
+const MIN_SLAB_ITEMS: usize = 8;
 
-pub struct Slab<A: Allocator> {
-    layout: Layout,
+/// Link stored within each free item of a slab.
+struct FreeItem {
+    next: Option<NonNull<FreeItem>>,
+}
+
+/// Header of a slab, stored at the base of the slab's memory, so the slab of any item can be found by aligning the
+/// item's address down to the slab's length.
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeItem>>,
+    used: usize,
     capacity: usize,
-    items: TryVec<NonNull<[u8]>, A>,
-    memory: NonNull<[u8]>,
-    allocator: A,
+    item_size: usize,
 }
 
-impl<A: Allocator> core::fmt::Debug for Slab<A> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("Slab")
-            .field("Layout", &self.layout())
-            .field("Capacity", &self.capacity())
-            .field("Items", &self.items.len())
-            .field("Memory", &self.memory.to_raw_parts())
-            .finish()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlabState {
+    Empty,
+    Partial,
+    Full,
 }
 
-impl<A: Allocator + Copy> Slab<A> {
-    fn new_in(layout: Layout, allocator: A) -> Result<Self, AllocError> {
-        debug_assert!(layout.size() > 0);
+impl Slab {
+    /// Length of the slabs holding items of `item_size`. Slabs are aligned to their length.
+    const fn length(item_size: usize) -> usize {
+        let length = item_size * MIN_SLAB_ITEMS;
 
-        let padded_layout = layout.pad_to_align();
-        let memory = allocator.allocate(unsafe { Layout::from_size_align_unchecked(SLAB_LENGTH, layout.align()) })?;
-        let capacity = memory.len() / padded_layout.size();
-        let mut list = TryVec::with_capacity_in(capacity, allocator).map_err(|_| AllocError)?;
+        if length > MIN_SLAB_LENGTH {
+            length
+        } else {
+            MIN_SLAB_LENGTH
+        }
+    }
 
-        for index in 0..capacity {
-            let start_offset = index * padded_layout.size();
-            let end_offset = start_offset + layout.size();
-            list.push(unsafe { memory.get_unchecked_mut(start_offset..end_offset) }).map_err(|_| AllocError)?;
+    /// Offset of the first item within the slab, such that every item is aligned to its size.
+    const fn items_offset(item_size: usize) -> usize {
+        core::mem::size_of::<Self>().next_multiple_of(item_size)
+    }
+
+    /// Allocates a slab of items of `item_size`, with all of its items free.
+    fn new_in(item_size: usize, allocator: impl Allocator) -> Result<NonNull<Self>, AllocError> {
+        let length = Self::length(item_size);
+        // ### Safety: Slab length is a power of two.
+        let memory = allocator.allocate(unsafe { Layout::from_size_align_unchecked(length, length) })?;
+        let base = memory.as_non_null_ptr();
+        // Items find their slab by aligning down to its length, so a misaligned slab would corrupt other memory.
+        assert!(base.addr().get() & (length - 1) == 0, "backing allocator returned a misaligned slab");
+
+        let items_offset = Self::items_offset(item_size);
+        let capacity = (length - items_offset) / item_size;
+
+        // Link the items in address order, so they're handed out in address order.
+        let mut free = None;
+        for index in (0..capacity).rev() {
+            // ### Safety: Item is within the slab's memory, and aligned to its size.
+            let item = unsafe { base.as_ptr().add(items_offset + (index * item_size)) }.cast::<FreeItem>();
+            // ### Safety: See above.
+            unsafe { item.write(FreeItem { next: free }) };
+            free = NonNull::new(item);
         }
 
-        Ok(Self { layout, capacity, items: list, memory, allocator })
+        let slab = base.cast::<Self>();
+        // ### Safety: Slab's memory is aligned to its length, which always fits the header.
+        unsafe { slab.as_ptr().write(Self { prev: None, next: None, free, used: 0, capacity, item_size }) };
+
+        Ok(slab)
     }
-}
 
-impl<A: Allocator> Slab<A> {
-    #[inline]
-    pub const fn layout(&self) -> Layout {
-        self.layout
+    /// Returns the slab containing `ptr`, if it could be an item of a slab of items of `item_size`.
+    ///
+    /// ### Safety
+    ///
+    /// `ptr` must have been allocated from a slab of items of `item_size`.
+    unsafe fn of_item(ptr: NonNull<u8>, item_size: usize) -> Option<NonNull<Self>> {
+        let length = Self::length(item_size);
+        let slab = ptr.map_addr(|addr| NonZeroUsize::new(addr.get() & !(length - 1)).unwrap()).cast::<Self>();
+        let item_offset = ptr.addr().get() & (length - 1);
+
+        // ### Safety: Caller is required to provide an item of a slab, whose header is at the base of its memory.
+        let is_item = unsafe { slab.as_ref() }.item_size == item_size
+            && item_offset >= Self::items_offset(item_size)
+            && ((item_offset - Self::items_offset(item_size)) % item_size) == 0;
+
+        is_item.then_some(slab)
     }
 
-    #[inline]
-    pub fn remaining(&self) -> usize {
-        self.items.len()
+    fn state(&self) -> SlabState {
+        if self.used == 0 {
+            SlabState::Empty
+        } else if self.used < self.capacity {
+            SlabState::Partial
+        } else {
+            SlabState::Full
+        }
     }
 
-    #[inline]
-    pub const fn capacity(&self) -> usize {
-        self.capacity
+    fn take_item(&mut self) -> Option<NonNull<u8>> {
+        let item = self.free?;
+        // ### Safety: Free items always hold a valid link.
+        self.free = unsafe { item.as_ref() }.next;
+        self.used += 1;
+
+        Some(item.cast())
     }
 
-    #[inline]
-    pub fn take_item(&mut self) -> Option<NonNull<[u8]>> {
-        self.items.pop()
+    /// ### Safety
+    ///
+    /// `ptr` must be an item of this slab, which is in use.
+    unsafe fn return_item(&mut self, ptr: NonNull<u8>) {
+        let item = ptr.cast::<FreeItem>();
+        // ### Safety: Caller is required to provide an item of this slab, which is no longer used.
+        unsafe { item.as_ptr().write(FreeItem { next: self.free }) };
+        self.free = Some(item);
+        self.used -= 1;
     }
+}
 
-    pub fn return_item(&mut self, ptr: NonNull<u8>) -> Result<(), AllocError> {
-        if (unsafe { &*self.memory.as_ptr() }).as_ptr_range().contains(&ptr.as_ptr().cast_const())
-            && ptr.addr().get().next_multiple_of(self.layout().align()) == ptr.addr().get()
-        {
-            let layout_size = self.layout().size();
-            self.items.push(NonNull::slice_from_raw_parts(ptr, layout_size)).map_err(|_| AllocError)
-        } else {
-            Err(AllocError)
+/// Intrusive list of slabs, linked through their headers.
+struct SlabList {
+    head: Option<NonNull<Slab>>,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self { head: None }
+    }
+
+    /// ### Safety
+    ///
+    /// `slab` must be valid, and not in any list.
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        // ### Safety: Caller is required to provide a valid slab.
+        let slab_ref = unsafe { slab.as_mut() };
+        slab_ref.prev = None;
+        slab_ref.next = self.head;
+
+        if let Some(mut head) = self.head {
+            // ### Safety: Slabs in the list are valid.
+            unsafe { head.as_mut() }.prev = Some(slab);
+        }
+
+        self.head = Some(slab);
+    }
+
+    /// ### Safety
+    ///
+    /// `slab` must be valid, and in this list.
+    unsafe fn remove(&mut self, mut slab: NonNull<Slab>) {
+        // ### Safety: Caller is required to provide a valid slab.
+        let slab_ref = unsafe { slab.as_mut() };
+
+        match slab_ref.prev {
+            // ### Safety: Slabs in the list are valid.
+            Some(mut prev) => unsafe { prev.as_mut() }.next = slab_ref.next,
+            None => self.head = slab_ref.next,
+        }
+
+        if let Some(mut next) = slab_ref.next {
+            // ### Safety: Slabs in the list are valid.
+            unsafe { next.as_mut() }.prev = slab_ref.prev;
         }
+
+        slab_ref.prev = None;
+        slab_ref.next = None;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<Slab>> {
+        let slab = self.head?;
+        // ### Safety: Slab is the head of this list.
+        unsafe { self.remove(slab) };
+
+        Some(slab)
     }
 }
 
-impl<A: Allocator> Drop for Slab<A> {
-    fn drop(&mut self) {
-        unsafe {
-            self.allocator.deallocate(
-                self.memory.as_non_null_ptr(),
-                Layout::from_size_align_unchecked(self.memory.len(), self.layout.align()),
-            )
-        };
+/// Slabs of a single size class, listed by how many of their items are in use.
+struct SizeClass {
+    empty: SlabList,
+    partial: SlabList,
+    full: SlabList,
+}
+
+impl SizeClass {
+    const fn new() -> Self {
+        Self { empty: SlabList::new(), partial: SlabList::new(), full: SlabList::new() }
+    }
+
+    fn list_mut(&mut self, state: SlabState) -> &mut SlabList {
+        match state {
+            SlabState::Empty => &mut self.empty,
+            SlabState::Partial => &mut self.partial,
+            SlabState::Full => &mut self.full,
+        }
+    }
+
+    /// Moves `slab` to the list for its state, if its state changed from `old_state`.
+    ///
+    /// ### Safety
+    ///
+    /// `slab` must be valid, and in the list for `old_state`.
+    unsafe fn relist(&mut self, slab: NonNull<Slab>, old_state: SlabState) {
+        // ### Safety: Caller is required to provide a valid slab.
+        let state = unsafe { slab.as_ref() }.state();
+
+        if state != old_state {
+            // ### Safety: Caller is required to provide the list the slab is in.
+            unsafe {
+                self.list_mut(old_state).remove(slab);
+                self.list_mut(state).push(slab);
+            }
+        }
     }
 }
 
+/// Allocator which serves small allocations from slabs of power-of-two size classes, and forwards larger ones to
+/// the backing allocator.
+///
+/// Each size class keeps its slabs in empty, partial, and full lists, and allocates from partial slabs before
+/// empty ones. Slabs are aligned to their length, so the slab of any item is found in constant time. Empty slabs
+/// are kept for reuse until they're reclaimed (see [`SlabAllocator::reclaim`]).
 pub struct SlabAllocator<A: Allocator> {
-    slabs: Mutex<TryVec<Slab<A>, A>>,
+    classes: [Mutex<SizeClass>; CLASS_COUNT],
     max_size: usize,
     allocator: A,
 }
@@ -123,44 +267,319 @@ unsafe impl<A: Allocator + Copy> Send for SlabAllocator<A> {}
 unsafe impl<A: Allocator + Copy> Sync for SlabAllocator<A> {}
 
 impl<A: Allocator + Copy> SlabAllocator<A> {
+    /// Creates a slab allocator whose largest size class is `1 << max_size_shift` bytes.
     #[inline]
     pub const fn new_in(max_size_shift: u32, allocator: A) -> Self {
-        Self { slabs: Mutex::new(TryVec::new_in(allocator)), max_size: 1 << max_size_shift, allocator }
+        assert!(max_size_shift >= MIN_SIZE_SHIFT && max_size_shift <= MAX_SIZE_SHIFT);
+
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY_CLASS: Mutex<SizeClass> = Mutex::new(SizeClass::new());
+
+        Self { classes: [EMPTY_CLASS; CLASS_COUNT], max_size: 1 << max_size_shift, allocator }
+    }
+
+    /// Index and item size of the size class which serves `layout`, or `None` if it's served by the backing
+    /// allocator.
+    fn size_class(&self, layout: Layout) -> Option<(usize, usize)> {
+        let item_size = layout.size().max(layout.align()).max(1 << MIN_SIZE_SHIFT).checked_next_power_of_two()?;
+
+        (item_size <= self.max_size).then(|| ((item_size.trailing_zeros() - MIN_SIZE_SHIFT) as usize, item_size))
+    }
+
+    /// Returns every empty slab to the backing allocator, returning how many bytes were released. Size classes which
+    /// are in use (e.g. by the caller, if it's allocating) are skipped.
+    pub fn reclaim(&self) -> usize {
+        let mut released = 0;
+
+        for (index, class) in self.classes.iter().enumerate() {
+            let Some(mut class) = class.try_lock() else { continue };
+            let length = Slab::length(1 << (index as u32 + MIN_SIZE_SHIFT));
+
+            while let Some(slab) = class.empty.pop() {
+                // ### Safety: Empty slabs have no items in use, and were allocated with this layout.
+                unsafe {
+                    self.allocator.deallocate(slab.cast(), Layout::from_size_align_unchecked(length, length));
+                }
+
+                released += length;
+            }
+        }
+
+        released
     }
 }
 
 unsafe impl<A: Allocator + Copy> Allocator for SlabAllocator<A> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let padded_layout = layout.pad_to_align();
-        if padded_layout.size() == 0 {
-            Err(AllocError)
-        } else if padded_layout.size() > self.max_size {
-            self.allocator.allocate(layout)
-        } else {
-            let mut slabs = self.slabs.lock();
-            let slab = match slabs.iter_mut().find(|slab| slab.remaining() > 0 && slab.layout() == layout) {
-                Some(slab) => slab,
-                None => {
-                    slabs.push(Slab::new_in(layout, self.allocator)?).map_err(|_| AllocError)?;
-                    slabs.iter_mut().last().unwrap()
-                }
-            };
-
-            Ok(slab.take_item().unwrap())
+        if layout.size() == 0 {
+            return Err(AllocError);
         }
+
+        let Some((index, item_size)) = self.size_class(layout) else { return self.allocator.allocate(layout) };
+
+        let mut class = self.classes[index].lock();
+        let slab = match class.partial.head.or(class.empty.head) {
+            Some(slab) => slab,
+
+            None => {
+                let slab = Slab::new_in(item_size, self.allocator)?;
+                // ### Safety: Slab was just allocated.
+                unsafe { class.empty.push(slab) };
+
+                slab
+            }
+        };
+
+        // ### Safety: Slabs in the class's lists are valid, and only accessed with the class locked.
+        let slab_mut = unsafe { &mut *slab.as_ptr() };
+        let old_state = slab_mut.state();
+        // Slabs in the partial and empty lists always have free items.
+        let item = slab_mut.take_item().ok_or(AllocError)?;
+        // ### Safety: The slab is in the list for its previous state.
+        unsafe { class.relist(slab, old_state) };
+
+        Ok(NonNull::slice_from_raw_parts(item, item_size))
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        let padded_layout = layout.pad_to_align();
-        if padded_layout.size() > self.max_size {
-            self.allocator.deallocate(ptr, layout);
-        } else {
-            let mut slabs = self.slabs.lock();
-            slabs
-                .iter_mut()
-                .filter(|slab| slab.remaining() < slab.capacity() && slab.layout() == layout)
-                .find_map(|slab| slab.return_item(ptr).ok())
-                .unwrap();
+        // Zero-sized allocations are never served, so there's nothing to return.
+        if layout.size() == 0 {
+            return;
+        }
+
+        let Some((index, item_size)) = self.size_class(layout) else { return self.allocator.deallocate(ptr, layout) };
+
+        let mut class = self.classes[index].lock();
+        // ### Safety: Caller is required to provide a pointer allocated with this layout, so from this size class.
+        let Some(slab) = (unsafe { Slab::of_item(ptr, item_size) }) else {
+            error!("Deallocated pointer {:?} is not an item of a {} byte slab; leaking it.", ptr, item_size);
+            return;
+        };
+
+        // ### Safety: Slabs in the class's lists are valid, and only accessed with the class locked.
+        let slab_mut = unsafe { &mut *slab.as_ptr() };
+        let old_state = slab_mut.state();
+        // ### Safety: Caller is required to provide an item which is in use.
+        unsafe { slab_mut.return_item(ptr) };
+        // ### Safety: The slab is in the list for its previous state.
+        unsafe { class.relist(slab, old_state) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        alloc::Global,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Backing allocator which counts the allocations it has outstanding.
+    struct Backing {
+        live: AtomicUsize,
+    }
+
+    impl Backing {
+        const fn new() -> Self {
+            Self { live: AtomicUsize::new(0) }
+        }
+
+        fn live(&self) -> usize {
+            self.live.load(Ordering::Relaxed)
+        }
+    }
+
+    unsafe impl Allocator for &Backing {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            let memory = Global.allocate(layout)?;
+            self.live.fetch_add(1, Ordering::Relaxed);
+
+            Ok(memory)
         }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            // ### Safety: Caller is required to provide memory allocated from this allocator.
+            unsafe { Global.deallocate(ptr, layout) };
+        }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 1).unwrap()
+    }
+
+    fn allocate(slab_allocator: &SlabAllocator<&Backing>, size: usize) -> NonNull<u8> {
+        slab_allocator.allocate(layout(size)).unwrap().as_non_null_ptr()
+    }
+
+    type Heads = (Option<NonNull<Slab>>, Option<NonNull<Slab>>, Option<NonNull<Slab>>);
+
+    /// Heads of the empty, partial, and full lists of the size class at `index`.
+    fn heads(slab_allocator: &SlabAllocator<&Backing>, index: usize) -> Heads {
+        let class = slab_allocator.classes[index].lock();
+        (class.empty.head, class.partial.head, class.full.head)
+    }
+
+    #[test]
+    fn size_class_boundaries() {
+        let backing = Backing::new();
+        let slab_allocator = SlabAllocator::new_in(MAX_SIZE_SHIFT, &backing);
+
+        assert_eq!(slab_allocator.size_class(layout(1)), Some((0, 16)));
+        assert_eq!(slab_allocator.size_class(layout(16)), Some((0, 16)));
+        assert_eq!(slab_allocator.size_class(layout(17)), Some((1, 32)));
+        assert_eq!(slab_allocator.size_class(Layout::from_size_align(8, 64).unwrap()), Some((2, 64)));
+        assert_eq!(slab_allocator.size_class(layout(4096)), Some((CLASS_COUNT - 1, 4096)));
+        assert_eq!(slab_allocator.size_class(layout(4097)), None);
+
+        let small_allocator = SlabAllocator::new_in(8, &backing);
+        assert_eq!(small_allocator.size_class(layout(256)), Some((4, 256)));
+        assert_eq!(small_allocator.size_class(layout(257)), None);
+
+        for shift in MIN_SIZE_SHIFT..=MAX_SIZE_SHIFT {
+            let item_size = 1 << shift;
+            let memory = slab_allocator.allocate(layout(item_size)).unwrap();
+            assert_eq!(memory.len(), item_size);
+            assert_eq!(memory.as_non_null_ptr().addr().get() % item_size, 0);
+
+            // ### Safety: Memory was just allocated with this layout.
+            unsafe { slab_allocator.deallocate(memory.as_non_null_ptr(), layout(item_size)) };
+        }
+
+        // Sizes past the largest class go straight to the backing allocator.
+        let live = backing.live();
+        let large = allocate(&slab_allocator, 4097);
+        assert_eq!(backing.live(), live + 1);
+        // ### Safety: Memory was just allocated with this layout.
+        unsafe { slab_allocator.deallocate(large, layout(4097)) };
+        assert_eq!(backing.live(), live);
+
+        slab_allocator.reclaim();
+        assert_eq!(backing.live(), 0);
+    }
+
+    #[test]
+    fn of_item_bounds() {
+        const ITEM_SIZE: usize = 4096;
+
+        let backing = Backing::new();
+        let slab_allocator = SlabAllocator::new_in(MAX_SIZE_SHIFT, &backing);
+        let length = Slab::length(ITEM_SIZE);
+        let capacity = (length - Slab::items_offset(ITEM_SIZE)) / ITEM_SIZE;
+        assert!(length > MIN_SLAB_LENGTH);
+
+        let items = (0..capacity).map(|_| allocate(&slab_allocator, ITEM_SIZE)).collect::<Vec<_>>();
+        let first = items[0];
+        let last = items[capacity - 1];
+
+        let base = first.addr().get() - Slab::items_offset(ITEM_SIZE);
+        assert_eq!(base % length, 0);
+        assert_eq!(last.addr().get(), base + length - ITEM_SIZE);
+
+        // ### Safety: Both pointers are items of this size class.
+        unsafe {
+            assert_eq!(Slab::of_item(first, ITEM_SIZE).unwrap().addr().get(), base);
+            assert_eq!(Slab::of_item(last, ITEM_SIZE).unwrap().addr().get(), base);
+            assert!(Slab::of_item(first.byte_add(8), ITEM_SIZE).is_none());
+        }
+
+        for item in items {
+            // ### Safety: Memory was allocated with this layout.
+            unsafe { slab_allocator.deallocate(item, layout(ITEM_SIZE)) };
+        }
+
+        assert_eq!(slab_allocator.reclaim(), length);
+    }
+
+    #[test]
+    fn slab_lists() {
+        const ITEM_SIZE: usize = 4096;
+        const INDEX: usize = CLASS_COUNT - 1;
+
+        let backing = Backing::new();
+        let slab_allocator = SlabAllocator::new_in(MAX_SIZE_SHIFT, &backing);
+        let capacity = (Slab::length(ITEM_SIZE) - Slab::items_offset(ITEM_SIZE)) / ITEM_SIZE;
+
+        let mut items = vec![allocate(&slab_allocator, ITEM_SIZE)];
+        let (empty, partial, full) = heads(&slab_allocator, INDEX);
+        assert!(empty.is_none() && partial.is_some() && full.is_none());
+        let slab = partial.unwrap();
+
+        items.extend((1..capacity).map(|_| allocate(&slab_allocator, ITEM_SIZE)));
+        assert_eq!(heads(&slab_allocator, INDEX), (None, None, Some(slab)));
+
+        // Allocating from a class whose slabs are all full starts a new slab.
+        let extra = allocate(&slab_allocator, ITEM_SIZE);
+        let (empty, partial, full) = heads(&slab_allocator, INDEX);
+        assert!(empty.is_none() && partial.is_some_and(|partial| partial != slab) && full == Some(slab));
+        // ### Safety: Memory was just allocated with this layout.
+        unsafe { slab_allocator.deallocate(extra, layout(ITEM_SIZE)) };
+        assert!(heads(&slab_allocator, INDEX).0.is_some());
+
+        // ### Safety: Memory was allocated with this layout.
+        unsafe { slab_allocator.deallocate(items.pop().unwrap(), layout(ITEM_SIZE)) };
+        let (_, partial, full) = heads(&slab_allocator, INDEX);
+        assert!(partial == Some(slab) && full.is_none());
+
+        for item in items {
+            // ### Safety: Memory was allocated with this layout.
+            unsafe { slab_allocator.deallocate(item, layout(ITEM_SIZE)) };
+        }
+
+        let (_, partial, full) = heads(&slab_allocator, INDEX);
+        assert!(partial.is_none() && full.is_none());
+        assert_eq!(slab_allocator.reclaim(), 2 * Slab::length(ITEM_SIZE));
+    }
+
+    #[test]
+    fn reclaim_empty_slabs() {
+        const ITEM_SIZE: usize = 4096;
+
+        let backing = Backing::new();
+        let slab_allocator = SlabAllocator::new_in(MAX_SIZE_SHIFT, &backing);
+        let length = Slab::length(ITEM_SIZE);
+        let capacity = (length - Slab::items_offset(ITEM_SIZE)) / ITEM_SIZE;
+
+        // Fill one slab, and start a second.
+        let mut items = (0..=capacity).map(|_| allocate(&slab_allocator, ITEM_SIZE)).collect::<Vec<_>>();
+        let small = allocate(&slab_allocator, 16);
+        assert_eq!(backing.live(), 3);
+        assert_eq!(slab_allocator.reclaim(), 0);
+
+        // Empty the first slab, leaving the second partial.
+        let remaining = items.pop().unwrap();
+        for item in items {
+            // ### Safety: Memory was allocated with this layout.
+            unsafe { slab_allocator.deallocate(item, layout(ITEM_SIZE)) };
+        }
+
+        // Size classes which are locked are skipped.
+        {
+            let _class = slab_allocator.classes[CLASS_COUNT - 1].lock();
+            assert_eq!(slab_allocator.reclaim(), 0);
+        }
+
+        assert_eq!(slab_allocator.reclaim(), length);
+        assert_eq!(backing.live(), 2);
+
+        // ### Safety: Memory was allocated with these layouts.
+        unsafe {
+            slab_allocator.deallocate(remaining, layout(ITEM_SIZE));
+            slab_allocator.deallocate(small, layout(16));
+        }
+
+        assert_eq!(slab_allocator.reclaim(), length + Slab::length(16));
+        assert_eq!(backing.live(), 0);
+    }
+
+    #[test]
+    fn zero_size() {
+        let backing = Backing::new();
+        let slab_allocator = SlabAllocator::new_in(MAX_SIZE_SHIFT, &backing);
+
+        assert!(slab_allocator.allocate(layout(0)).is_err());
+        // ### Safety: Zero-sized deallocations don't touch the pointer.
+        unsafe { slab_allocator.deallocate(NonNull::dangling(), layout(0)) };
+        assert_eq!(backing.live(), 0);
     }
 }
@@ -0,0 +1,99 @@
+use abi::MmapFlags;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const PAGE_SIZE: usize = 0x1000;
+/// Block sizes of the small allocation classes. Larger allocations are mapped directly.
+const BLOCK_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+/// Allocator which carves small allocations out of `mmap`ed pages, and maps larger ones directly.
+struct Allocator {
+    lock: AtomicBool,
+    /// Heads of the intrusive free lists for each block size.
+    free_lists: core::cell::UnsafeCell<[Option<NonNull<FreeBlock>>; BLOCK_SIZES.len()]>,
+}
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+// ### Safety: Access to the free lists is synchronized by `lock`.
+unsafe impl Sync for Allocator {}
+
+impl Allocator {
+    const fn new() -> Self {
+        Self { lock: AtomicBool::new(false), free_lists: core::cell::UnsafeCell::new([None; BLOCK_SIZES.len()]) }
+    }
+
+    fn with_free_lists<T>(&self, func: impl FnOnce(&mut [Option<NonNull<FreeBlock>>; BLOCK_SIZES.len()]) -> T) -> T {
+        while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        // ### Safety: The lock is held, so no other references to the free lists exist.
+        let result = func(unsafe { &mut *self.free_lists.get() });
+        self.lock.store(false, Ordering::Release);
+
+        result
+    }
+
+    /// Returns the index of the smallest block size that fits `layout`, if any.
+    fn class_index(layout: Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        BLOCK_SIZES.iter().position(|block_size| *block_size >= size)
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::class_index(layout) {
+            Some(class_index) => self.with_free_lists(|free_lists| {
+                if free_lists[class_index].is_none() {
+                    // Refill the free list with a new page of blocks.
+                    let Ok(page) =
+                        crate::mmap(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap(), MmapFlags::READ_WRITE)
+                    else {
+                        return core::ptr::null_mut();
+                    };
+
+                    let block_size = BLOCK_SIZES[class_index];
+                    for block_offset in (0..PAGE_SIZE).step_by(block_size).rev() {
+                        let block = page.as_mut_ptr().add(block_offset).cast::<FreeBlock>();
+                        block.write(FreeBlock { next: free_lists[class_index] });
+                        free_lists[class_index] = NonNull::new(block);
+                    }
+                }
+
+                free_lists[class_index].map_or(core::ptr::null_mut(), |block| {
+                    free_lists[class_index] = block.as_ref().next;
+                    block.as_ptr().cast()
+                })
+            }),
+
+            None => crate::mmap(layout, MmapFlags::READ_WRITE).map_or(core::ptr::null_mut(), NonNull::as_mut_ptr),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Self::class_index(layout) {
+            Some(class_index) => self.with_free_lists(|free_lists| {
+                let block = ptr.cast::<FreeBlock>();
+                block.write(FreeBlock { next: free_lists[class_index] });
+                free_lists[class_index] = NonNull::new(block);
+            }),
+
+            None => {
+                if let Some(ptr) = NonNull::new(ptr) {
+                    // Failing to unmap only leaks the memory.
+                    let _ = crate::munmap(ptr, layout.size());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+#![no_std]
+#![feature(naked_functions, asm_const, slice_ptr_get)]
+
+//! Userspace runtime for Linuiz programs.
+//!
+//! Provides the `_start` entry point, a panic handler which reports through the kernel log, a global allocator
+//! backed by `mmap`, and safe wrappers over every system call. Programs depend on this crate, and define their
+//! entry point as:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! #[no_mangle]
+//! fn main() {
+//!     log::info!("Hello, world!");
+//! }
+//! ```
+
+extern crate alloc;
+
+mod heap;
+mod rt;
+pub mod syscall;
+
+pub use abi::{
+    ChannelFlags, Handle, HandleRights, LogLevel, MmapFlags, RegisterMessage, TaskInfo, TaskState, MAX_MESSAGE_LEN,
+};
+pub use syscall::*;
@@ -0,0 +1,103 @@
+use abi::LogLevel;
+use core::fmt::Write;
+
+extern "Rust" {
+    /// The program's entry point, defined as `#[no_mangle] fn main()`.
+    fn main();
+}
+
+/// ### Safety
+///
+/// This function should never be called by software.
+#[naked]
+#[no_mangle]
+unsafe extern "sysv64" fn _start() -> ! {
+    core::arch::asm!(
+        "
+        xor rbp, rbp    # terminate stack traces here
+        call {}
+        ud2
+        ",
+        sym start,
+        options(noreturn)
+    )
+}
+
+extern "sysv64" fn start() -> ! {
+    static LOGGER: Logger = Logger;
+
+    // The logger can only fail to be set if it already has been, and `_start` runs once.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Trace);
+
+    // ### Safety: `main` is required to be defined by the program.
+    unsafe { main() };
+
+    crate::exit(0)
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let mut buffer = StackWriter::<0x200>::new();
+    // A truncated message is still useful.
+    let _ = write!(buffer, "{info}");
+    buffer.log(LogLevel::Error);
+
+    crate::exit(101)
+}
+
+/// Fixed-size, null-terminated formatting buffer, which silently truncates anything that doesn't fit.
+struct StackWriter<const LEN: usize> {
+    buffer: [u8; LEN],
+    len: usize,
+}
+
+impl<const LEN: usize> StackWriter<LEN> {
+    const fn new() -> Self {
+        Self { buffer: [0; LEN], len: 0 }
+    }
+
+    fn log(&self, level: LogLevel) {
+        // ### Safety: Only whole `str`s are ever written to the buffer, and truncation is done on `char` boundaries.
+        crate::log(level, unsafe { core::str::from_utf8_unchecked(&self.buffer[..self.len]) });
+    }
+}
+
+impl<const LEN: usize> Write for StackWriter<LEN> {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        // Always leave room for the null terminator.
+        let remaining_len = LEN - 1 - self.len;
+        let write_len = if string.len() <= remaining_len {
+            string.len()
+        } else {
+            (0..=remaining_len).rev().find(|index| string.is_char_boundary(*index)).unwrap_or(0)
+        };
+
+        self.buffer[self.len..(self.len + write_len)].copy_from_slice(&string.as_bytes()[..write_len]);
+        self.len += write_len;
+
+        if write_len == string.len() {
+            Ok(())
+        } else {
+            Err(core::fmt::Error)
+        }
+    }
+}
+
+/// Forwards records from the `log` crate to the kernel log.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut buffer = StackWriter::<0x200>::new();
+        // A truncated message is still useful.
+        let _ = write!(buffer, "{}", record.args());
+        buffer.log(record.level().into());
+    }
+
+    fn flush(&self) {}
+}
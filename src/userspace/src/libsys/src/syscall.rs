@@ -0,0 +1,349 @@
+use abi::{ChannelFlags, Handle, HandleRights, LogLevel, MmapFlags, RegisterMessage, SyscallReturn, TaskInfo, Vector};
+use core::{alloc::Layout, ptr::NonNull, time::Duration};
+
+/// Maximum length of a message which can be logged without allocating.
+const LOG_BUFFER_LEN: usize = 0x100;
+
+/// Error returned by a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// An error defined by the ABI.
+    Abi(abi::Error),
+    /// An error code not known to this version of the ABI (i.e. the kernel is newer than this program).
+    Unknown(u64),
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Performs a raw system call.
+///
+/// ### Safety
+///
+/// Caller must ensure the arguments are valid for the given vector, as described in the `abi` crate.
+#[inline]
+pub unsafe fn syscall(vector: Vector, args: [u64; 5]) -> Result<u64> {
+    let rax: u64;
+    let rdx: u64;
+
+    core::arch::asm!(
+        "syscall",
+        inout("rdi") vector as u64 => _,
+        inout("rsi") args[0] => _,
+        inout("rdx") args[1] => rdx,
+        inout("r10") args[2] => _,
+        inout("r8") args[3] => _,
+        inout("r9") args[4] => _,
+        // `syscall` overwrites `rcx` and `r11` with the return address and flags.
+        out("rcx") _,
+        out("r11") _,
+        out("rax") rax,
+        options(nostack)
+    );
+
+    SyscallReturn { rax, rdx }.into_result().map_err(|err| err.map_or_else(Error::Unknown, Error::Abi))
+}
+
+/// Performs a raw endpoint system call, which returns a [`RegisterMessage`] in the argument registers.
+///
+/// ### Safety
+///
+/// Caller must ensure the arguments are valid for the given vector, as described in the `abi` crate.
+#[inline]
+unsafe fn syscall_register_message(
+    vector: Vector,
+    handle: Handle,
+    message: RegisterMessage,
+) -> Result<RegisterMessage> {
+    let rax: u64;
+    let rdx: u64;
+    let r10: u64;
+    let r8: u64;
+    let r9: u64;
+
+    core::arch::asm!(
+        "syscall",
+        inout("rdi") vector as u64 => _,
+        inout("rsi") handle as u64 => _,
+        inout("rdx") message[0] => rdx,
+        inout("r10") message[1] => r10,
+        inout("r8") message[2] => r8,
+        inout("r9") message[3] => r9,
+        // `syscall` overwrites `rcx` and `r11` with the return address and flags.
+        out("rcx") _,
+        out("r11") _,
+        out("rax") rax,
+        options(nostack)
+    );
+
+    SyscallReturn { rax, rdx }
+        .into_result()
+        .map(|rdx| [rdx, r10, r8, r9])
+        .map_err(|err| err.map_or_else(Error::Unknown, Error::Abi))
+}
+
+/// Gives up the remainder of the current task's time slice.
+pub fn yield_now() {
+    // ### Safety: System call takes no arguments.
+    unsafe { syscall(Vector::Yield, [0; 5]) }.unwrap();
+}
+
+/// Returns the 16-byte ID of the current task.
+pub fn task_id() -> [u8; 16] {
+    let mut task_id = [0u8; 16];
+
+    // ### Safety: Pointer is valid for writes of 16 bytes.
+    unsafe { syscall(Vector::TaskId, [task_id.as_mut_ptr() as u64, 0, 0, 0, 0]) }.unwrap();
+
+    task_id
+}
+
+/// Exits the current task with `code`.
+pub fn exit(code: u32) -> ! {
+    // ### Safety: System call takes no pointers.
+    let _ = unsafe { syscall(Vector::Exit, [code as u64, 0, 0, 0, 0]) };
+
+    unreachable!("task continued after exiting")
+}
+
+/// Clones the current task (and a copy-on-write copy of its address space), returning the child's 16-byte ID to the
+/// current task, and `None` to the child. The child resumes from this call with no handles.
+pub fn task_clone() -> Result<Option<[u8; 16]>> {
+    let mut child_id = [0u8; 16];
+
+    // ### Safety: Pointer is valid for writes of 16 bytes.
+    let is_parent = unsafe { syscall(Vector::TaskClone, [child_id.as_mut_ptr() as u64, 0, 0, 0, 0]) }?;
+
+    Ok((is_parent > 0).then_some(child_id))
+}
+
+/// Blocks until the child task `child_id` (as returned by [`task_clone`]) exits, returning its exit code. Each child
+/// can only be waited for once.
+pub fn task_wait(child_id: &[u8; 16]) -> Result<u32> {
+    // ### Safety: Pointer is valid for reads of 16 bytes.
+    unsafe { syscall(Vector::TaskWait, [child_id.as_ptr() as u64, 0, 0, 0, 0]) }.map(|code| code as u32)
+}
+
+/// Restricts the current task to the cores set in `mask` (bit `n` allows the core with ID `n`, and `u64::MAX`
+/// allows every core), moving it to an allowed core if necessary.
+pub fn set_affinity(mask: u64) -> Result<()> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall(Vector::SetAffinity, [mask, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Fills `buffer` with the statistics of as many tasks as fit, returning the total number of tasks. If that exceeds
+/// the buffer's length, the call can be repeated with a larger buffer.
+pub fn task_stats(buffer: &mut [TaskInfo]) -> Result<usize> {
+    // ### Safety: Pointer is valid for writes of the buffer's length, in records.
+    unsafe { syscall(Vector::TaskStats, [buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0, 0]) }
+        .map(|count| count as usize)
+}
+
+/// Logs a message to the kernel output. Messages containing null bytes are truncated at the first null byte.
+pub fn log(level: LogLevel, message: &str) {
+    let message = message.split('\0').next().unwrap_or("");
+
+    if message.len() < LOG_BUFFER_LEN {
+        let mut buffer = [0u8; LOG_BUFFER_LEN];
+        buffer[..message.len()].copy_from_slice(message.as_bytes());
+
+        log_cstr(level, &buffer);
+    } else {
+        let mut buffer = alloc::vec::Vec::with_capacity(message.len() + 1);
+        buffer.extend_from_slice(message.as_bytes());
+        buffer.push(0);
+
+        log_cstr(level, &buffer);
+    }
+}
+
+/// Logs the null-terminated string at the beginning of `bytes`.
+fn log_cstr(level: LogLevel, bytes: &[u8]) {
+    debug_assert!(bytes.contains(&0));
+
+    // ### Safety: `bytes` contains a null terminator, so the kernel never reads past its end.
+    // Logging can't meaningfully report its own failure, so errors are ignored.
+    let _ = unsafe { syscall(Vector::Log, [level as u64, bytes.as_ptr() as u64, 0, 0, 0]) };
+}
+
+/// Maps a new region of memory into this task's address space. Fixed mappings must be made with [`mmap_at`].
+pub fn mmap(layout: Layout, flags: MmapFlags) -> Result<NonNull<[u8]>> {
+    if flags.contains(MmapFlags::FIXED) {
+        return Err(Error::Abi(abi::Error::InvalidArgument));
+    }
+
+    // ### Safety: Without `MmapFlags::FIXED`, mapping new memory doesn't affect any existing memory.
+    unsafe { mmap_raw(0, layout, flags) }
+}
+
+/// Maps a new region of memory into this task's address space, at (or near, if neither [`MmapFlags::FIXED`] nor
+/// [`MmapFlags::FIXED_NOREPLACE`] are specified) the given page-aligned address.
+///
+/// ### Safety
+///
+/// If [`MmapFlags::FIXED`] is specified, any existing mappings in the region are replaced, so the caller must
+/// ensure no references to them exist.
+pub unsafe fn mmap_at(address: NonNull<u8>, layout: Layout, flags: MmapFlags) -> Result<NonNull<[u8]>> {
+    mmap_raw(address.as_ptr() as u64, layout, flags)
+}
+
+unsafe fn mmap_raw(address: u64, layout: Layout, flags: MmapFlags) -> Result<NonNull<[u8]>> {
+    let address =
+        syscall(Vector::Mmap, [layout.size() as u64, layout.align() as u64, flags.bits() as u64, address, 0])?;
+
+    let ptr = NonNull::new(address as usize as *mut u8).ok_or(Error::Abi(abi::Error::OutOfMemory))?;
+    Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+}
+
+/// Unmaps the pages in `ptr..(ptr + len)` from this task's address space.
+///
+/// ### Safety
+///
+/// Caller must ensure no references to the unmapped memory exist.
+pub unsafe fn munmap(ptr: NonNull<u8>, len: usize) -> Result<()> {
+    syscall(Vector::Munmap, [ptr.as_ptr() as u64, len as u64, 0, 0, 0]).map(|_| ())
+}
+
+/// Changes the protection of the pages in `ptr..(ptr + len)`, which must have been mapped with [`mmap`].
+///
+/// ### Safety
+///
+/// Caller must ensure no references to the memory exist which would violate the new protection.
+pub unsafe fn mprotect(ptr: NonNull<u8>, len: usize, flags: MmapFlags) -> Result<()> {
+    syscall(Vector::Mprotect, [ptr.as_ptr() as u64, len as u64, flags.bits() as u64, 0, 0]).map(|_| ())
+}
+
+/// Creates a zeroed shared memory object of at least `len` bytes, returning its handle.
+pub fn shm_create(len: usize) -> Result<Handle> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall(Vector::ShmCreate, [len as u64, 0, 0, 0, 0]) }.map(|handle| handle as Handle)
+}
+
+/// Maps the shared memory object `handle` into this task's address space, returning its address. Fixed mappings
+/// must be made with [`shm_map_at`].
+pub fn shm_map(handle: Handle, flags: MmapFlags) -> Result<NonNull<u8>> {
+    if flags.contains(MmapFlags::FIXED) {
+        return Err(Error::Abi(abi::Error::InvalidArgument));
+    }
+
+    // ### Safety: Without `MmapFlags::FIXED`, mapping new memory doesn't affect any existing memory.
+    unsafe { shm_map_raw(handle, 0, flags) }
+}
+
+/// Maps the shared memory object `handle` into this task's address space, at (or near, as with [`mmap_at`]) the
+/// given page-aligned address.
+///
+/// ### Safety
+///
+/// If [`MmapFlags::FIXED`] is specified, any existing mappings in the region are replaced, so the caller must
+/// ensure no references to them exist.
+pub unsafe fn shm_map_at(handle: Handle, address: NonNull<u8>, flags: MmapFlags) -> Result<NonNull<u8>> {
+    shm_map_raw(handle, address.as_ptr() as u64, flags)
+}
+
+unsafe fn shm_map_raw(handle: Handle, address: u64, flags: MmapFlags) -> Result<NonNull<u8>> {
+    let address = syscall(Vector::ShmMap, [handle as u64, flags.bits() as u64, address, 0, 0])?;
+
+    NonNull::new(address as usize as *mut u8).ok_or(Error::Abi(abi::Error::OutOfMemory))
+}
+
+/// Returns the time elapsed since boot.
+pub fn uptime() -> Duration {
+    // ### Safety: System call takes no arguments.
+    Duration::from_micros(unsafe { syscall(Vector::Uptime, [0; 5]) }.unwrap())
+}
+
+/// Blocks the current task for at least `duration`, rounded up to whole microseconds.
+pub fn sleep(duration: Duration) {
+    let microseconds = duration.as_nanos().div_ceil(1000).try_into().unwrap_or(u64::MAX);
+
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall(Vector::Sleep, [microseconds, 0, 0, 0, 0]) }.unwrap();
+}
+
+/// Creates a pair of connected channel endpoints, returning their handles.
+pub fn channel_create() -> Result<(Handle, Handle)> {
+    // ### Safety: System call takes no arguments.
+    let handles = unsafe { syscall(Vector::ChannelCreate, [0; 5]) }?;
+
+    Ok(((handles & 0xFFFF_FFFF) as Handle, (handles >> 32) as Handle))
+}
+
+/// Sends `message` (which must be at most [`abi::MAX_MESSAGE_LEN`] bytes) through the channel endpoint `handle`.
+pub fn channel_send(handle: Handle, message: &[u8], flags: ChannelFlags) -> Result<()> {
+    // ### Safety: Pointer is valid for reads of `message.len()` bytes.
+    unsafe { channel_send_raw(handle, message, flags, 0) }
+}
+
+/// Sends `message` through the channel endpoint `handle`, as with [`channel_send`], moving `transfer` to the
+/// receiving task. Once sent, `transfer` no longer refers to the object.
+pub fn channel_send_handle(handle: Handle, message: &[u8], transfer: Handle, flags: ChannelFlags) -> Result<()> {
+    // ### Safety: Pointer is valid for reads of `message.len()` bytes.
+    unsafe { channel_send_raw(handle, message, flags, transfer) }
+}
+
+unsafe fn channel_send_raw(handle: Handle, message: &[u8], flags: ChannelFlags, transfer: Handle) -> Result<()> {
+    syscall(
+        Vector::ChannelSend,
+        [handle as u64, message.as_ptr() as u64, message.len() as u64, flags.bits() as u64, transfer as u64],
+    )
+    .map(|_| ())
+}
+
+/// Receives a message from the channel endpoint `handle` into `buffer`, returning the message's length, and the
+/// handle of the object transferred with it (if any).
+pub fn channel_recv(handle: Handle, buffer: &mut [u8], flags: ChannelFlags) -> Result<(usize, Option<Handle>)> {
+    // ### Safety: Pointer is valid for writes of `buffer.len()` bytes.
+    let received = unsafe {
+        syscall(
+            Vector::ChannelRecv,
+            [handle as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64, flags.bits() as u64, 0],
+        )
+    }?;
+
+    let transferred = (received >> 32) as Handle;
+    Ok(((received & 0xFFFF_FFFF) as usize, (transferred > 0).then_some(transferred)))
+}
+
+/// Creates a call/reply endpoint, returning its handle.
+pub fn endpoint_create() -> Result<Handle> {
+    // ### Safety: System call takes no arguments.
+    unsafe { syscall(Vector::EndpointCreate, [0; 5]) }.map(|handle| handle as Handle)
+}
+
+/// Calls the endpoint `handle` with `message`, blocking until a server replies.
+pub fn endpoint_call(handle: Handle, message: RegisterMessage) -> Result<RegisterMessage> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall_register_message(Vector::EndpointCall, handle, message) }
+}
+
+/// Receives the next call made to the endpoint `handle`, blocking until one is made.
+pub fn endpoint_recv(handle: Handle) -> Result<RegisterMessage> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall_register_message(Vector::EndpointRecv, handle, [0; 4]) }
+}
+
+/// Replies to the last call received from the endpoint `handle` with `message`.
+pub fn endpoint_reply(handle: Handle, message: RegisterMessage) -> Result<()> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall_register_message(Vector::EndpointReply, handle, message) }.map(|_| ())
+}
+
+/// Replies to the last call received from the endpoint `handle` with `message`, then receives the next call.
+pub fn endpoint_reply_recv(handle: Handle, message: RegisterMessage) -> Result<RegisterMessage> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall_register_message(Vector::EndpointReplyRecv, handle, message) }
+}
+
+/// Duplicates `handle`, returning a new handle to the same object with `rights` (which must be a subset of the
+/// rights of `handle`).
+pub fn handle_duplicate(handle: Handle, rights: HandleRights) -> Result<Handle> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall(Vector::HandleDuplicate, [handle as u64, rights.bits() as u64, 0, 0, 0]) }
+        .map(|handle| handle as Handle)
+}
+
+/// Closes `handle`. The object is dropped once no other references to it exist.
+pub fn handle_close(handle: Handle) -> Result<()> {
+    // ### Safety: System call takes no pointers.
+    unsafe { syscall(Vector::HandleClose, [handle as u64, 0, 0, 0, 0]) }.map(|_| ())
+}
@@ -1,43 +1,14 @@
 #![no_std]
 #![no_main]
-#![feature(sync_unsafe_cell, naked_functions, asm_const, cstr_from_bytes_until_nul)]
 
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    loop {}
-}
+extern crate alloc;
 
 #[no_mangle]
-extern "C" fn _start() -> ! {
-    let log_message = core::ffi::CStr::from_bytes_until_nul(b"process logging test\0").unwrap();
-
+fn main() {
     for _ in 0..10 {
-        unsafe {
-            core::arch::asm!(
-                "
-                push rax
-                push rcx
-                push r8
-                push r9
-                push r10
-                push r11
-
-                syscall
-
-                pop r11
-                pop r10
-                pop r9
-                pop r9
-                pop rcx
-                pop rax
-                ",
-                inout("rdi") 0x100 => _,
-                inout("rsi") log::Level::Info as usize => _,
-                inout("rdx")  log_message.as_ptr() => _,
-                options(nostack, nomem)
-            );
-        }
+        log::info!("process logging test");
     }
 
-    loop {}
+    let message = alloc::format!("allocated message, logged at {:?} since boot", libsys::uptime());
+    log::info!("{}", message);
 }